@@ -0,0 +1,67 @@
+use std::collections::HashMap;
+
+use actix_web::rt::System;
+use actix_web::test::{call_service, init_service, TestRequest};
+use actix_web::{web, App, HttpResponse};
+use actix_web_metrics::ActixWebMetricsBuilder;
+use criterion::{criterion_group, criterion_main, Criterion};
+use metrics_exporter_prometheus::PrometheusBuilder;
+
+fn per_request_overhead(c: &mut Criterion) {
+    PrometheusBuilder::new().install_recorder().unwrap();
+
+    let system = System::new();
+    let mut group = c.benchmark_group("per_request");
+
+    let app = system.block_on(init_service(
+        App::new().service(web::resource("/resource/{id}").to(HttpResponse::Ok)),
+    ));
+    group.bench_function("without_middleware", |b| {
+        b.iter(|| {
+            system.block_on(async {
+                call_service(&app, TestRequest::with_uri("/resource/42").to_request()).await
+            })
+        })
+    });
+
+    let app = system.block_on(init_service(
+        App::new()
+            .wrap(ActixWebMetricsBuilder::new().build())
+            .service(web::resource("/resource/{id}").to(HttpResponse::Ok)),
+    ));
+    group.bench_function("with_middleware", |b| {
+        b.iter(|| {
+            system.block_on(async {
+                call_service(&app, TestRequest::with_uri("/resource/42").to_request()).await
+            })
+        })
+    });
+
+    let const_labels = HashMap::from([
+        ("service".to_string(), "bench".to_string()),
+        ("region".to_string(), "local".to_string()),
+        ("environment".to_string(), "test".to_string()),
+    ]);
+    let app = system.block_on(init_service(
+        App::new()
+            .wrap(
+                ActixWebMetricsBuilder::new()
+                    .namespace("bench")
+                    .const_labels(const_labels)
+                    .build(),
+            )
+            .service(web::resource("/resource/{id}").to(HttpResponse::Ok)),
+    ));
+    group.bench_function("with_middleware_and_const_labels", |b| {
+        b.iter(|| {
+            system.block_on(async {
+                call_service(&app, TestRequest::with_uri("/resource/42").to_request()).await
+            })
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, per_request_overhead);
+criterion_main!(benches);
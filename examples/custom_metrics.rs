@@ -1,18 +1,21 @@
 use actix_web::{web, App, HttpResponse, HttpServer};
-use actix_web_metrics::ActixWebMetricsBuilder;
+use actix_web_metrics::{ActixWebMetricsBuilder, RequestContextRecorder};
 use metrics::counter;
 use metrics_exporter_prometheus::PrometheusBuilder;
 
 async fn health() -> HttpResponse {
+    // recorded with the `http.route` and `http.request.method` labels of the request
     counter!("my_custom_counter").increment(1);
     HttpResponse::Ok().finish()
 }
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    PrometheusBuilder::new().install().unwrap();
+    let (recorder, exporter) = PrometheusBuilder::new().build().unwrap();
+    actix_web::rt::spawn(exporter);
+    metrics::set_global_recorder(RequestContextRecorder::new(recorder)).unwrap();
 
-    let metrics = ActixWebMetricsBuilder::new().build();
+    let metrics = ActixWebMetricsBuilder::new().request_context(true).build();
 
     HttpServer::new(move || {
         App::new()
@@ -0,0 +1,312 @@
+use std::fmt::{self, Write};
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::info;
+
+use crate::error::ConfigError;
+
+/// Destination of the access log lines.
+///
+/// Implemented for closures, e.g. to write the lines to stdout:
+///
+/// ```rust
+/// use actix_web_metrics::{AccessLogConfig, ActixWebMetricsBuilder};
+///
+/// let metrics = ActixWebMetricsBuilder::new()
+///     .access_log(AccessLogConfig::json().sink(|line: &str| println!("{line}")))
+///     .build();
+/// ```
+pub trait AccessLogSink: Send + Sync + 'static {
+    /// Writes one line, without a trailing newline
+    fn write(&self, line: &str);
+}
+
+impl<F> AccessLogSink for F
+where
+    F: Fn(&str) + Send + Sync + 'static,
+{
+    fn write(&self, line: &str) {
+        self(line)
+    }
+}
+
+/// Configuration of the access log.
+///
+/// One line is written per recorded request, once its response body is dropped, with the same
+/// route as the `http.route` label. Lines are JSON objects or rendered from a format string,
+/// e.g. `{method} {route} {status} {duration}`, where `{{` and `}}` stand for literal braces, with
+/// the fields:
+///
+/// | Field              | Value                                                        |
+/// |--------------------|--------------------------------------------------------------|
+/// | `method`           | request method                                               |
+/// | `scope`            | scope of the `http.scope` label, empty if disabled           |
+/// | `route`            | route of the `http.route` label                              |
+/// | `status`           | response status code                                         |
+/// | `outcome`          | `completed`, `aborted` if the response body was dropped before its end, or `error` if the service returned an error |
+/// | `duration`         | seconds until the response body was sent                     |
+/// | `handler_duration` | seconds until the handler returned the response              |
+/// | `request_size`     | request body size in bytes                                   |
+/// | `response_size`    | response body size in bytes                                  |
+///
+/// Requests the service returned an error for are logged right away with the `error` outcome, an
+/// empty scope and route, and the status of the error.
+///
+/// Lines are written with `log` at info level, with the `actix_web_metrics::access_log` target,
+/// unless another [`AccessLogSink`] is set. Excluded requests are not logged.
+#[derive(Clone)]
+pub struct AccessLogConfig {
+    // `None` for JSON lines
+    format: Option<String>,
+    sink: Option<Arc<dyn AccessLogSink>>,
+}
+
+impl fmt::Debug for AccessLogConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AccessLogConfig")
+            .field("format", &self.format)
+            .field("sink", &self.sink.as_ref().map(|_| "AccessLogSink"))
+            .finish()
+    }
+}
+
+impl Default for AccessLogConfig {
+    fn default() -> Self {
+        Self::json()
+    }
+}
+
+impl AccessLogConfig {
+    /// Write JSON objects
+    pub fn json() -> Self {
+        Self {
+            format: None,
+            sink: None,
+        }
+    }
+
+    /// Write lines rendered from `format`, e.g. `{method} {route} {status} {duration}`
+    pub fn format<T: Into<String>>(format: T) -> Self {
+        Self {
+            format: Some(format.into()),
+            sink: None,
+        }
+    }
+
+    /// Write the lines to `sink` instead of `log`
+    pub fn sink<S: AccessLogSink>(mut self, sink: S) -> Self {
+        self.sink = Some(Arc::new(sink));
+        self
+    }
+}
+
+/// Fields of an access log line.
+pub(crate) struct AccessLogEntry<'a> {
+    pub(crate) method: &'a str,
+    pub(crate) scope: Option<&'a str>,
+    pub(crate) route: &'a str,
+    pub(crate) status: u16,
+    pub(crate) outcome: Outcome,
+    pub(crate) duration: Duration,
+    pub(crate) handler_duration: Duration,
+    pub(crate) request_size: usize,
+    pub(crate) response_size: usize,
+}
+
+/// How a logged request ended.
+#[derive(Clone, Copy)]
+pub(crate) enum Outcome {
+    Completed,
+    Aborted,
+    Error,
+}
+
+impl Outcome {
+    pub(crate) fn new(completed: bool) -> Self {
+        if completed {
+            Self::Completed
+        } else {
+            Self::Aborted
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Completed => "completed",
+            Self::Aborted => "aborted",
+            Self::Error => "error",
+        }
+    }
+}
+
+/// Field of an access log format string.
+#[derive(Clone, Copy)]
+enum Field {
+    Method,
+    Scope,
+    Route,
+    Status,
+    Outcome,
+    Duration,
+    HandlerDuration,
+    RequestSize,
+    ResponseSize,
+}
+
+impl Field {
+    fn parse(name: &str) -> Option<Self> {
+        Some(match name {
+            "method" => Self::Method,
+            "scope" => Self::Scope,
+            "route" => Self::Route,
+            "status" => Self::Status,
+            "outcome" => Self::Outcome,
+            "duration" => Self::Duration,
+            "handler_duration" => Self::HandlerDuration,
+            "request_size" => Self::RequestSize,
+            "response_size" => Self::ResponseSize,
+            _ => return None,
+        })
+    }
+
+    fn write(self, line: &mut String, entry: &AccessLogEntry<'_>) {
+        let _ = match self {
+            Self::Method => write!(line, "{}", entry.method),
+            Self::Scope => write!(line, "{}", entry.scope.unwrap_or_default()),
+            Self::Route => write!(line, "{}", entry.route),
+            Self::Status => write!(line, "{}", entry.status),
+            Self::Outcome => write!(line, "{}", entry.outcome.as_str()),
+            Self::Duration => write!(line, "{:.6}", entry.duration.as_secs_f64()),
+            Self::HandlerDuration => write!(line, "{:.6}", entry.handler_duration.as_secs_f64()),
+            Self::RequestSize => write!(line, "{}", entry.request_size),
+            Self::ResponseSize => write!(line, "{}", entry.response_size),
+        };
+    }
+}
+
+/// Part of an access log format string, parsed once when the middleware is built.
+enum Segment {
+    Literal(String),
+    Field(Field),
+}
+
+/// Parses `format`, failing with the reason it is invalid.
+fn parse_format(format: &str) -> Result<Vec<Segment>, String> {
+    let mut segments = Vec::new();
+    let mut literal = String::new();
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.as_str().starts_with('{') => {
+                chars.next();
+                literal.push('{');
+            }
+            '}' if chars.as_str().starts_with('}') => {
+                chars.next();
+                literal.push('}');
+            }
+            '{' => {
+                let rest = chars.as_str();
+                let end = rest.find('}').ok_or_else(|| "unclosed `{`".to_string())?;
+                let name = &rest[..end];
+                let field = Field::parse(name).ok_or_else(|| format!("unknown field `{name}`"))?;
+                if !literal.is_empty() {
+                    segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                }
+                segments.push(Segment::Field(field));
+                chars = rest[end + 1..].chars();
+            }
+            '}' => return Err("unmatched `}`".to_string()),
+            c => literal.push(c),
+        }
+    }
+    if !literal.is_empty() {
+        segments.push(Segment::Literal(literal));
+    }
+    Ok(segments)
+}
+
+/// Writes the access log lines according to an [`AccessLogConfig`].
+pub(crate) struct AccessLog {
+    // `None` for JSON lines
+    format: Option<Vec<Segment>>,
+    sink: Option<Arc<dyn AccessLogSink>>,
+}
+
+impl AccessLog {
+    pub(crate) fn new(config: AccessLogConfig) -> Result<Self, ConfigError> {
+        let format = match config.format {
+            Some(format) => match parse_format(&format) {
+                Ok(segments) => Some(segments),
+                Err(reason) => return Err(ConfigError::InvalidAccessLogFormat { format, reason }),
+            },
+            None => None,
+        };
+        Ok(Self {
+            format,
+            sink: config.sink,
+        })
+    }
+
+    pub(crate) fn write(&self, entry: &AccessLogEntry<'_>) {
+        let line = match &self.format {
+            Some(segments) => {
+                let mut line = String::with_capacity(128);
+                for segment in segments {
+                    match segment {
+                        Segment::Literal(literal) => line.push_str(literal),
+                        Segment::Field(field) => field.write(&mut line, entry),
+                    }
+                }
+                line
+            }
+            None => json(entry),
+        };
+        match &self.sink {
+            Some(sink) => sink.write(&line),
+            None => info!(target: "actix_web_metrics::access_log", "{line}"),
+        }
+    }
+}
+
+fn json(entry: &AccessLogEntry<'_>) -> String {
+    let mut line = String::with_capacity(192);
+    line.push_str("{\"method\":");
+    push_json_string(&mut line, entry.method);
+    if let Some(scope) = entry.scope {
+        line.push_str(",\"scope\":");
+        push_json_string(&mut line, scope);
+    }
+    line.push_str(",\"route\":");
+    push_json_string(&mut line, entry.route);
+    let _ = write!(
+        line,
+        ",\"status\":{},\"outcome\":\"{}\",\"duration\":{:.6},\"handler_duration\":{:.6},\"request_size\":{},\"response_size\":{}}}",
+        entry.status,
+        entry.outcome.as_str(),
+        entry.duration.as_secs_f64(),
+        entry.handler_duration.as_secs_f64(),
+        entry.request_size,
+        entry.response_size,
+    );
+    line
+}
+
+fn push_json_string(line: &mut String, value: &str) {
+    line.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => line.push_str("\\\""),
+            '\\' => line.push_str("\\\\"),
+            '\n' => line.push_str("\\n"),
+            '\r' => line.push_str("\\r"),
+            '\t' => line.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(line, "\\u{:04x}", c as u32);
+            }
+            c => line.push(c),
+        }
+    }
+    line.push('"');
+}
@@ -0,0 +1,282 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use metrics::Histogram;
+
+use crate::cache::RouteHandles;
+
+/// Configuration for worker-local pre-aggregation.
+///
+/// When enabled, every worker thread buffers its metric updates and flushes them to the recorder
+/// once `flush_threshold` requests have been buffered or `flush_interval` has elapsed, whichever
+/// comes first. Pending updates are also flushed when the worker shuts down.
+///
+/// Request counts are summed. Histogram values are kept one by one and only equal values are
+/// recorded at once, so buffering saves few recorder calls for durations, which rarely repeat.
+/// With [`duration_buckets`](Self::duration_buckets) or
+/// [`body_size_buckets`](Self::body_size_buckets), values are counted per bucket instead and
+/// recorded as the upper bound of their bucket: with the same buckets configured in the exporter,
+/// bucket counts are exact while the sums over-estimate the values by up to the bucket width.
+/// Values above the last bucket are kept as-is.
+///
+/// The `http.server.active_requests` gauge is always updated right away, as its increments and
+/// decrements would mostly cancel out.
+#[derive(Debug, Clone)]
+pub struct LocalAggregationConfig {
+    flush_interval: Duration,
+    flush_threshold: usize,
+    duration_buckets: Arc<[f64]>,
+    body_size_buckets: Arc<[f64]>,
+}
+
+impl Default for LocalAggregationConfig {
+    fn default() -> Self {
+        Self {
+            flush_interval: Duration::from_secs(1),
+            flush_threshold: 1024,
+            duration_buckets: Arc::new([]),
+            body_size_buckets: Arc::new([]),
+        }
+    }
+}
+
+impl LocalAggregationConfig {
+    /// Set the maximum time updates are buffered before being flushed
+    ///
+    /// Defaults to 1 second
+    pub fn flush_interval(mut self, interval: Duration) -> Self {
+        self.flush_interval = interval;
+        self
+    }
+
+    /// Set the number of buffered requests that triggers a flush
+    ///
+    /// Defaults to 1024
+    pub fn flush_threshold(mut self, threshold: usize) -> Self {
+        self.flush_threshold = threshold;
+        self
+    }
+
+    /// Count the durations per bucket, with the upper bounds of the buckets in seconds
+    ///
+    /// Disabled by default, durations are kept one by one
+    pub fn duration_buckets(mut self, buckets: &[f64]) -> Self {
+        self.duration_buckets = sorted(buckets);
+        self
+    }
+
+    /// Count the request and response body sizes per bucket, with the upper bounds of the
+    /// buckets in bytes
+    ///
+    /// Disabled by default, body sizes are kept one by one
+    pub fn body_size_buckets(mut self, buckets: &[f64]) -> Self {
+        self.body_size_buckets = sorted(buckets);
+        self
+    }
+
+    pub(crate) fn interval(&self) -> Duration {
+        self.flush_interval
+    }
+}
+
+thread_local! {
+    // Pending updates of the current worker, keyed by the id of the `ActixWebMetrics` instance.
+    static LOCAL_AGGREGATES: RefCell<HashMap<u64, LocalAggregate>> = RefCell::new(HashMap::new());
+}
+
+struct LocalAggregate {
+    last_flush: Instant,
+    pending_requests: usize,
+    duration_buckets: Arc<[f64]>,
+    body_size_buckets: Arc<[f64]>,
+    histograms: HashMap<*const RouteHandles, PendingSamples>,
+}
+
+struct PendingSamples {
+    handles: Arc<RouteHandles>,
+    requests: u64,
+    durations: PendingValues,
+    request_body_sizes: PendingValues,
+    response_body_sizes: PendingValues,
+}
+
+/// Values of a histogram waiting for a flush.
+struct PendingValues {
+    bounds: Arc<[f64]>,
+    // number of values per bucket of `bounds`
+    counts: Vec<u64>,
+    // values kept as-is, above the last bound
+    values: Vec<f64>,
+}
+
+impl PendingValues {
+    fn new(bounds: &Arc<[f64]>) -> Self {
+        Self {
+            bounds: bounds.clone(),
+            counts: vec![0; bounds.len()],
+            values: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, value: f64) {
+        // the bucket of a value is the first one whose upper bound is greater or equal
+        match self
+            .counts
+            .get_mut(self.bounds.partition_point(|&bound| bound < value))
+        {
+            Some(count) => *count += 1,
+            None => self.values.push(value),
+        }
+    }
+
+    /// Records the values with as few recorder calls as possible.
+    fn record(&mut self, histogram: &Histogram) {
+        for (&bound, count) in self.bounds.iter().zip(&mut self.counts) {
+            if *count > 0 {
+                histogram.record_many(bound, std::mem::take(count) as usize);
+            }
+        }
+        self.values.sort_unstable_by(f64::total_cmp);
+        for run in self.values.chunk_by(|a, b| a == b) {
+            histogram.record_many(run[0], run.len());
+        }
+        self.values.clear();
+    }
+}
+
+impl LocalAggregate {
+    fn new(config: &LocalAggregationConfig) -> Self {
+        Self {
+            last_flush: Instant::now(),
+            pending_requests: 0,
+            duration_buckets: config.duration_buckets.clone(),
+            body_size_buckets: config.body_size_buckets.clone(),
+            histograms: HashMap::new(),
+        }
+    }
+
+    fn should_flush(&self, config: &LocalAggregationConfig) -> bool {
+        self.pending_requests >= config.flush_threshold
+            || self.last_flush.elapsed() >= config.flush_interval
+    }
+
+    fn flush(&mut self) {
+        for (_, mut samples) in self.histograms.drain() {
+            if let Some(requests) = &samples.handles.requests {
+                requests.increment(samples.requests);
+            }
+            samples.durations.record(&samples.handles.duration);
+            samples
+                .request_body_sizes
+                .record(&samples.handles.request_body_size);
+            samples
+                .response_body_sizes
+                .record(&samples.handles.response_body_size);
+        }
+        self.pending_requests = 0;
+        self.last_flush = Instant::now();
+    }
+}
+
+impl Drop for LocalAggregate {
+    fn drop(&mut self) {
+        // Runs when the worker thread exits, so nothing is lost if the middleware was not dropped
+        // before its thread.
+        self.flush();
+    }
+}
+
+fn sorted(buckets: &[f64]) -> Arc<[f64]> {
+    let mut buckets = buckets.to_vec();
+    buckets.sort_unstable_by(f64::total_cmp);
+    buckets.dedup();
+    buckets.into()
+}
+
+/// Histogram values of a sampled request.
+pub(crate) struct RequestSample {
+    pub(crate) duration: f64,
+    pub(crate) request_size: f64,
+    pub(crate) response_size: f64,
+}
+
+/// Buffers a finished request, and its histogram samples if it is sampled, on the current thread.
+pub(crate) fn record_request(
+    id: u64,
+    config: &LocalAggregationConfig,
+    handles: Arc<RouteHandles>,
+    sample: Option<RequestSample>,
+) {
+    with_aggregate(id, config, |aggregate| {
+        let LocalAggregate {
+            duration_buckets,
+            body_size_buckets,
+            histograms,
+            pending_requests,
+            ..
+        } = aggregate;
+        let samples = histograms
+            .entry(Arc::as_ptr(&handles))
+            .or_insert_with(|| PendingSamples {
+                handles,
+                requests: 0,
+                durations: PendingValues::new(duration_buckets),
+                request_body_sizes: PendingValues::new(body_size_buckets),
+                response_body_sizes: PendingValues::new(body_size_buckets),
+            });
+        samples.requests += 1;
+        if let Some(sample) = sample {
+            samples.durations.push(sample.duration);
+            samples.request_body_sizes.push(sample.request_size);
+            samples.response_body_sizes.push(sample.response_size);
+        }
+        *pending_requests += 1;
+    });
+}
+
+fn with_aggregate(id: u64, config: &LocalAggregationConfig, f: impl FnOnce(&mut LocalAggregate)) {
+    let _ = LOCAL_AGGREGATES.try_with(|aggregates| {
+        let mut aggregates = aggregates.borrow_mut();
+        let aggregate = aggregates
+            .entry(id)
+            .or_insert_with(|| LocalAggregate::new(config));
+        f(aggregate);
+        if aggregate.should_flush(config) {
+            aggregate.flush();
+        }
+    });
+}
+
+/// Flushes and forgets the pending updates of an instance on the current thread.
+pub(crate) fn flush(id: u64) {
+    // dropping the aggregate flushes it
+    let aggregate = LOCAL_AGGREGATES
+        .try_with(|aggregates| aggregates.borrow_mut().remove(&id))
+        .ok()
+        .flatten();
+    drop(aggregate);
+}
+
+/// Flushes the pending updates of an instance every `interval` on the current arbiter, so idle
+/// workers don't hold on to samples, until `stopped` is set.
+pub(crate) fn spawn_interval_flush(id: u64, interval: Duration, stopped: Arc<AtomicBool>) {
+    let Some(arbiter) = actix_rt::Arbiter::try_current() else {
+        return;
+    };
+
+    arbiter.spawn(async move {
+        let mut ticks = actix_rt::time::interval(interval);
+        // the first tick completes immediately
+        ticks.tick().await;
+        loop {
+            ticks.tick().await;
+            if stopped.load(Ordering::Relaxed) {
+                break;
+            }
+            flush(id);
+        }
+    });
+}
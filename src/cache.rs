@@ -0,0 +1,457 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, PoisonError, RwLock};
+use std::time::{Duration, Instant};
+
+use actix_web::http::{Method, StatusCode, Version};
+use log::warn;
+use metrics::{
+    Counter, Gauge, Histogram, Key, Label, Level, Metadata, Recorder, SharedString, Unit,
+};
+
+use crate::MetricsMetadata;
+
+/// Number of handles kept at most, past which handles are registered on every request.
+const MAX_CACHED_HANDLES: usize = 10_000;
+
+static METADATA: Metadata<'static> =
+    Metadata::new(module_path!(), Level::INFO, Some(module_path!()));
+
+/// Handles recorded once per finished request.
+pub(crate) struct RouteHandles {
+    /// Only registered if the request counter is enabled
+    pub(crate) requests: Option<Counter>,
+    pub(crate) duration: Histogram,
+    pub(crate) request_body_size: Histogram,
+    pub(crate) response_body_size: Histogram,
+}
+
+type RouteHandlesKey = (Method, StatusCode, Version);
+
+type RouteHandlesMap = HashMap<String, HashMap<RouteHandlesKey, Arc<RouteHandles>>>;
+
+/// Identity of a recorder, only ever compared and never dereferenced.
+///
+/// The vtable is part of the identity, so zero-sized recorders, which may share an address, are
+/// told apart by their type.
+#[derive(Clone, Copy)]
+struct RecorderId(*const (dyn Recorder + 'static));
+
+// SAFETY: the pointer is only compared, never dereferenced
+unsafe impl Send for RecorderId {}
+unsafe impl Sync for RecorderId {}
+
+impl RecorderId {
+    fn of(recorder: &dyn Recorder) -> Self {
+        let recorder: *const (dyn Recorder + '_) = recorder;
+        // SAFETY: only the lifetime is erased, the pointer is never dereferenced
+        Self(unsafe {
+            std::mem::transmute::<*const (dyn Recorder + '_), *const (dyn Recorder + 'static)>(
+                recorder,
+            )
+        })
+    }
+
+    fn is(self, recorder: &dyn Recorder) -> bool {
+        std::ptr::eq(self.0, recorder)
+    }
+}
+
+/// Whether `recorder` is likely the no-op recorder `metrics` falls back to when no recorder is
+/// installed, which `metrics` has no way to tell.
+///
+/// The fallback is a zero-sized `static`, so it has an address of its own. Other zero-sized
+/// recorders are mostly boxed when installed globally, or promoted constants when installed locally,
+/// both of which are at the dangling address of their alignment instead. Comparing vtables with the
+/// one of `NoopRecorder` is not an option as they are duplicated across codegen units.
+fn is_noop_recorder(recorder: &dyn Recorder) -> bool {
+    let address = (recorder as *const dyn Recorder).cast::<()>().addr();
+    std::mem::size_of_val(recorder) == 0 && address != std::mem::align_of_val(recorder)
+}
+
+/// Handles registered with one recorder.
+#[derive(Default)]
+struct Handles {
+    // `None` until the first lookup
+    recorder: Option<RecorderId>,
+    // when the handles were last dropped, `None` until the first lookup
+    since: Option<Instant>,
+    active_requests: HashMap<Method, HashMap<String, Arc<Gauge>>>,
+    // keyed by scope first, which is empty unless the scope label is enabled
+    routes: HashMap<String, RouteHandlesMap>,
+    cached: usize,
+}
+
+impl Handles {
+    fn is_for(&self, recorder: &dyn Recorder) -> bool {
+        self.recorder.is_some_and(|id| id.is(recorder))
+    }
+
+    /// Whether the handles were registered less than `max_age` ago.
+    fn is_fresh(&self, max_age: Duration) -> bool {
+        self.since.is_some_and(|since| since.elapsed() < max_age)
+    }
+
+    /// Drops the handles of another recorder than `recorder`, returning whether it changed.
+    fn reset_for(&mut self, recorder: &dyn Recorder) -> bool {
+        if self.is_for(recorder) {
+            return false;
+        }
+        *self = Self {
+            recorder: Some(RecorderId::of(recorder)),
+            since: Some(Instant::now()),
+            ..Self::default()
+        };
+        true
+    }
+
+    /// Drops the handles older than `max_age`, keeping the recorder they were registered with.
+    fn expire(&mut self, max_age: Duration) {
+        if self.is_fresh(max_age) {
+            return;
+        }
+        *self = Self {
+            recorder: self.recorder,
+            since: Some(Instant::now()),
+            ..Self::default()
+        };
+    }
+}
+
+/// Registered metric handles, keyed by the label values that identify them.
+///
+/// Handles are registered with the recorder on first use and reused afterwards, so the hot path
+/// neither allocates label sets nor re-hashes metric keys. The maps are nested so lookups can be
+/// done with the borrowed scope, route and scheme of the current request.
+///
+/// Metrics are registered with the bound recorder if there is one, or with the recorder the
+/// `metrics` macros would use otherwise. `metrics` can't tell whether a recorder is installed, so
+/// handles are kept along with the recorder they were registered with, and dropped once another
+/// recorder is resolved, e.g. when the recorder is installed after the first requests. Metrics
+/// are described whenever this happens, so descriptions aren't lost either. A warning is logged
+/// once if the resolved recorder is the no-op recorder.
+///
+/// Only handles whose labels are bounded are cached, i.e. not those of unmatched paths recorded
+/// as-is, and at most [`MAX_CACHED_HANDLES`] of them.
+///
+/// Cached handles are dropped, and registered again on their next use, once they are older than
+/// `max_age`, as the recorder may have dropped the metrics behind them in the meantime, e.g. the
+/// Prometheus exporter with an `idle_timeout`. Nothing is cached if `max_age` is zero.
+pub(crate) struct HandleCache {
+    recorder: Option<Arc<dyn Recorder + Send + Sync>>,
+    max_age: Duration,
+    handles: RwLock<Handles>,
+    warned_noop_recorder: AtomicBool,
+}
+
+impl HandleCache {
+    pub(crate) fn new(
+        recorder: Option<Arc<dyn Recorder + Send + Sync>>,
+        max_age: Duration,
+    ) -> Self {
+        Self {
+            recorder,
+            max_age,
+            handles: RwLock::default(),
+            warned_noop_recorder: AtomicBool::new(false),
+        }
+    }
+
+    pub(crate) fn with_recorder<T>(&self, f: impl FnOnce(&dyn Recorder) -> T) -> T {
+        match &self.recorder {
+            Some(recorder) => f(recorder.as_ref()),
+            None => metrics::with_recorder(f),
+        }
+    }
+
+    /// Looks up a handle with `get`, or registers it with `register` and stores it with `insert`
+    /// if `cache` is set.
+    ///
+    /// Handles of another recorder are dropped, and the metrics described, before registering.
+    /// Handles older than the max age are dropped as well, without describing the metrics again.
+    /// Registering with the no-op recorder is warned about once.
+    fn get_or_register<T: Clone>(
+        &self,
+        names: &MetricsMetadata,
+        cache: bool,
+        get: impl Fn(&Handles) -> Option<&T>,
+        register: impl FnOnce(&dyn Recorder) -> T,
+        insert: impl FnOnce(&mut Handles, T) -> T,
+    ) -> T {
+        let cache = cache && !self.max_age.is_zero();
+        self.with_recorder(|recorder| {
+            let register = |recorder: &dyn Recorder| {
+                if !self.warned_noop_recorder.load(Ordering::Relaxed)
+                    && is_noop_recorder(recorder)
+                    && !self.warned_noop_recorder.swap(true, Ordering::Relaxed)
+                {
+                    warn!(
+                        "actix-web-metrics is recording into the no-op recorder, metrics are \
+                         discarded until a recorder is installed"
+                    );
+                }
+                register(recorder)
+            };
+            {
+                let handles = self.handles.read().unwrap_or_else(PoisonError::into_inner);
+                if handles.is_for(recorder) {
+                    if !cache {
+                        drop(handles);
+                        return register(recorder);
+                    }
+                    if handles.is_fresh(self.max_age) {
+                        if let Some(handle) = get(&handles) {
+                            return handle.clone();
+                        }
+                        if handles.cached >= MAX_CACHED_HANDLES {
+                            drop(handles);
+                            return register(recorder);
+                        }
+                    }
+                }
+            }
+
+            let mut handles = self.handles.write().unwrap_or_else(PoisonError::into_inner);
+            if handles.reset_for(recorder) {
+                describe_active_requests(recorder, names);
+                describe_route_metrics(recorder, names);
+            }
+            handles.expire(self.max_age);
+            if let Some(handle) = get(&handles) {
+                return handle.clone();
+            }
+            if !cache || handles.cached >= MAX_CACHED_HANDLES {
+                drop(handles);
+                return register(recorder);
+            }
+            let handle = register(recorder);
+            handles.cached += 1;
+            insert(&mut handles, handle)
+        })
+    }
+
+    /// Emits the unit and description of every metric.
+    ///
+    /// The handles of another recorder are dropped, so the metrics aren't described again on the
+    /// first lookup.
+    pub(crate) fn describe(&self, names: &MetricsMetadata) {
+        self.with_recorder(|recorder| {
+            self.handles
+                .write()
+                .unwrap_or_else(PoisonError::into_inner)
+                .reset_for(recorder);
+            describe_active_requests(recorder, names);
+            describe_route_metrics(recorder, names);
+        });
+    }
+
+    /// Gauge tracking in-flight requests for a method and url scheme.
+    pub(crate) fn active_requests(
+        &self,
+        names: &MetricsMetadata,
+        method: &Method,
+        scheme: &str,
+    ) -> Arc<Gauge> {
+        self.get_or_register(
+            names,
+            true,
+            |handles| {
+                handles
+                    .active_requests
+                    .get(method)
+                    .and_then(|schemes| schemes.get(scheme))
+            },
+            |recorder| {
+                let mut labels = Vec::with_capacity(2 + names.const_labels.len());
+                labels.push(Label::new(
+                    names.http_request_method.clone(),
+                    method_label(method),
+                ));
+                labels.push(Label::new(names.url_scheme.clone(), scheme_label(scheme)));
+                labels.extend(names.const_labels.iter().cloned());
+
+                let key = Key::from_parts(names.http_server_active_requests.clone(), labels);
+                Arc::new(recorder.register_gauge(&key, &METADATA))
+            },
+            |handles, gauge| {
+                handles
+                    .active_requests
+                    .entry(method.clone())
+                    .or_default()
+                    .entry(scheme.to_string())
+                    .or_insert(gauge)
+                    .clone()
+            },
+        )
+    }
+
+    /// Histograms for a finished request with the given label values, only cached if `cache` is
+    /// set.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn route(
+        &self,
+        names: &MetricsMetadata,
+        cache: bool,
+        scope: Option<&str>,
+        route: &str,
+        method: &Method,
+        status: StatusCode,
+        version: Version,
+    ) -> Arc<RouteHandles> {
+        let handles_key = (method.clone(), status, version);
+
+        self.get_or_register(
+            names,
+            cache,
+            |handles| {
+                handles
+                    .routes
+                    .get(scope.unwrap_or_default())
+                    .and_then(|routes| routes.get(route))
+                    .and_then(|routes| routes.get(&handles_key))
+            },
+            |recorder| {
+                let mut labels = Vec::with_capacity(6 + names.const_labels.len());
+                labels.push(Label::new(names.http_route.clone(), route.to_string()));
+                if let (Some(name), Some(scope)) = (&names.http_scope, scope) {
+                    labels.push(Label::new(name.clone(), scope.to_string()));
+                }
+                labels.push(Label::new(
+                    names.http_request_method.clone(),
+                    method_label(method),
+                ));
+                labels.push(Label::new(
+                    names.http_response_status_code.clone(),
+                    status.as_str().to_string(),
+                ));
+                labels.push(Label::new(names.network_protocol_name.clone(), "http"));
+                if let Some(version) = http_version_label(version) {
+                    labels.push(Label::new(names.network_protocol_version.clone(), version));
+                }
+                labels.extend(names.const_labels.iter().cloned());
+
+                let register = |name: &SharedString| {
+                    let key = Key::from_parts(name.clone(), labels.clone());
+                    recorder.register_histogram(&key, &METADATA)
+                };
+                let (duration, request_body_size, response_body_size) =
+                    match names.route_histograms.get(route) {
+                        Some(histograms) => (
+                            &histograms.duration,
+                            &histograms.request_body_size,
+                            &histograms.response_body_size,
+                        ),
+                        None => (
+                            &names.http_server_request_duration,
+                            &names.http_server_request_body_size,
+                            &names.http_server_response_body_size,
+                        ),
+                    };
+                Arc::new(RouteHandles {
+                    requests: names.http_server_requests.as_ref().map(|name| {
+                        let key = Key::from_parts(name.clone(), labels.clone());
+                        recorder.register_counter(&key, &METADATA)
+                    }),
+                    duration: register(duration),
+                    request_body_size: register(request_body_size),
+                    response_body_size: register(response_body_size),
+                })
+            },
+            |handles, route_handles| {
+                handles
+                    .routes
+                    .entry(scope.unwrap_or_default().to_string())
+                    .or_default()
+                    .entry(route.to_string())
+                    .or_default()
+                    .entry(handles_key.clone())
+                    .or_insert(route_handles)
+                    .clone()
+            },
+        )
+    }
+}
+
+fn describe_active_requests(recorder: &dyn Recorder, names: &MetricsMetadata) {
+    recorder.describe_gauge(
+        names.http_server_active_requests.clone().into(),
+        None,
+        "Number of active HTTP server requests.".into(),
+    );
+}
+
+fn describe_route_metrics(recorder: &dyn Recorder, names: &MetricsMetadata) {
+    if let Some(name) = &names.http_server_requests {
+        recorder.describe_counter(
+            name.clone().into(),
+            None,
+            "Number of HTTP server requests.".into(),
+        );
+    }
+    let routes = names.route_histograms.values().map(|histograms| {
+        (
+            &histograms.duration,
+            &histograms.request_body_size,
+            &histograms.response_body_size,
+        )
+    });
+    let all = std::iter::once((
+        &names.http_server_request_duration,
+        &names.http_server_request_body_size,
+        &names.http_server_response_body_size,
+    ));
+    for (duration, request_body_size, response_body_size) in all.chain(routes) {
+        recorder.describe_histogram(
+            duration.clone().into(),
+            Some(Unit::Seconds),
+            "HTTP request duration in seconds for all requests".into(),
+        );
+        recorder.describe_histogram(
+            request_body_size.clone().into(),
+            Some(Unit::Bytes),
+            "HTTP request size in bytes for all requests".into(),
+        );
+        recorder.describe_histogram(
+            response_body_size.clone().into(),
+            Some(Unit::Bytes),
+            "HTTP response size in bytes for all requests".into(),
+        );
+    }
+}
+
+fn method_label(method: &Method) -> SharedString {
+    let method = match *method {
+        Method::GET => "GET",
+        Method::POST => "POST",
+        Method::PUT => "PUT",
+        Method::DELETE => "DELETE",
+        Method::HEAD => "HEAD",
+        Method::OPTIONS => "OPTIONS",
+        Method::CONNECT => "CONNECT",
+        Method::PATCH => "PATCH",
+        Method::TRACE => "TRACE",
+        _ => return SharedString::from_owned(method.as_str().to_string()),
+    };
+    SharedString::const_str(method)
+}
+
+fn scheme_label(scheme: &str) -> SharedString {
+    match scheme {
+        "http" => SharedString::const_str("http"),
+        "https" => SharedString::const_str("https"),
+        _ => SharedString::from_owned(scheme.to_string()),
+    }
+}
+
+fn http_version_label(version: Version) -> Option<&'static str> {
+    let v = match version {
+        v if v == Version::HTTP_09 => "0.9",
+        v if v == Version::HTTP_10 => "1.0",
+        v if v == Version::HTTP_11 => "1.1",
+        v if v == Version::HTTP_2 => "2",
+        v if v == Version::HTTP_3 => "3",
+        _ => return None,
+    };
+
+    Some(v)
+}
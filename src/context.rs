@@ -0,0 +1,184 @@
+use std::future::Future;
+use std::sync::Arc;
+
+use actix_web::dev::ServiceRequest;
+use actix_web::http::StatusCode;
+use metrics::{
+    Counter, Gauge, Histogram, Key, KeyName, Label, Metadata, Recorder, SharedString, Unit,
+};
+use tokio::task::futures::TaskLocalFuture;
+
+use crate::handle::RequestRules;
+use crate::labeler::RequestPatterns;
+use crate::ActixWebMetrics;
+
+tokio::task_local! {
+    static CONTEXT: RequestContext;
+}
+
+/// Labels of the request handled by the current task.
+///
+/// Set by the middleware while the request is handled when
+/// [`request_context`](crate::ActixWebMetricsBuilder::request_context) is enabled, and added to
+/// the metrics recorded through a [`RequestContextRecorder`]. Tasks spawned by a handler do not
+/// inherit it, it has to be propagated explicitly:
+///
+/// ```rust
+/// use actix_web::HttpResponse;
+/// use actix_web_metrics::RequestContext;
+/// use metrics::counter;
+///
+/// async fn handler() -> HttpResponse {
+///     actix_web::rt::spawn(RequestContext::current().scope(async {
+///         counter!("emails_sent").increment(1);
+///     }));
+///     HttpResponse::Accepted().finish()
+/// }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct RequestContext {
+    // `None` outside of a request, avoiding an allocation per request when disabled
+    labels: Option<Arc<[Label]>>,
+}
+
+impl RequestContext {
+    /// Context of the request handled by the current task, empty outside of a request
+    pub fn current() -> Self {
+        CONTEXT.try_with(Clone::clone).unwrap_or_default()
+    }
+
+    /// Labels of the request
+    pub fn labels(&self) -> &[Label] {
+        self.labels.as_deref().unwrap_or_default()
+    }
+
+    /// Run `fut` within this context, e.g. a task spawned while handling the request
+    pub fn scope<F: Future>(self, fut: F) -> impl Future<Output = F::Output> {
+        CONTEXT.scope(self, fut)
+    }
+
+    /// Run `f` within this context, e.g. on a thread spawned while handling the request
+    pub fn sync_scope<R>(self, f: impl FnOnce() -> R) -> R {
+        CONTEXT.sync_scope(self, f)
+    }
+
+    /// Context of `req`, with the route it would be recorded with if successful.
+    pub(crate) fn for_request(
+        metrics: &ActixWebMetrics,
+        req: &ServiceRequest,
+        rules: &RequestRules,
+        mounted_depth: usize,
+    ) -> Self {
+        let names = &metrics.inner.names;
+        let full_pattern = req.match_pattern();
+        let scope = metrics.scope(full_pattern.as_deref(), mounted_depth);
+        let label = RequestPatterns::new(metrics, req.request(), full_pattern).label(
+            metrics,
+            rules,
+            StatusCode::OK,
+            scope,
+        );
+
+        let mut labels = Vec::with_capacity(3);
+        if let (Some(name), Some(scope)) = (&names.http_scope, label.scope()) {
+            labels.push(Label::new(name.clone(), scope.to_string()));
+        }
+        labels.push(Label::new(
+            names.http_route.clone(),
+            label.route().to_string(),
+        ));
+        labels.push(Label::new(
+            names.http_request_method.clone(),
+            req.method().as_str().to_string(),
+        ));
+        Self {
+            labels: Some(labels.into()),
+        }
+    }
+
+    pub(crate) fn scope_future<F: Future>(self, fut: F) -> TaskLocalFuture<Self, F> {
+        CONTEXT.scope(self, fut)
+    }
+}
+
+/// Records into another recorder, adding the labels of the [`RequestContext`] of the current task.
+///
+/// Labels already set on a metric are kept as-is, so the metrics of the middleware itself are
+/// unchanged. Labels are added when metrics are registered, i.e. on every `counter!`,
+/// `gauge!` or `histogram!` call:
+///
+/// ```rust
+/// use actix_web_metrics::{ActixWebMetricsBuilder, RequestContextRecorder};
+/// use metrics_exporter_prometheus::PrometheusBuilder;
+///
+/// let recorder = PrometheusBuilder::new().build_recorder();
+/// metrics::set_global_recorder(RequestContextRecorder::new(recorder)).unwrap();
+///
+/// let metrics = ActixWebMetricsBuilder::new().request_context(true).build();
+/// ```
+///
+/// NOTE: The route and method of every request become labels of the metrics recorded while
+/// handling it, increasing their cardinality accordingly.
+#[derive(Debug)]
+pub struct RequestContextRecorder<R> {
+    inner: R,
+}
+
+impl<R> RequestContextRecorder<R> {
+    /// Wrap `inner`
+    pub fn new(inner: R) -> Self {
+        Self { inner }
+    }
+
+    /// Get the wrapped recorder back
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    fn with_context(key: &Key) -> Key {
+        CONTEXT
+            .try_with(|context| {
+                let extra: Vec<Label> = context
+                    .labels()
+                    .iter()
+                    .filter(|label| !key.labels().any(|own| own.key() == label.key()))
+                    .cloned()
+                    .collect();
+                if extra.is_empty() {
+                    key.clone()
+                } else {
+                    key.with_extra_labels(extra)
+                }
+            })
+            .unwrap_or_else(|_| key.clone())
+    }
+}
+
+impl<R: Recorder> Recorder for RequestContextRecorder<R> {
+    fn describe_counter(&self, key: KeyName, unit: Option<Unit>, description: SharedString) {
+        self.inner.describe_counter(key, unit, description);
+    }
+
+    fn describe_gauge(&self, key: KeyName, unit: Option<Unit>, description: SharedString) {
+        self.inner.describe_gauge(key, unit, description);
+    }
+
+    fn describe_histogram(&self, key: KeyName, unit: Option<Unit>, description: SharedString) {
+        self.inner.describe_histogram(key, unit, description);
+    }
+
+    fn register_counter(&self, key: &Key, metadata: &Metadata<'_>) -> Counter {
+        self.inner
+            .register_counter(&Self::with_context(key), metadata)
+    }
+
+    fn register_gauge(&self, key: &Key, metadata: &Metadata<'_>) -> Gauge {
+        self.inner
+            .register_gauge(&Self::with_context(key), metadata)
+    }
+
+    fn register_histogram(&self, key: &Key, metadata: &Metadata<'_>) -> Histogram {
+        self.inner
+            .register_histogram(&Self::with_context(key), metadata)
+    }
+}
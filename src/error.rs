@@ -0,0 +1,205 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+
+use regex::{Regex, RegexSet};
+
+/// Error returned for an invalid middleware configuration.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum ConfigError {
+    /// The namespace is empty or contains characters that are invalid in a metric name.
+    InvalidNamespace(String),
+    /// A metric name is empty or contains invalid characters.
+    InvalidMetricName {
+        /// Default name of the metric, e.g. `http.server.request.duration`
+        metric: &'static str,
+        /// Configured name
+        name: String,
+    },
+    /// Two metrics are configured with the same name.
+    DuplicateMetricName(String),
+    /// A label name is empty or contains invalid characters.
+    InvalidLabelName {
+        /// Default name of the label, e.g. `http.route`
+        label: &'static str,
+        /// Configured name
+        name: String,
+    },
+    /// Two labels are configured with the same name.
+    DuplicateLabelName(String),
+    /// A const label name is empty or contains invalid characters.
+    InvalidConstLabelName(String),
+    /// A const label has the same name as one of the labels set by the middleware.
+    ConstLabelCollision(String),
+    /// An exclude regex failed to compile.
+    InvalidExcludeRegex {
+        /// The invalid pattern
+        pattern: String,
+        /// Compilation error
+        source: regex::Error,
+    },
+    /// The mask for unmatched patterns is empty.
+    EmptyUnmatchedPatternsMask,
+    /// The local aggregation flush interval is zero.
+    ZeroFlushInterval,
+    /// An excluded status code is not in the `100..=999` range.
+    InvalidStatusCode(u16),
+    /// An unmatched path normalization rule failed to compile.
+    InvalidNormalizationRule {
+        /// The invalid pattern
+        pattern: String,
+        /// Compilation error
+        source: regex::Error,
+    },
+    /// A sample rate is not in the `0.0..=1.0` range.
+    InvalidSampleRate {
+        /// Route of the override, `None` for the global rate
+        route: Option<String>,
+        /// Configured rate
+        rate: f64,
+    },
+    /// The access log format string is invalid or uses unknown fields.
+    InvalidAccessLogFormat {
+        /// The invalid format string
+        format: String,
+        /// What is invalid, e.g. an unknown field
+        reason: String,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidNamespace(namespace) => write!(f, "invalid namespace `{namespace}`"),
+            Self::InvalidMetricName { metric, name } => {
+                write!(f, "invalid name `{name}` for metric `{metric}`")
+            }
+            Self::DuplicateMetricName(name) => {
+                write!(f, "metric name `{name}` is used for more than one metric")
+            }
+            Self::InvalidLabelName { label, name } => {
+                write!(f, "invalid name `{name}` for label `{label}`")
+            }
+            Self::DuplicateLabelName(name) => {
+                write!(f, "label name `{name}` is used for more than one label")
+            }
+            Self::InvalidConstLabelName(name) => write!(f, "invalid const label name `{name}`"),
+            Self::ConstLabelCollision(name) => write!(
+                f,
+                "const label `{name}` collides with a label set by the middleware"
+            ),
+            Self::InvalidExcludeRegex { pattern, source } => {
+                write!(f, "invalid exclude regex `{pattern}`: {source}")
+            }
+            Self::EmptyUnmatchedPatternsMask => write!(f, "unmatched patterns mask is empty"),
+            Self::ZeroFlushInterval => write!(f, "local aggregation flush interval is zero"),
+            Self::InvalidStatusCode(status) => write!(f, "invalid status code `{status}`"),
+            Self::InvalidNormalizationRule { pattern, source } => {
+                write!(f, "invalid normalization rule `{pattern}`: {source}")
+            }
+            Self::InvalidSampleRate { route: None, rate } => {
+                write!(f, "invalid sample rate `{rate}`")
+            }
+            Self::InvalidSampleRate {
+                route: Some(route),
+                rate,
+            } => write!(f, "invalid sample rate `{rate}` for route `{route}`"),
+            Self::InvalidAccessLogFormat { format, reason } => {
+                write!(f, "invalid access log format `{format}`: {reason}")
+            }
+        }
+    }
+}
+
+impl Error for ConfigError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::InvalidExcludeRegex { source, .. }
+            | Self::InvalidNormalizationRule { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// Metric names may contain `.` as the OpenTelemetry conventions use it, exporters such as
+/// `metrics-exporter-prometheus` replace it with `_`.
+pub(crate) fn is_valid_metric_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || matches!(c, '_' | ':' | '.'))
+        && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | ':' | '.'))
+}
+
+/// Label names starting with `__` are reserved by Prometheus.
+pub(crate) fn is_valid_label_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    !name.starts_with("__")
+        && chars
+            .next()
+            .is_some_and(|c| c.is_ascii_alphabetic() || matches!(c, '_' | '.'))
+        && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.'))
+}
+
+/// Checks the metric names, identified by their default name.
+pub(crate) fn validate_metric_names(names: &[(&'static str, &str)]) -> Result<(), ConfigError> {
+    let mut seen = HashSet::new();
+    for (metric, name) in names {
+        if !is_valid_metric_name(name) {
+            return Err(ConfigError::InvalidMetricName {
+                metric,
+                name: name.to_string(),
+            });
+        }
+        if !seen.insert(*name) {
+            return Err(ConfigError::DuplicateMetricName(name.to_string()));
+        }
+    }
+    Ok(())
+}
+
+/// Checks the label names, identified by their default name, and the const labels.
+pub(crate) fn validate_label_names(
+    labels: &[(&'static str, &str)],
+    const_labels: &HashMap<String, String>,
+) -> Result<(), ConfigError> {
+    let mut seen = HashSet::new();
+    for (label, name) in labels {
+        if !is_valid_label_name(name) {
+            return Err(ConfigError::InvalidLabelName {
+                label,
+                name: name.to_string(),
+            });
+        }
+        if !seen.insert(*name) {
+            return Err(ConfigError::DuplicateLabelName(name.to_string()));
+        }
+    }
+    for name in const_labels.keys() {
+        if !is_valid_label_name(name) {
+            return Err(ConfigError::InvalidConstLabelName(name.clone()));
+        }
+        if seen.contains(name.as_str()) {
+            return Err(ConfigError::ConstLabelCollision(name.clone()));
+        }
+    }
+    Ok(())
+}
+
+/// Compiles the exclude patterns, reporting the first invalid one.
+pub(crate) fn compile_exclude_regex(patterns: &[String]) -> Result<RegexSet, ConfigError> {
+    for pattern in patterns {
+        if let Err(source) = Regex::new(pattern) {
+            return Err(ConfigError::InvalidExcludeRegex {
+                pattern: pattern.clone(),
+                source,
+            });
+        }
+    }
+    // patterns compile on their own, this can only fail if the set exceeds the size limit
+    RegexSet::new(patterns).map_err(|source| ConfigError::InvalidExcludeRegex {
+        pattern: patterns.join("|"),
+        source,
+    })
+}
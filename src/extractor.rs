@@ -0,0 +1,210 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::future::{ready, Ready};
+use std::sync::{PoisonError, RwLock};
+
+use actix_web::dev::Payload;
+use actix_web::http::{Method, StatusCode};
+use actix_web::{error, Error, FromRequest, HttpMessage, HttpRequest};
+use metrics::{Counter, Gauge, Histogram, Key, Label, Level, Metadata, SharedString};
+
+use crate::labeler::{RequestPatterns, RouteLabel};
+use crate::{shared_string, ActixWebMetrics};
+
+/// Number of metric names kept at most, past which names are prefixed on every call.
+const MAX_CACHED_NAMES: usize = 1_000;
+
+static METADATA: Metadata<'static> =
+    Metadata::new(module_path!(), Level::INFO, Some(module_path!()));
+
+/// Names of the metrics recorded through [`RequestMetrics`], prefixed with the namespace once.
+pub(crate) struct NamespacedNames {
+    prefix: String,
+    names: RwLock<HashMap<String, SharedString>>,
+}
+
+impl NamespacedNames {
+    pub(crate) fn new(prefix: String) -> Self {
+        Self {
+            prefix,
+            names: RwLock::default(),
+        }
+    }
+
+    fn get(&self, name: &str) -> SharedString {
+        if let Some(name) = self
+            .names
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(name)
+        {
+            return name.clone();
+        }
+
+        let namespaced = shared_string(format!("{}{name}", self.prefix));
+        let mut names = self.names.write().unwrap_or_else(PoisonError::into_inner);
+        if names.len() < MAX_CACHED_NAMES {
+            names.insert(name.to_string(), namespaced.clone());
+        }
+        namespaced
+    }
+}
+
+/// Inserted in the request extensions by the middleware if
+/// [`request_metrics`](crate::ActixWebMetricsBuilder::request_metrics) is enabled, so handlers can
+/// find it.
+#[derive(Clone)]
+pub(crate) struct InstalledMiddleware {
+    pub(crate) metrics: ActixWebMetrics,
+    pub(crate) mounted_depth: usize,
+}
+
+/// Extractor recording metrics scoped to the current request.
+///
+/// Metrics get the namespace and const labels of the middleware, along with the route and method
+/// labels of the request. The route is the one recorded for a successful response. Extracting
+/// it fails with an internal server error if the request isn't handled by the middleware, or if
+/// [`request_metrics`](crate::ActixWebMetricsBuilder::request_metrics) isn't enabled.
+///
+/// ```rust
+/// use actix_web::{web, App, HttpResponse};
+/// use actix_web_metrics::{ActixWebMetricsBuilder, RequestMetrics};
+///
+/// async fn create_order(metrics: RequestMetrics) -> HttpResponse {
+///     metrics.counter("orders_created").increment(1);
+///     HttpResponse::Created().finish()
+/// }
+///
+/// let metrics = ActixWebMetricsBuilder::new()
+///     .namespace("shop")
+///     .request_metrics(true)
+///     .build();
+/// let app = App::new()
+///     .wrap(metrics)
+///     .service(web::resource("/orders").post(create_order));
+/// ```
+pub struct RequestMetrics {
+    metrics: ActixWebMetrics,
+    label: RouteLabel,
+    method: Method,
+    // computed once, cloning them only clones the shared strings
+    labels: Vec<Label>,
+}
+
+impl fmt::Debug for RequestMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RequestMetrics")
+            .field("label", &self.label)
+            .field("method", &self.method)
+            .finish_non_exhaustive()
+    }
+}
+
+impl RequestMetrics {
+    /// Value of the `http.route` label of the request
+    pub fn route(&self) -> &str {
+        self.label.route()
+    }
+
+    /// Value of the `http.scope` label of the request, `None` unless the scope label is enabled
+    pub fn scope(&self) -> Option<&str> {
+        self.label.scope()
+    }
+
+    /// Method of the request
+    pub fn method(&self) -> &Method {
+        &self.method
+    }
+
+    /// Labels added to the metrics: the scope, route and method of the request and the const
+    /// labels
+    pub fn labels(&self) -> Vec<Label> {
+        self.labels.clone()
+    }
+
+    /// Counter named `name`, prefixed with the namespace
+    pub fn counter(&self, name: &str) -> Counter {
+        let key = self.key(name);
+        self.metrics
+            .inner
+            .handles
+            .with_recorder(|recorder| recorder.register_counter(&key, &METADATA))
+    }
+
+    /// Gauge named `name`, prefixed with the namespace
+    pub fn gauge(&self, name: &str) -> Gauge {
+        let key = self.key(name);
+        self.metrics
+            .inner
+            .handles
+            .with_recorder(|recorder| recorder.register_gauge(&key, &METADATA))
+    }
+
+    /// Histogram named `name`, prefixed with the namespace
+    pub fn histogram(&self, name: &str) -> Histogram {
+        let key = self.key(name);
+        self.metrics
+            .inner
+            .handles
+            .with_recorder(|recorder| recorder.register_histogram(&key, &METADATA))
+    }
+
+    fn key(&self, name: &str) -> Key {
+        let name = self.metrics.inner.custom_names.get(name);
+        Key::from_parts(name, self.labels.clone())
+    }
+}
+
+fn labels(metrics: &ActixWebMetrics, label: &RouteLabel, method: &Method) -> Vec<Label> {
+    let names = &metrics.inner.names;
+    let mut labels = Vec::with_capacity(3 + names.const_labels.len());
+    if let (Some(name), Some(scope)) = (&names.http_scope, label.scope()) {
+        labels.push(Label::new(name.clone(), shared_string(scope.to_string())));
+    }
+    labels.push(Label::new(
+        names.http_route.clone(),
+        shared_string(label.route().to_string()),
+    ));
+    labels.push(Label::new(
+        names.http_request_method.clone(),
+        shared_string(method.as_str().to_string()),
+    ));
+    labels.extend(names.const_labels.iter().cloned());
+    labels
+}
+
+impl FromRequest for RequestMetrics {
+    type Error = Error;
+    type Future = Ready<Result<Self, Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let Some(InstalledMiddleware {
+            metrics,
+            mounted_depth,
+        }) = req.extensions().get::<InstalledMiddleware>().cloned()
+        else {
+            return ready(Err(error::ErrorInternalServerError(
+                "RequestMetrics requires the ActixWebMetrics middleware with request_metrics \
+                 enabled to wrap the handler",
+            )));
+        };
+
+        let rules = metrics.inner.rules.load();
+        let full_pattern = req.match_pattern();
+        let scope = metrics.scope(full_pattern.as_deref(), mounted_depth);
+        let label = RequestPatterns::new(&metrics, req, full_pattern).label(
+            &metrics,
+            &rules,
+            StatusCode::OK,
+            scope,
+        );
+
+        let method = req.method().clone();
+        ready(Ok(Self {
+            labels: labels(&metrics, &label, &method),
+            method,
+            metrics,
+            label,
+        }))
+    }
+}
@@ -0,0 +1,129 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::{Arc, PoisonError, RwLock};
+
+use actix_web::http::StatusCode;
+use regex::RegexSet;
+
+use crate::error::{self, ConfigError};
+
+/// Rules deciding which requests are recorded and how unmatched requests are labelled.
+///
+/// Every request reads the rules once when it starts, so it is recorded consistently even if the
+/// rules are changed while it is in flight.
+#[derive(Debug, Clone)]
+pub(crate) struct RequestRules {
+    pub(crate) exclude: HashSet<String>,
+    pub(crate) exclude_regex: RegexSet,
+    pub(crate) exclude_status: HashSet<StatusCode>,
+    pub(crate) unmatched_patterns_mask: Option<String>,
+}
+
+impl RequestRules {
+    pub(crate) fn is_excluded(&self, pattern: &str, status: StatusCode) -> bool {
+        self.exclude.contains(pattern)
+            || self.exclude_regex.is_match(pattern)
+            || self.exclude_status.contains(&status)
+    }
+}
+
+/// Copy-on-write storage of the current [`RequestRules`].
+pub(crate) struct SharedRules(RwLock<Arc<RequestRules>>);
+
+impl SharedRules {
+    pub(crate) fn new(rules: RequestRules) -> Self {
+        Self(RwLock::new(Arc::new(rules)))
+    }
+
+    pub(crate) fn load(&self) -> Arc<RequestRules> {
+        self.0
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    fn update(&self, f: impl FnOnce(&mut RequestRules)) {
+        // holding the write lock while copying serializes concurrent updates, so none is lost
+        let mut current = self.0.write().unwrap_or_else(PoisonError::into_inner);
+        let mut rules = RequestRules::clone(&current);
+        f(&mut rules);
+        *current = Arc::new(rules);
+    }
+}
+
+/// Handle to change which requests an [`ActixWebMetrics`](crate::ActixWebMetrics) instance
+/// records while the server is running.
+///
+/// Obtained with [`ActixWebMetrics::handle`](crate::ActixWebMetrics::handle). Handles are cheap
+/// to clone and can be used from any thread. Every change is applied atomically; requests that are
+/// in flight keep the rules that were current when they started.
+#[derive(Clone)]
+pub struct ActixWebMetricsHandle {
+    rules: Arc<SharedRules>,
+}
+
+impl ActixWebMetricsHandle {
+    pub(crate) fn new(rules: Arc<SharedRules>) -> Self {
+        Self { rules }
+    }
+
+    /// Replace the paths that are excluded from metrics
+    pub fn set_exclude<I, T>(&self, paths: I)
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<String>,
+    {
+        let exclude = paths.into_iter().map(Into::into).collect();
+        self.rules.update(|rules| rules.exclude = exclude);
+    }
+
+    /// Replace the regexes of paths that are excluded from metrics
+    ///
+    /// The current regexes are kept if one of the patterns is invalid.
+    pub fn set_exclude_regex<I, T>(&self, patterns: I) -> Result<(), ConfigError>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<String>,
+    {
+        let patterns: Vec<String> = patterns.into_iter().map(Into::into).collect();
+        let exclude_regex = error::compile_exclude_regex(&patterns)?;
+        self.rules
+            .update(|rules| rules.exclude_regex = exclude_regex);
+        Ok(())
+    }
+
+    /// Replace the status codes that are excluded from metrics
+    pub fn set_exclude_status<I, T>(&self, statuses: I)
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<StatusCode>,
+    {
+        let exclude_status = statuses.into_iter().map(Into::into).collect();
+        self.rules
+            .update(|rules| rules.exclude_status = exclude_status);
+    }
+
+    /// Replace the mask for requests not matched to a handler, `None` disables masking
+    ///
+    /// WARNING: Disabling masking may lead to unbounded cardinality for unmatched requests.
+    pub fn set_unmatched_patterns_mask<T: Into<String>>(
+        &self,
+        mask: Option<T>,
+    ) -> Result<(), ConfigError> {
+        let mask = mask.map(Into::into);
+        if mask.as_deref() == Some("") {
+            return Err(ConfigError::EmptyUnmatchedPatternsMask);
+        }
+        self.rules
+            .update(|rules| rules.unmatched_patterns_mask = mask);
+        Ok(())
+    }
+}
+
+impl fmt::Debug for ActixWebMetricsHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ActixWebMetricsHandle")
+            .field("rules", &self.rules.load())
+            .finish()
+    }
+}
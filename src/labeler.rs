@@ -0,0 +1,238 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use actix_web::http::StatusCode;
+use actix_web::{HttpMessage, HttpRequest};
+use log::warn;
+use strfmt::strfmt;
+
+use crate::handle::RequestRules;
+use crate::{route, ActixWebMetrics, ActixWebMetricsExtension};
+
+/// Route of a request as recorded in the `http.scope` and `http.route` labels.
+///
+/// Stored in the response extensions by the middleware for every recorded request if
+/// [`route_label_extension`](crate::ActixWebMetricsBuilder::route_label_extension) is enabled:
+///
+/// ```rust
+/// use actix_web::dev::Service;
+/// use actix_web::{App, HttpMessage};
+/// use actix_web_metrics::{ActixWebMetricsBuilder, RouteLabel};
+///
+/// let app = App::new()
+///     .wrap_fn(|req, srv| {
+///         let fut = srv.call(req);
+///         async move {
+///             let res = fut.await?;
+///             if let Some(label) = res.response().extensions().get::<RouteLabel>() {
+///                 println!("{}", label.route());
+///             }
+///             Ok(res)
+///         }
+///     })
+///     .wrap(ActixWebMetricsBuilder::new().route_label_extension(true).build());
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RouteLabel {
+    scope: Option<String>,
+    route: String,
+}
+
+impl RouteLabel {
+    /// Value of the `http.scope` label, `None` unless the scope label is enabled
+    pub fn scope(&self) -> Option<&str> {
+        self.scope.as_deref()
+    }
+
+    /// Value of the `http.route` label
+    pub fn route(&self) -> &str {
+        &self.route
+    }
+}
+
+/// Computes the route labels of requests the same way as the middleware it is created from.
+///
+/// Meant for other middleware, e.g. rate limiters or loggers, keying requests by the same routes
+/// as the metrics:
+///
+/// ```rust
+/// use actix_web::http::StatusCode;
+/// use actix_web::test::TestRequest;
+/// use actix_web_metrics::ActixWebMetricsBuilder;
+///
+/// let metrics = ActixWebMetricsBuilder::new()
+///     .mask_unmatched_patterns("UNKNOWN")
+///     .build();
+/// let labeler = metrics.route_labeler();
+///
+/// let req = TestRequest::with_uri("/missing").to_http_request();
+/// assert_eq!(labeler.label(&req, StatusCode::NOT_FOUND).route(), "UNKNOWN");
+/// ```
+///
+/// Params kept with [`ActixWebMetricsExtension`] are only filled in once the request is routed.
+/// The scope is split off with the [`scope_prefixes`](crate::ActixWebMetricsBuilder::scope_prefixes),
+/// as the scopes the middleware is mounted in are unknown to the labeler.
+#[derive(Clone)]
+pub struct RouteLabeler {
+    metrics: ActixWebMetrics,
+}
+
+impl fmt::Debug for RouteLabeler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RouteLabeler").finish_non_exhaustive()
+    }
+}
+
+impl RouteLabeler {
+    pub(crate) fn new(metrics: ActixWebMetrics) -> Self {
+        Self { metrics }
+    }
+
+    /// Label of `req` answered with `status`
+    pub fn label(&self, req: &HttpRequest, status: StatusCode) -> RouteLabel {
+        let rules = self.metrics.inner.rules.load();
+        let full_pattern = req.match_pattern();
+        let scope = self.metrics.scope(full_pattern.as_deref(), 0);
+        RequestPatterns::new(&self.metrics, req, full_pattern).label(
+            &self.metrics,
+            &rules,
+            status,
+            scope,
+        )
+    }
+}
+
+/// Patterns of a request, before they are normalized into a [`RouteLabel`].
+pub(crate) struct RequestPatterns {
+    // a route pattern with some params not-filled and some params filled in by user-defined
+    pub(crate) mixed: String,
+    pub(crate) fallback: Option<String>,
+    pub(crate) matched: bool,
+}
+
+impl RequestPatterns {
+    pub(crate) fn new(
+        metrics: &ActixWebMetrics,
+        req: &HttpRequest,
+        full_pattern: Option<String>,
+    ) -> Self {
+        let matched = full_pattern.is_some();
+        let resource_name = metrics
+            .inner
+            .use_resource_names
+            .then(|| req.match_name())
+            .flatten();
+
+        // mixed is the final path used as label value in metrics. When some params are kept (to
+        // allow for more cardinality) the plain pattern is kept as fallback.
+        let (mixed, fallback) = match (full_pattern, resource_name) {
+            (None, _) => (req.path().to_string(), None),
+            (Some(_), Some(resource_name)) => (resource_name.to_string(), None),
+            (Some(full_pattern), None) => {
+                // get metrics config for this specific route
+                let extensions = req.extensions();
+                match extensions
+                    .get::<ActixWebMetricsExtension>()
+                    .filter(|config| !config.cardinality_keep_params.is_empty())
+                {
+                    None => (full_pattern, None),
+                    Some(config) => {
+                        let mut params: HashMap<String, String> = HashMap::new();
+
+                        for (key, val) in req.match_info().iter() {
+                            if config.cardinality_keep_params.iter().any(|p| p == key) {
+                                params.insert(key.to_string(), val.to_string());
+                                continue;
+                            }
+                            params.insert(key.to_string(), format!("{{{key}}}"));
+                        }
+
+                        match strfmt(&full_pattern, &params) {
+                            Ok(mixed_cardinality_pattern) => {
+                                (mixed_cardinality_pattern, Some(full_pattern))
+                            }
+                            Err(_) => {
+                                warn!("Cannot build mixed cardinality pattern {full_pattern}, with params {params:?}");
+                                (full_pattern, None)
+                            }
+                        }
+                    }
+                }
+            }
+        };
+
+        Self {
+            mixed,
+            fallback,
+            matched,
+        }
+    }
+
+    /// Normalizes the patterns and splits off the scope, of the form returned by
+    /// [`ActixWebMetrics::scope`].
+    pub(crate) fn label(
+        self,
+        metrics: &ActixWebMetrics,
+        rules: &RequestRules,
+        status: StatusCode,
+        scope: Option<(String, usize)>,
+    ) -> RouteLabel {
+        let this = &*metrics.inner;
+
+        // do not record mixed patterns that were considered invalid by the server
+        let pattern = match self.fallback {
+            Some(fallback) if fallback != self.mixed && (status == 404 || status == 405) => {
+                fallback
+            }
+            _ => self.mixed,
+        };
+
+        let pattern = if self.matched {
+            match this
+                .route_normalizer
+                .as_ref()
+                .and_then(|normalizer| normalizer.normalize(&pattern))
+            {
+                Some(normalized) => normalized,
+                None => pattern,
+            }
+        } else if let Some(normalizer) = &this.unmatched_paths {
+            let overflow = rules
+                .unmatched_patterns_mask
+                .as_deref()
+                .unwrap_or("UNKNOWN");
+            normalizer.label(&pattern, overflow).into_owned()
+        } else if let Some(mask) = &rules.unmatched_patterns_mask {
+            mask.clone()
+        } else {
+            pattern
+        };
+
+        // the route is relative to the scope, unless it isn't a pattern, e.g. a resource name
+        match scope {
+            Some((scope, depth)) => {
+                let route = match route::split_segments(&pattern, depth) {
+                    Some((_, "")) => "/".to_string(),
+                    Some((_, route)) if depth > 0 => route.to_string(),
+                    _ => pattern,
+                };
+                RouteLabel {
+                    scope: Some(scope),
+                    route,
+                }
+            }
+            None => RouteLabel {
+                scope: None,
+                route: pattern,
+            },
+        }
+    }
+
+    /// Label of an excluded request, only reported to the observers.
+    pub(crate) fn excluded_label(self, scope: Option<(String, usize)>) -> RouteLabel {
+        RouteLabel {
+            scope: scope.map(|(scope, _)| scope),
+            route: self.mixed,
+        }
+    }
+}
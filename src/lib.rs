@@ -113,7 +113,7 @@ NOTE: There are 2 important things to note:
 * The `metrics-exporter-prometheus` crate can be swapped for another metrics.rs compatible exporter.
 * The endpoint exposed by `metrics-exporter-prometheus` is not part of the actix web http server.
 
-If you want to expose a prometheus endpoint directly in actix-web see the `prometheus_endpoint.rs` example.
+If you want to expose a prometheus endpoint directly in actix-web see [Serving a Prometheus endpoint](#serving-a-prometheus-endpoint).
 
 # Features
 
@@ -229,32 +229,598 @@ becomes
 ```text
 http_requests_duration_seconds_sum{http_route="UNMATCHED",http_request_method="GET",http_response_status="400"} 0.000424898
 ```
+
+## Worker-local aggregation
+
+At very high request rates, recording every request directly into the global recorder can become measurable.
+`local_aggregation()` buffers the updates on each worker thread and flushes them to the recorder when a threshold of
+requests is reached, when the flush interval elapses and when the worker shuts down.
+
+```rust
+use std::time::Duration;
+
+use actix_web_metrics::{ActixWebMetricsBuilder, LocalAggregationConfig};
+
+ActixWebMetricsBuilder::new()
+    .local_aggregation(
+        LocalAggregationConfig::default()
+            .flush_interval(Duration::from_millis(500))
+            .flush_threshold(256),
+    )
+    .build();
+```
+
+Request counts are summed, while histogram values are kept one by one and only equal values are recorded at once,
+which saves few recorder calls for durations. `duration_buckets()` and `body_size_buckets()` count the values per
+bucket instead, recorded as the upper bound of their bucket: with the same buckets in the exporter the bucket counts
+are exact, the sums are over-estimated by up to the bucket width. The `http.server.active_requests` gauge is always
+updated right away.
+
+## Binding a recorder
+
+By default metrics are recorded into the global recorder, like the `metrics` macros do.
+Use `recorder()` to send the HTTP metrics to a specific recorder instead, e.g. to keep them apart from business
+metrics or to capture them in tests without a thread-local recorder.
+
+```rust
+use std::sync::Arc;
+
+use actix_web_metrics::ActixWebMetricsBuilder;
+use metrics_exporter_prometheus::PrometheusBuilder;
+
+let recorder = PrometheusBuilder::new().build_recorder();
+let handle = recorder.handle();
+
+ActixWebMetricsBuilder::new()
+    .recorder(Arc::new(recorder))
+    .build();
+```
+
+## Installing the recorder after the middleware
+
+Metrics are described (unit and help text) when they are first recorded, so the recorder can be installed after
+the middleware is built. `ActixWebMetrics::describe()` emits the descriptions right away if they should be exposed
+before the first request.
+
+Metric handles are registered again, and the metrics described again, whenever the middleware records into another
+recorder than before, e.g. once the recorder is installed after the first requests were handled.
+
+A warning is logged once if the middleware records into the no-op recorder `metrics` falls back to when no
+recorder is installed.
+
+Metric handles are kept for every route, status and method seen, up to 10,000 of them, so they are not registered
+on every request. Unmatched paths are only kept once masked or normalized. The handles are registered again once
+they are older than `handle_max_age()`, 10 seconds by default, as the recorder may drop the metrics behind them in
+the meantime. With an `idle_timeout` on the Prometheus exporter, keep it at least as long as `handle_max_age()` so
+no update is lost.
+
+## Configuration validation
+
+`build()` panics on an invalid configuration, e.g. an exclude regex that does not compile, a metric name that is
+invalid for Prometheus or a const label that collides with one of the labels set by the middleware.
+Use `try_build()` to handle these mistakes as a [`ConfigError`] instead.
+
+This includes the namespace: an invalid one, e.g. `my-app` or an empty one, is rejected with
+`ConfigError::InvalidNamespace`. This is a breaking change, as such namespaces used to be accepted, so `build()` now
+panics on them.
+
+```rust
+use actix_web_metrics::{ActixWebMetricsBuilder, ConfigError};
+
+let result = ActixWebMetricsBuilder::new()
+    .exclude_regex("/healthz/(")
+    .try_build();
+
+assert!(matches!(result, Err(ConfigError::InvalidExcludeRegex { .. })));
+```
+
+## Loading the configuration from a file
+
+With the `serde` feature, `ActixWebMetricsSettings` can be deserialized from any serde format
+and converted into a builder. Every field is optional and defaults to the builder defaults.
+
+```rust,ignore
+use actix_web_metrics::{ActixWebMetricsBuilder, ActixWebMetricsSettings};
+
+let settings: ActixWebMetricsSettings = toml::from_str(r#"
+    namespace = "my_app"
+    exclude = ["/health"]
+    exclude_status = [404]
+
+    [const_labels]
+    service = "api"
+
+    [metrics.labels]
+    http_route = "route"
+"#)?;
+
+let metrics = ActixWebMetricsBuilder::try_from(settings)?.try_build()?;
+```
+
+## Changing excludes at runtime
+
+`ActixWebMetrics::handle()` returns a cloneable handle to change the excluded paths, regexes and status codes
+and the unmatched patterns mask while the server is running, e.g. to silence a noisy route without a redeploy.
+Every change is applied atomically, requests in flight keep the rules that were current when they started.
+
+```rust
+use actix_web::{web, App, HttpResponse};
+use actix_web_metrics::ActixWebMetricsBuilder;
+
+let metrics = ActixWebMetricsBuilder::new().build();
+let handle = metrics.handle();
+
+let app = App::new()
+    .wrap(metrics)
+    .service(web::resource("/health").to(HttpResponse::Ok));
+
+// later, e.g. from an admin endpoint
+handle.set_exclude(["/health"]);
+handle.set_exclude_regex(["^/internal/.*"]).unwrap();
+handle.set_unmatched_patterns_mask(Some("UNMATCHED")).unwrap();
+```
+
+## Excluding requests with predicates
+
+Besides paths, regexes and status codes, requests can be excluded with predicates. `exclude_request_if` runs
+before the request is handled, so excluded requests, e.g. health checks, are not counted at all and cost close to
+nothing. `exclude_response_if` runs once the handler returned and sees the request and the head of the response.
+`exclude_methods` and `exclude_user_agents` cover the common cases.
+
+```rust
+use actix_web::http::{Method, StatusCode};
+use actix_web_metrics::ActixWebMetricsBuilder;
+
+let metrics = ActixWebMetricsBuilder::new()
+    .exclude_methods([Method::OPTIONS, Method::HEAD])
+    .exclude_user_agents(["kube-probe/", "ELB-HealthChecker/"])
+    .exclude_request_if(|req| req.headers().contains_key("x-synthetic"))
+    .exclude_response_if(|_, res| res.status == StatusCode::NOT_MODIFIED)
+    .build();
+```
+
+## Sampling
+
+At very high request rates, recording the histograms of a fraction of the requests may be enough. With sampling
+enabled, the request histograms are only recorded for sampled requests, and every request is counted exactly by the
+`http.server.requests` counter, so request rates stay unbiased. Rates can be overridden per route.
+
+```rust
+use actix_web_metrics::{ActixWebMetricsBuilder, SamplingConfig};
+
+let metrics = ActixWebMetricsBuilder::new()
+    .sampling(
+        SamplingConfig::default()
+            .rate(0.1)
+            .route("/health", 0.0)
+            .route("/checkout", 1.0),
+    )
+    .build();
+```
+
+## Request counter
+
+Some exporters do not expose the count of histograms, e.g. when converting them to summaries. `request_counter(true)`
+adds a `http.server.requests` counter with the same labels as `http.server.request.duration`. It honours the same
+exclusion rules as the histograms, and its name can be changed with `ActixWebMetricsConfig::http_server_requests_name`.
+
+```rust
+use actix_web_metrics::{ActixWebMetricsBuilder, ActixWebMetricsConfig};
+
+let metrics = ActixWebMetricsBuilder::new()
+    .request_counter(true)
+    .metrics_config(ActixWebMetricsConfig::default().http_server_requests_name("http_requests"))
+    .build();
+```
+
+## Naming and normalizing routes
+
+`use_resource_names(true)` labels `http.route` with the name of the matched resource, when it has one, instead of its
+pattern. Note that actix-web looks names up by path, so resources only differing by guards share the first name.
+A `RouteNormalizer`, e.g. a closure, can rewrite the route of matched requests before it is recorded.
+
+```rust
+use actix_web::{web, App, HttpResponse};
+use actix_web_metrics::ActixWebMetricsBuilder;
+
+let metrics = ActixWebMetricsBuilder::new()
+    .use_resource_names(true)
+    .route_normalizer(|route: &str| {
+        let rest = route.strip_prefix("/v1/").or_else(|| route.strip_prefix("/v2/"))?;
+        Some(format!("/{{version}}/{rest}"))
+    })
+    .build();
+
+let app = App::new().wrap(metrics).service(
+    web::resource("/api/{tenant}/orders/{order_id}/items/{item_id}")
+        .name("order_item")
+        .to(HttpResponse::Ok),
+);
+```
+
+## Normalizing unmatched paths
+
+Between masking every unmatched request and disabling masking, unmatched paths can be normalized: segments such as
+numeric ids, UUIDs, hashes and long slugs are replaced by placeholders and deep paths are truncated, so
+`/users/42/avatar` is labelled `/users/{id}/avatar`. Once a maximum number of distinct paths has been labelled, new
+paths fall back to the mask, so the cardinality stays bounded.
+
+```rust
+use actix_web_metrics::{ActixWebMetricsBuilder, UnmatchedPathsConfig};
+
+let metrics = ActixWebMetricsBuilder::new()
+    .normalize_unmatched_paths(
+        UnmatchedPathsConfig::default()
+            .rule("v[0-9]+", "{version}")
+            .max_depth(3)
+            .max_distinct(50),
+    )
+    .build();
+```
+
+## Tracking unmatched paths
+
+Masking hides what unmatched requests, e.g. from scanners, are hitting. `track_unmatched_paths` counts their raw
+paths in memory, never as labels, keeping the most requested ones with the Space-Saving algorithm so memory stays
+bounded. Excluded requests are tracked as well, e.g. with `exclude_status(StatusCode::NOT_FOUND)`. The top paths are
+returned by `ActixWebMetrics::top_unmatched_paths()` and can be logged periodically.
+
+```rust
+use std::time::Duration;
+
+use actix_web_metrics::{ActixWebMetricsBuilder, TopUnmatchedPathsConfig};
+
+let metrics = ActixWebMetricsBuilder::new()
+    .track_unmatched_paths(
+        TopUnmatchedPathsConfig::default()
+            .capacity(100)
+            .log_interval(Duration::from_secs(300)),
+    )
+    .build();
+
+for entry in metrics.top_unmatched_paths() {
+    println!("{}: {}", entry.path, entry.count);
+}
+```
+
+## Scope labels
+
+With `scope_label(true)`, the prefix of the `web::scope` the middleware is mounted in is recorded in a separate
+`http.scope` label and `http.route` only holds the resource-relative pattern, so the same handlers mounted under
+several scopes share their routes. When the middleware wraps the `App`, the scopes are given as patterns instead,
+the longest one matching the route is used. Unmatched requests have an empty scope.
+
+```rust
+use actix_web::{web, App, HttpResponse};
+use actix_web_metrics::ActixWebMetricsBuilder;
+
+let metrics = ActixWebMetricsBuilder::new().scope_label(true).build();
+
+let app = App::new()
+    .service(
+        web::scope("/api/v1")
+            .wrap(metrics.clone())
+            .service(web::resource("/users/{id}").to(HttpResponse::Ok)),
+    )
+    .service(
+        web::scope("/internal")
+            .wrap(metrics)
+            .service(web::resource("/users/{id}").to(HttpResponse::Ok)),
+    );
+
+// or, wrapping the whole application
+let metrics = ActixWebMetricsBuilder::new()
+    .scope_prefixes(["/api/v1", "/tenants/{tenant}"])
+    .build();
+```
+
+## Serving a Prometheus endpoint
+
+With the `prometheus` feature, `PrometheusEndpoint` serves the metrics of a `PrometheusHandle` from the actix web
+server itself. Its responses are never recorded by the middleware, it can require basic authentication or restrict
+the peer addresses, and it answers in the OpenMetrics format when the `Accept` header prefers it. Pass it to
+`exclude_endpoint` so scrapes are not counted as active requests nor seen by observers either. The upkeep of the
+handle (draining the histograms) runs on every scrape, there is no need to run it periodically.
+
+```rust,ignore
+use actix_web::App;
+use actix_web_metrics::{ActixWebMetricsBuilder, PrometheusEndpoint};
+use metrics_exporter_prometheus::PrometheusBuilder;
+
+let prometheus = PrometheusBuilder::new().install_recorder().unwrap();
+let endpoint = PrometheusEndpoint::new(prometheus)
+    .path("/metrics")
+    .basic_auth("prometheus", "secret")
+    .allow_network("10.0.0.0".parse().unwrap(), 8);
+let metrics = ActixWebMetricsBuilder::new().exclude_endpoint(&endpoint).build();
+
+let app = App::new().wrap(metrics).service(endpoint);
+```
+
+## Histogram buckets
+
+`metrics-exporter-prometheus` exports histograms as summaries unless buckets are set for their exact exported name.
+With the `prometheus` feature, `PrometheusBuilderExt::http_server_buckets` sets the buckets of the request histograms
+for the names resolved by the middleware, including the namespace and custom names. Durations default to the buckets
+recommended by the OpenTelemetry semantic conventions and body sizes to powers of 4, from 64B to 64MiB. The exporter
+selects buckets by metric name only: to give a route buckets of its own, `route_histograms()` records its histograms
+in metrics named with a suffix, e.g. `my_app_http_server_request_duration_upload`, and `HttpServerBuckets::route()`
+sets their buckets.
+
+```rust,ignore
+use actix_web_metrics::{ActixWebMetricsBuilder, HttpServerBuckets, PrometheusBuilderExt};
+use metrics_exporter_prometheus::PrometheusBuilder;
+
+let metrics = ActixWebMetricsBuilder::new()
+    .namespace("my_app")
+    .route_histograms("/upload", "upload")
+    .build();
+let buckets = HttpServerBuckets::default()
+    .duration(&[0.1, 0.5, 1.0])
+    .route("/upload", HttpServerBuckets::default().duration(&[1.0, 10.0, 60.0]));
+PrometheusBuilder::new()
+    .http_server_buckets(&metrics, &buckets)?
+    .install()?;
+```
+
+## Recording into OpenTelemetry
+
+With the `opentelemetry` feature, the middleware records directly into an OpenTelemetry `Meter` with the instruments
+of the semantic conventions: the durations in a `Histogram<f64>` in seconds, with the recommended buckets, the body
+sizes in `Histogram<u64>` in bytes, the active requests in an `UpDownCounter<i64>` and the request counter in a
+`Counter<u64>`. Metric and label names are configured as usual. Other metrics recorded through the middleware, e.g.
+those of `RequestMetrics`, get no unit and the default buckets.
+
+```rust,ignore
+use actix_web_metrics::ActixWebMetricsBuilder;
+use opentelemetry::global;
+
+let metrics = ActixWebMetricsBuilder::new()
+    .opentelemetry_meter(global::meter("actix-web"))
+    .build();
+```
+
+## Pushing metrics
+
+Short-lived servers and batch jobs may never be scraped. With the `push` feature, `PrometheusPusher` pushes the
+exposition of a `PrometheusHandle` to a Pushgateway compatible endpoint periodically, from a dedicated thread, and
+one last time when it is shut down. Failed pushes are retried with a backoff and every attempt has a timeout.
+`shutdown` blocks until the last push is done, while dropping the pusher does not wait for it.
+
+```rust,ignore
+use std::time::Duration;
+
+use actix_web_metrics::{PrometheusPusher, PushConfig};
+use metrics_exporter_prometheus::PrometheusBuilder;
+
+let prometheus = PrometheusBuilder::new().install_recorder()?;
+let pusher = PrometheusPusher::start(
+    prometheus,
+    PushConfig::new("http://pushgateway:9091/metrics/job/worker")
+        .interval(Duration::from_secs(15))
+        .timeout(Duration::from_secs(2))
+        .retries(3),
+);
+
+// run the server, then push one last time, blocking until it is done
+actix_web::rt::task::spawn_blocking(move || pusher.shutdown()).await?;
+```
+
+## Tracing
+
+With the `tracing` feature, the label values computed for a request and its timings are recorded on the span current
+when the middleware is called, e.g. the root span of `tracing-actix-web` when it wraps the middleware, so logs,
+traces and metrics agree on the route. Only the fields declared by the span are recorded: `http.scope`, `http.route`,
+`http.response.status_code`, `http.request.body.size`, `http.response.body.size` and `http.server.request.duration`,
+in seconds. The same fields and `http.request.method` are also emitted in a debug event with the
+`actix_web_metrics` target once the request is recorded.
+
+```rust,ignore
+use actix_web::App;
+use actix_web_metrics::ActixWebMetricsBuilder;
+use tracing_actix_web::TracingLogger;
+
+let app = App::new()
+    .wrap(ActixWebMetricsBuilder::new().build())
+    .wrap(TracingLogger::default());
+```
+
+## Access log
+
+`access_log` writes one line per recorded request, once its response body is sent or dropped, with the route of the
+`http.route` label rather than the raw path, along with the status, whether the body was sent to its end, durations and
+body sizes. Requests the service returned an error for are logged right away with the `error` outcome and an empty
+route. Lines are JSON objects or rendered from a format string, and are written with `log` unless another sink is set.
+
+```rust
+use actix_web_metrics::{AccessLogConfig, ActixWebMetricsBuilder};
+
+let metrics = ActixWebMetricsBuilder::new()
+    .access_log(AccessLogConfig::format(
+        "{method} {route} {status} {duration}s {response_size}B",
+    ))
+    .build();
+
+let metrics = ActixWebMetricsBuilder::new()
+    .access_log(AccessLogConfig::json().sink(|line: &str| println!("{line}")))
+    .build();
+```
+
+## Request observers
+
+`observer` registers a `MetricsObserver` notified when a request starts and once its response body is sent or
+dropped, with the route, status, timings and sizes computed for the metrics, e.g. to feed your own analytics. Requests
+the service returned an error for are ended right away as `failed`, and are not recorded in the metrics.
+
+```rust
+use actix_web_metrics::{ActixWebMetricsBuilder, MetricsObserver, RequestOutcome};
+
+struct Analytics;
+
+impl MetricsObserver for Analytics {
+    fn on_request_end(&self, outcome: &RequestOutcome<'_>) {
+        if !outcome.excluded && !outcome.completed {
+            println!("{} {} aborted by the client", outcome.method, outcome.route);
+        }
+    }
+}
+
+let metrics = ActixWebMetricsBuilder::new().observer(Analytics).build();
+```
+
+## Route labels
+
+With `route_label_extension`, the `http.scope` and `http.route` labels of a recorded request are stored in its
+response extensions as a `RouteLabel`. `route_labeler` returns a `RouteLabeler` computing the same labels from any request, with the kept params, 404/405
+fallback, normalizers and unmatched masking of the middleware, e.g. to key a rate limiter by route.
+
+```rust
+use actix_web::http::StatusCode;
+use actix_web::test::TestRequest;
+use actix_web_metrics::ActixWebMetricsBuilder;
+
+let metrics = ActixWebMetricsBuilder::new()
+    .mask_unmatched_patterns("UNKNOWN")
+    .build();
+let labeler = metrics.route_labeler();
+
+let req = TestRequest::with_uri("/missing").to_http_request();
+assert_eq!(labeler.label(&req, StatusCode::NOT_FOUND).route(), "UNKNOWN");
+```
+
+## Request context for custom metrics
+
+Custom metrics recorded by handlers carry none of the labels of the request by default. With `request_context`, the
+middleware sets a `RequestContext` while each request is handled, and a `RequestContextRecorder` wrapping your recorder
+adds its `http.route` and `http.request.method` labels (and `http.scope` if enabled) to the metrics recorded within.
+Spawned tasks do not inherit the context, propagate it with `RequestContext::current().scope(..)`.
+
+```rust
+use actix_web::HttpResponse;
+use actix_web_metrics::{ActixWebMetricsBuilder, RequestContext, RequestContextRecorder};
+use metrics::counter;
+use metrics_exporter_prometheus::PrometheusBuilder;
+
+let recorder = PrometheusBuilder::new().build_recorder();
+metrics::set_global_recorder(RequestContextRecorder::new(recorder)).unwrap();
+
+let metrics = ActixWebMetricsBuilder::new().request_context(true).build();
+
+async fn create_order() -> HttpResponse {
+    counter!("orders_created").increment(1);
+    actix_web::rt::spawn(RequestContext::current().scope(async {
+        counter!("emails_sent").increment(1);
+    }));
+    HttpResponse::Created().finish()
+}
+```
+
+## Request metrics extractor
+
+Handlers can take a `RequestMetrics` to record business metrics scoped to the current request. Its `counter`, `gauge`
+and `histogram` helpers apply the namespace and const labels of the middleware, along with the route and method labels
+of the request. It is enabled with `request_metrics`, extracting it fails with an internal server error if the
+middleware doesn't wrap the handler or doesn't have it enabled.
+
+```rust
+use actix_web::{web, App, HttpResponse};
+use actix_web_metrics::{ActixWebMetricsBuilder, RequestMetrics};
+
+async fn create_order(metrics: RequestMetrics) -> HttpResponse {
+    metrics.counter("orders_created").increment(1);
+    HttpResponse::Created().finish()
+}
+
+let metrics = ActixWebMetricsBuilder::new()
+    .namespace("shop")
+    .request_metrics(true)
+    .build();
+let app = App::new()
+    .wrap(metrics)
+    .service(web::resource("/orders").post(create_order));
+```
 */
 #![deny(missing_docs)]
+#![cfg_attr(docsrs, feature(doc_cfg))]
+
+mod access_log;
+mod aggregation;
+mod cache;
+mod context;
+mod error;
+mod extractor;
+mod handle;
+mod labeler;
+mod observer;
+#[cfg(feature = "opentelemetry")]
+mod otel;
+#[cfg(feature = "prometheus")]
+mod prometheus;
+#[cfg(feature = "push")]
+mod push;
+mod route;
+mod sampling;
+#[cfg(feature = "serde")]
+mod settings;
+mod top_paths;
+mod trace;
+mod unmatched;
 
 use actix_web::http::Uri;
-use log::warn;
-use metrics::{describe_gauge, describe_histogram, gauge, histogram, Unit};
+use metrics::{Gauge, Label, Recorder, SharedString};
+use std::borrow::Cow;
 use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::future::{ready, Future, Ready};
 use std::marker::PhantomData;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
 use std::task::{Context, Poll};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use actix_web::{
     body::{BodySize, MessageBody},
-    dev::{self, Service, ServiceRequest, ServiceResponse, Transform},
-    http::{Method, StatusCode, Version},
+    dev::{self, ResponseHead, Service, ServiceRequest, ServiceResponse, Transform},
+    http::{header, Method, StatusCode, Version},
     web::Bytes,
-    Error, HttpMessage,
+    Error, HttpMessage, HttpRequest,
 };
 use futures_core::ready;
 use pin_project_lite::pin_project;
-
-use regex::RegexSet;
-use strfmt::strfmt;
+use tokio::task::futures::TaskLocalFuture;
+
+use crate::access_log::{AccessLog, AccessLogEntry, Outcome};
+use crate::aggregation::RequestSample;
+use crate::cache::HandleCache;
+use crate::extractor::{InstalledMiddleware, NamespacedNames};
+use crate::handle::{RequestRules, SharedRules};
+use crate::labeler::RequestPatterns;
+use crate::sampling::Sampler;
+use crate::top_paths::TopUnmatchedPaths;
+use crate::trace::{RequestFields, RequestSpan};
+use crate::unmatched::UnmatchedPathNormalizer;
+
+pub use crate::access_log::{AccessLogConfig, AccessLogSink};
+pub use crate::aggregation::LocalAggregationConfig;
+pub use crate::context::{RequestContext, RequestContextRecorder};
+pub use crate::error::ConfigError;
+pub use crate::extractor::RequestMetrics;
+pub use crate::handle::ActixWebMetricsHandle;
+pub use crate::labeler::{RouteLabel, RouteLabeler};
+pub use crate::observer::{MetricsObserver, RequestOutcome};
+#[cfg(feature = "prometheus")]
+#[cfg_attr(docsrs, doc(cfg(feature = "prometheus")))]
+pub use crate::prometheus::{HttpServerBuckets, PrometheusBuilderExt, PrometheusEndpoint};
+#[cfg(feature = "push")]
+#[cfg_attr(docsrs, doc(cfg(feature = "push")))]
+pub use crate::push::{PrometheusPusher, PushConfig};
+pub use crate::route::RouteNormalizer;
+pub use crate::sampling::SamplingConfig;
+#[cfg(feature = "serde")]
+pub use crate::settings::ActixWebMetricsSettings;
+pub use crate::top_paths::{TopUnmatchedPathsConfig, UnmatchedPathCount};
+pub use crate::unmatched::UnmatchedPathsConfig;
 
 /// ActixWebMetricsExtension define middleware and config struct to change the behaviour of the metrics
 /// struct to define some particularities
@@ -265,17 +831,57 @@ pub struct ActixWebMetricsExtension {
 }
 
 /// Builder to create new [`ActixWebMetrics`] struct.
-#[derive(Debug)]
 pub struct ActixWebMetricsBuilder {
     namespace: Option<String>,
     const_labels: HashMap<String, String>,
     exclude: HashSet<String>,
-    exclude_regex: RegexSet,
+    exclude_regex: Vec<String>,
     exclude_status: HashSet<StatusCode>,
     unmatched_patterns_mask: Option<String>,
+    unmatched_paths: Option<UnmatchedPathsConfig>,
+    top_unmatched_paths: Option<TopUnmatchedPathsConfig>,
     metrics_config: ActixWebMetricsConfig,
+    local_aggregation: Option<LocalAggregationConfig>,
+    sampling: Option<SamplingConfig>,
+    request_counter: bool,
+    route_histograms: Vec<(String, String)>,
+    use_resource_names: bool,
+    route_normalizer: Option<Box<dyn RouteNormalizer>>,
+    scope_label: bool,
+    scope_prefixes: Vec<String>,
+    access_log: Option<AccessLogConfig>,
+    observers: Vec<Box<dyn MetricsObserver>>,
+    request_context: bool,
+    request_metrics: bool,
+    route_label_extension: bool,
+    recorder: Option<RecorderSource>,
+    handle_max_age: Duration,
+    exclude_request_if: Vec<RequestPredicate>,
+    exclude_response_if: Vec<ResponsePredicate>,
 }
 
+/// What the middleware records into, set with [`ActixWebMetricsBuilder::recorder`] or
+/// [`ActixWebMetricsBuilder::opentelemetry_meter`].
+enum RecorderSource {
+    Recorder(Arc<dyn Recorder + Send + Sync>),
+    #[cfg(feature = "opentelemetry")]
+    OpenTelemetry(opentelemetry::metrics::Meter),
+}
+
+impl RecorderSource {
+    #[cfg_attr(not(feature = "opentelemetry"), allow(unused_variables))]
+    fn into_recorder(self, names: &MetricsMetadata) -> Arc<dyn Recorder + Send + Sync> {
+        match self {
+            Self::Recorder(recorder) => recorder,
+            #[cfg(feature = "opentelemetry")]
+            Self::OpenTelemetry(meter) => Arc::new(otel::OpenTelemetryRecorder::new(meter, names)),
+        }
+    }
+}
+
+type RequestPredicate = Box<dyn Fn(&ServiceRequest) -> bool + Send + Sync>;
+type ResponsePredicate = Box<dyn Fn(&HttpRequest, &ResponseHead) -> bool + Send + Sync>;
+
 impl ActixWebMetricsBuilder {
     /// Create new `ActixWebMetricsBuilder`
     pub fn new() -> Self {
@@ -283,10 +889,29 @@ impl ActixWebMetricsBuilder {
             namespace: None,
             const_labels: HashMap::new(),
             exclude: HashSet::new(),
-            exclude_regex: RegexSet::empty(),
+            exclude_regex: Vec::new(),
             exclude_status: HashSet::new(),
             unmatched_patterns_mask: Some("UNKNOWN".to_string()),
+            unmatched_paths: None,
+            top_unmatched_paths: None,
             metrics_config: ActixWebMetricsConfig::default(),
+            local_aggregation: None,
+            sampling: None,
+            request_counter: false,
+            route_histograms: Vec::new(),
+            use_resource_names: false,
+            route_normalizer: None,
+            scope_label: false,
+            scope_prefixes: Vec::new(),
+            access_log: None,
+            observers: Vec::new(),
+            request_context: false,
+            request_metrics: false,
+            route_label_extension: false,
+            recorder: None,
+            handle_max_age: Duration::from_secs(10),
+            exclude_request_if: Vec::new(),
+            exclude_response_if: Vec::new(),
         }
     }
 
@@ -309,10 +934,10 @@ impl ActixWebMetricsBuilder {
     }
 
     /// Ignore and do not record metrics for paths matching the regex.
+    ///
+    /// The regex is compiled by [`ActixWebMetricsBuilder::try_build`].
     pub fn exclude_regex<T: Into<String>>(mut self, path: T) -> Self {
-        let mut patterns = self.exclude_regex.patterns().to_vec();
-        patterns.push(path.into());
-        self.exclude_regex = RegexSet::new(patterns).unwrap();
+        self.exclude_regex.push(path.into());
         self
     }
 
@@ -338,109 +963,490 @@ impl ActixWebMetricsBuilder {
         self
     }
 
+    /// Ignore and do not record metrics for requests matching the predicate.
+    ///
+    /// The predicate runs before the request is handled, matching requests are not counted in
+    /// `http.server.active_requests` either, so they cost close to nothing.
+    pub fn exclude_request_if<F>(mut self, predicate: F) -> Self
+    where
+        F: Fn(&ServiceRequest) -> bool + Send + Sync + 'static,
+    {
+        self.exclude_request_if.push(Box::new(predicate));
+        self
+    }
+
+    /// Ignore the requests of a [`PrometheusEndpoint`] mounted at the root of the app, so scrapes
+    /// are not counted in `http.server.active_requests` nor seen by the observers either.
+    #[cfg(feature = "prometheus")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "prometheus")))]
+    pub fn exclude_endpoint(self, endpoint: &PrometheusEndpoint) -> Self {
+        let path = endpoint.endpoint_path().to_string();
+        self.exclude_request_if(move |req| req.path() == path)
+    }
+
+    /// Ignore and do not record metrics for responses matching the predicate.
+    ///
+    /// The predicate runs once the handler returned, with the request and the head of the response,
+    /// the response body is not available to it.
+    pub fn exclude_response_if<F>(mut self, predicate: F) -> Self
+    where
+        F: Fn(&HttpRequest, &ResponseHead) -> bool + Send + Sync + 'static,
+    {
+        self.exclude_response_if.push(Box::new(predicate));
+        self
+    }
+
+    /// Ignore and do not record metrics for requests with one of the methods, e.g. `OPTIONS`.
+    pub fn exclude_methods<I: IntoIterator<Item = Method>>(self, methods: I) -> Self {
+        let methods: HashSet<Method> = methods.into_iter().collect();
+        self.exclude_request_if(move |req| methods.contains(req.method()))
+    }
+
+    /// Ignore and do not record metrics for requests whose `User-Agent` starts with one of the
+    /// prefixes, e.g. `kube-probe/` or `ELB-HealthChecker/`.
+    pub fn exclude_user_agents<I, T>(self, prefixes: I) -> Self
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<String>,
+    {
+        let prefixes: Vec<String> = prefixes.into_iter().map(Into::into).collect();
+        self.exclude_request_if(move |req| {
+            req.headers()
+                .get(header::USER_AGENT)
+                .and_then(|user_agent| user_agent.to_str().ok())
+                .is_some_and(|user_agent| {
+                    prefixes
+                        .iter()
+                        .any(|prefix| user_agent.starts_with(prefix.as_str()))
+                })
+        })
+    }
+
+    /// Label `http.route` with the name of the matched resource, if it has one, instead of its
+    /// pattern.
+    ///
+    /// Useful to alias long patterns. Resources are named with
+    /// [`Resource::name`](actix_web::Resource::name), excludes then match the name.
+    ///
+    /// NOTE: actix-web looks the name up by the request path, so resources sharing a pattern and
+    /// only differing by guards are all labelled with the name of the first one.
+    pub fn use_resource_names(mut self, enabled: bool) -> Self {
+        self.use_resource_names = enabled;
+        self
+    }
+
+    /// Rewrite the `http.route` label of matched requests, see [`RouteNormalizer`]
+    pub fn route_normalizer<T: RouteNormalizer>(mut self, normalizer: T) -> Self {
+        self.route_normalizer = Some(Box::new(normalizer));
+        self
+    }
+
+    /// Label unmatched requests with their normalized path instead of the mask.
+    ///
+    /// See [`UnmatchedPathsConfig`] for the rules and the distinct paths limit, above which the
+    /// mask is used.
+    pub fn normalize_unmatched_paths(mut self, config: UnmatchedPathsConfig) -> Self {
+        self.unmatched_paths = Some(config);
+        self
+    }
+
+    /// Track the most requested paths of unmatched requests, see [`TopUnmatchedPathsConfig`].
+    ///
+    /// Requests excluded from the metrics are tracked as well. The tracked paths are returned by
+    /// [`ActixWebMetrics::top_unmatched_paths`].
+    pub fn track_unmatched_paths(mut self, config: TopUnmatchedPathsConfig) -> Self {
+        self.top_unmatched_paths = Some(config);
+        self
+    }
+
+    /// Record the scope of matched requests in a `http.scope` label, `http.route` then only holds
+    /// the pattern relative to the scope.
+    ///
+    /// When the middleware wraps a [`Scope`](actix_web::Scope), the scope is the prefix it is
+    /// mounted at, e.g. `/api/v1`. When it wraps the `App`, the scope is the longest of the
+    /// [`scope_prefixes`](ActixWebMetricsBuilder::scope_prefixes) the route starts with. The label
+    /// is empty for other requests, including unmatched ones.
+    pub fn scope_label(mut self, enabled: bool) -> Self {
+        self.scope_label = enabled;
+        self
+    }
+
+    /// Set the scope patterns, e.g. `/api/v1` or `/tenants/{tenant}`, used for the `http.scope`
+    /// label when the middleware wraps the `App`
+    ///
+    /// Enables [`scope_label`](ActixWebMetricsBuilder::scope_label).
+    pub fn scope_prefixes<I, T>(mut self, prefixes: I) -> Self
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<String>,
+    {
+        self.scope_label = true;
+        self.scope_prefixes = prefixes
+            .into_iter()
+            .map(|prefix| prefix.into().trim_end_matches('/').to_string())
+            .collect();
+        self
+    }
+
+    /// Write an access log line per recorded request, with the same route as the metrics.
+    ///
+    /// See [`AccessLogConfig`] for the format and destination of the lines.
+    pub fn access_log(mut self, config: AccessLogConfig) -> Self {
+        self.access_log = Some(config);
+        self
+    }
+
+    /// Notify `observer` when requests start and end.
+    ///
+    /// Observers are called in the order they are added, see [`MetricsObserver`].
+    pub fn observer<O: MetricsObserver>(mut self, observer: O) -> Self {
+        self.observers.push(Box::new(observer));
+        self
+    }
+
+    /// Set the [`RequestContext`] of the requests while they are handled, so the metrics recorded
+    /// by handlers through a [`RequestContextRecorder`] get their route and method labels.
+    ///
+    /// The context route is known before the response, so params kept with
+    /// [`ActixWebMetricsExtension`] are only filled in when the middleware wraps the resource.
+    ///
+    /// Defaults to false
+    pub fn request_context(mut self, enabled: bool) -> Self {
+        self.request_context = enabled;
+        self
+    }
+
+    /// Store the [`RouteLabel`] of the recorded requests in their response extensions, for the
+    /// middleware wrapping this one.
+    ///
+    /// Defaults to false
+    pub fn route_label_extension(mut self, enabled: bool) -> Self {
+        self.route_label_extension = enabled;
+        self
+    }
+
+    /// Let handlers extract [`RequestMetrics`] to record metrics with the labels of the request.
+    ///
+    /// Defaults to false
+    pub fn request_metrics(mut self, enabled: bool) -> Self {
+        self.request_metrics = enabled;
+        self
+    }
+
     /// Set metrics configuration
     pub fn metrics_config(mut self, value: ActixWebMetricsConfig) -> Self {
         self.metrics_config = value;
         self
     }
 
+    /// Buffer metric updates per worker thread and flush them to the recorder periodically.
+    ///
+    /// This reduces the load on the recorder at very high request rates.
+    /// See [`LocalAggregationConfig`] for the flushing guarantees.
+    pub fn local_aggregation(mut self, config: LocalAggregationConfig) -> Self {
+        self.local_aggregation = Some(config);
+        self
+    }
+
+    /// Count requests in a `http.server.requests` counter, with the same labels as the
+    /// `http.server.request.duration` histogram.
+    ///
+    /// Useful with exporters that do not expose the count of histograms, e.g. when converting
+    /// them to summaries. Always enabled with [`ActixWebMetricsBuilder::sampling`].
+    pub fn request_counter(mut self, enabled: bool) -> Self {
+        self.request_counter = enabled;
+        self
+    }
+
+    /// Record the request histograms of `route` in metrics of their own, named after the
+    /// histograms with `.{suffix}` appended, e.g. `http.server.request.duration.upload`.
+    ///
+    /// `route` is compared to the `http.route` label. Meant for exporters selecting the histogram
+    /// buckets by metric name, so a route can get its own buckets, see
+    /// [`HttpServerBuckets::route`](crate::HttpServerBuckets::route).
+    pub fn route_histograms<R: Into<String>, S: Into<String>>(
+        mut self,
+        route: R,
+        suffix: S,
+    ) -> Self {
+        self.route_histograms.push((route.into(), suffix.into()));
+        self
+    }
+
+    /// Record the request histograms for a fraction of the requests only.
+    ///
+    /// Requests are then counted exactly by the `http.server.requests` counter.
+    /// See [`SamplingConfig`] for the rates.
+    pub fn sampling(mut self, config: SamplingConfig) -> Self {
+        self.sampling = Some(config);
+        self
+    }
+
+    /// Record metrics into the given recorder instead of the global one.
+    ///
+    /// Metrics recorded with the `metrics` macros, e.g. in handlers, are not affected.
+    pub fn recorder(mut self, recorder: Arc<dyn Recorder + Send + Sync>) -> Self {
+        self.recorder = Some(RecorderSource::Recorder(recorder));
+        self
+    }
+
+    /// Set how long registered metric handles are reused before being registered again, 10
+    /// seconds by default.
+    ///
+    /// The recorder may drop metrics that are not updated for a while, e.g. the Prometheus
+    /// exporter with an `idle_timeout`, after which updates through their handles are lost until
+    /// they are registered again. Use a max age no longer than the idle timeout so no update is
+    /// lost. A zero max age registers the handles on every request.
+    pub fn handle_max_age(mut self, max_age: Duration) -> Self {
+        self.handle_max_age = max_age;
+        self
+    }
+
+    /// Record metrics into an OpenTelemetry `Meter` instead of a `metrics` recorder.
+    ///
+    /// The request durations are recorded in a `Histogram<f64>` in seconds, with the buckets
+    /// recommended by the semantic conventions, the body sizes in `Histogram<u64>` in bytes, the
+    /// active requests in an `UpDownCounter<i64>` and the request counter in a `Counter<u64>`.
+    /// Metric and label names are configured as usual.
+    ///
+    /// Other metrics, e.g. those of [`RequestMetrics`], are recorded in a `Counter<u64>`, an
+    /// `UpDownCounter<i64>` or a `Histogram<f64>` without a unit and with the default buckets.
+    #[cfg(feature = "opentelemetry")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "opentelemetry")))]
+    pub fn opentelemetry_meter(mut self, meter: opentelemetry::metrics::Meter) -> Self {
+        self.recorder = Some(RecorderSource::OpenTelemetry(meter));
+        self
+    }
+
     /// Instantiate `ActixWebMetrics` struct
     ///
-    /// WARNING: This call purposefully leaks the memory of metrics and label names to avoid
-    /// allocations during runtime. Avoid calling more than once.
+    /// # Panics
+    ///
+    /// Panics if the configuration is invalid, see [`ActixWebMetricsBuilder::try_build`].
     pub fn build(self) -> ActixWebMetrics {
+        self.try_build()
+            .unwrap_or_else(|err| panic!("invalid actix-web-metrics configuration: {err}"))
+    }
+
+    /// Instantiate `ActixWebMetrics` struct, validating the configuration
+    pub fn try_build(self) -> Result<ActixWebMetrics, ConfigError> {
+        self.validate()?;
+        let exclude_regex = error::compile_exclude_regex(&self.exclude_regex)?;
+        let unmatched_paths = self
+            .unmatched_paths
+            .map(UnmatchedPathNormalizer::new)
+            .transpose()?;
+        let access_log = self.access_log.map(AccessLog::new).transpose()?;
+
         let namespace_prefix = if let Some(ns) = self.namespace {
             format!("{ns}_")
         } else {
             "".to_string()
         };
+        let metric_name = |name: String| shared_string(format!("{namespace_prefix}{name}"));
+        let config = self.metrics_config;
+
+        let route_histograms = self
+            .route_histograms
+            .into_iter()
+            .map(|(route, suffix)| {
+                let names = HistogramNames {
+                    duration: metric_name(format!(
+                        "{}.{suffix}",
+                        config.http_server_request_duration_name
+                    )),
+                    request_body_size: metric_name(format!(
+                        "{}.{suffix}",
+                        config.http_server_request_body_size_name
+                    )),
+                    response_body_size: metric_name(format!(
+                        "{}.{suffix}",
+                        config.http_server_response_body_size_name
+                    )),
+                };
+                (route, names)
+            })
+            .collect();
+        let names = MetricsMetadata {
+            http_server_request_duration: metric_name(config.http_server_request_duration_name),
+            http_server_request_body_size: metric_name(config.http_server_request_body_size_name),
+            http_server_response_body_size: metric_name(config.http_server_response_body_size_name),
+            http_server_active_requests: metric_name(config.http_server_active_requests_name),
+            http_server_requests: (self.request_counter || self.sampling.is_some())
+                .then(|| metric_name(config.http_server_requests_name)),
+            route_histograms,
+            http_route: shared_string(config.labels.http_route),
+            http_request_method: shared_string(config.labels.http_request_method),
+            http_response_status_code: shared_string(config.labels.http_response_status_code),
+            network_protocol_name: shared_string(config.labels.network_protocol_name),
+            network_protocol_version: shared_string(config.labels.network_protocol_version),
+            url_scheme: shared_string(config.labels.url_scheme),
+            http_scope: self
+                .scope_label
+                .then(|| shared_string(config.labels.http_scope)),
+            const_labels: {
+                let mut const_labels: Vec<Label> = self
+                    .const_labels
+                    .into_iter()
+                    .map(|(k, v)| Label::new(shared_string(k), shared_string(v)))
+                    .collect();
+                const_labels.sort_by(|a, b| a.key().cmp(b.key()));
+                const_labels
+            },
+        };
 
-        let http_server_request_duration_name = format!(
-            "{namespace_prefix}{}",
-            self.metrics_config.http_server_request_duration_name
-        );
-        describe_histogram!(
-            http_server_request_duration_name.clone(),
-            Unit::Seconds,
-            "HTTP request duration in seconds for all requests"
-        );
+        let recorder = self.recorder.map(|source| source.into_recorder(&names));
+        let handles = HandleCache::new(recorder, self.handle_max_age);
+        handles.describe(&names);
 
-        let http_server_request_body_size_name = format!(
-            "{namespace_prefix}{}",
-            self.metrics_config.http_server_request_body_size_name
-        );
-        describe_histogram!(
-            http_server_request_body_size_name.clone(),
-            Unit::Bytes,
-            "HTTP request size in bytes for all requests"
-        );
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
 
-        let http_server_response_body_size_name = format!(
-            "{namespace_prefix}{}",
-            self.metrics_config.http_server_response_body_size_name
-        );
-        describe_histogram!(
-            http_server_response_body_size_name.clone(),
-            Unit::Bytes,
-            "HTTP response size in bytes for all requests"
-        );
+        Ok(ActixWebMetrics {
+            inner: Arc::new(ActixWebMetricsInner {
+                id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+                rules: Arc::new(SharedRules::new(RequestRules {
+                    exclude: self.exclude,
+                    exclude_regex,
+                    exclude_status: self.exclude_status,
+                    unmatched_patterns_mask: self.unmatched_patterns_mask,
+                })),
+                names,
+                custom_names: NamespacedNames::new(namespace_prefix),
+                handles,
+                local_aggregation: self.local_aggregation,
+                sampler: self.sampling.map(Sampler::new),
+                use_resource_names: self.use_resource_names,
+                unmatched_paths,
+                top_unmatched_paths: self
+                    .top_unmatched_paths
+                    .as_ref()
+                    .map(|config| Arc::new(TopUnmatchedPaths::new(config))),
+                route_normalizer: self.route_normalizer,
+                scope_prefixes: self.scope_prefixes,
+                access_log,
+                observers: self.observers,
+                request_context: self.request_context,
+                request_metrics: self.request_metrics,
+                route_label_extension: self.route_label_extension,
+                exclude_request_if: self.exclude_request_if,
+                exclude_response_if: self.exclude_response_if,
+            }),
+        })
+    }
 
-        let http_server_active_requests_name = format!(
-            "{namespace_prefix}{}",
-            self.metrics_config.http_server_active_requests_name
-        );
-        describe_gauge!(
-            http_server_active_requests_name.clone(),
-            "Number of active HTTP server requests."
-        );
+    fn validate(&self) -> Result<(), ConfigError> {
+        if let Some(namespace) = &self.namespace {
+            if !error::is_valid_metric_name(namespace) {
+                return Err(ConfigError::InvalidNamespace(namespace.clone()));
+            }
+        }
 
-        let mut const_labels: Vec<(&'static str, String)> = self
-            .const_labels
+        let config = &self.metrics_config;
+        let mut metric_names = vec![
+            (
+                "http.server.request.duration",
+                config.http_server_request_duration_name.as_str(),
+            ),
+            (
+                "http.server.request.body.size",
+                &config.http_server_request_body_size_name,
+            ),
+            (
+                "http.server.response.body.size",
+                &config.http_server_response_body_size_name,
+            ),
+            (
+                "http.server.active_requests",
+                &config.http_server_active_requests_name,
+            ),
+        ];
+        if self.request_counter || self.sampling.is_some() {
+            metric_names.push(("http.server.requests", &config.http_server_requests_name));
+        }
+        let route_histogram_names: Vec<(&'static str, String)> = self
+            .route_histograms
             .iter()
-            .map(|(k, v)| {
-                let k: &'static str = Box::leak(Box::new(k.clone()));
-                (k, v.clone())
+            .flat_map(|(_, suffix)| {
+                metric_names[..3]
+                    .iter()
+                    .map(move |(metric, name)| (*metric, format!("{name}.{suffix}")))
             })
             .collect();
-        const_labels.sort_by_key(|v| v.0);
+        metric_names.extend(
+            route_histogram_names
+                .iter()
+                .map(|(metric, name)| (*metric, name.as_str())),
+        );
+        error::validate_metric_names(&metric_names)?;
+
+        let labels = &config.labels;
+        let mut label_names = vec![
+            ("http.route", labels.http_route.as_str()),
+            ("http.request.method", &labels.http_request_method),
+            (
+                "http.response.status_code",
+                &labels.http_response_status_code,
+            ),
+            ("network.protocol.name", &labels.network_protocol_name),
+            ("network.protocol.version", &labels.network_protocol_version),
+            ("url.scheme", &labels.url_scheme),
+        ];
+        if self.scope_label {
+            label_names.push(("http.scope", &labels.http_scope));
+        }
+        error::validate_label_names(&label_names, &self.const_labels)?;
 
-        ActixWebMetrics {
-            inner: Arc::new(ActixWebMetricsInner {
-                exclude: self.exclude,
-                exclude_regex: self.exclude_regex,
-                exclude_status: self.exclude_status,
-                unmatched_patterns_mask: self.unmatched_patterns_mask,
-                names: MetricsMetadata {
-                    http_server_request_duration: Box::leak(Box::new(
-                        http_server_request_duration_name,
-                    )),
-                    http_server_request_body_size: Box::leak(Box::new(
-                        http_server_request_body_size_name,
-                    )),
-                    http_server_response_body_size: Box::leak(Box::new(
-                        http_server_response_body_size_name,
-                    )),
-                    http_server_active_requests: Box::leak(Box::new(
-                        http_server_active_requests_name,
-                    )),
-                    http_route: Box::leak(Box::new(self.metrics_config.labels.http_route)),
-                    http_request_method: Box::leak(Box::new(
-                        self.metrics_config.labels.http_request_method,
-                    )),
-                    http_response_status_code: Box::leak(Box::new(
-                        self.metrics_config.labels.http_response_status_code,
-                    )),
-                    network_protocol_name: Box::leak(Box::new(
-                        self.metrics_config.labels.network_protocol_name,
-                    )),
-                    network_protocol_version: Box::leak(Box::new(
-                        self.metrics_config.labels.network_protocol_version,
-                    )),
-                    url_scheme: Box::leak(Box::new(self.metrics_config.labels.url_scheme)),
-                    const_labels,
-                },
-            }),
+        if self.unmatched_patterns_mask.as_deref() == Some("") {
+            return Err(ConfigError::EmptyUnmatchedPatternsMask);
         }
+
+        if let Some(config) = &self.local_aggregation {
+            if config.interval().is_zero() {
+                return Err(ConfigError::ZeroFlushInterval);
+            }
+        }
+
+        if let Some(config) = &self.sampling {
+            config.validate()?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Debug for ActixWebMetricsBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ActixWebMetricsBuilder")
+            .field("namespace", &self.namespace)
+            .field("const_labels", &self.const_labels)
+            .field("exclude", &self.exclude)
+            .field("exclude_regex", &self.exclude_regex)
+            .field("exclude_status", &self.exclude_status)
+            .field("unmatched_patterns_mask", &self.unmatched_patterns_mask)
+            .field("unmatched_paths", &self.unmatched_paths)
+            .field("top_unmatched_paths", &self.top_unmatched_paths)
+            .field("metrics_config", &self.metrics_config)
+            .field("local_aggregation", &self.local_aggregation)
+            .field("sampling", &self.sampling)
+            .field("request_counter", &self.request_counter)
+            .field("route_histograms", &self.route_histograms)
+            .field("use_resource_names", &self.use_resource_names)
+            .field(
+                "route_normalizer",
+                &self.route_normalizer.as_ref().map(|_| "RouteNormalizer"),
+            )
+            .field("scope_label", &self.scope_label)
+            .field("scope_prefixes", &self.scope_prefixes)
+            .field("access_log", &self.access_log)
+            .field("observers", &self.observers.len())
+            .field("request_context", &self.request_context)
+            .field("request_metrics", &self.request_metrics)
+            .field("route_label_extension", &self.route_label_extension)
+            .field("recorder", &self.recorder.as_ref().map(|_| "Recorder"))
+            .field("handle_max_age", &self.handle_max_age)
+            .field("exclude_request_if", &self.exclude_request_if.len())
+            .field("exclude_response_if", &self.exclude_response_if.len())
+            .finish()
     }
 }
 
@@ -451,7 +1457,12 @@ impl Default for ActixWebMetricsBuilder {
 }
 
 /// Configuration for the labels used in metrics
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default, deny_unknown_fields)
+)]
 pub struct LabelsConfig {
     http_route: String,
     http_request_method: String,
@@ -459,6 +1470,7 @@ pub struct LabelsConfig {
     network_protocol_name: String,
     network_protocol_version: String,
     url_scheme: String,
+    http_scope: String,
 }
 
 impl Default for LabelsConfig {
@@ -470,6 +1482,7 @@ impl Default for LabelsConfig {
             network_protocol_name: String::from("network.protocol.name"),
             network_protocol_version: String::from("network.protocol.version"),
             url_scheme: String::from("url.scheme"),
+            http_scope: String::from("http.scope"),
         }
     }
 }
@@ -510,17 +1523,29 @@ impl LabelsConfig {
         self.url_scheme = name.into();
         self
     }
+
+    /// set http scope label, see [`ActixWebMetricsBuilder::scope_label`]
+    pub fn http_scope<T: Into<String>>(mut self, name: T) -> Self {
+        self.http_scope = name.into();
+        self
+    }
 }
 
 /// Configuration for the collected metrics
 ///
 /// Stores individual metric configuration objects
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default, deny_unknown_fields)
+)]
 pub struct ActixWebMetricsConfig {
     http_server_request_duration_name: String,
     http_server_request_body_size_name: String,
     http_server_response_body_size_name: String,
     http_server_active_requests_name: String,
+    http_server_requests_name: String,
     labels: LabelsConfig,
 }
 
@@ -531,6 +1556,7 @@ impl Default for ActixWebMetricsConfig {
             http_server_request_body_size_name: String::from("http.server.request.body.size"),
             http_server_response_body_size_name: String::from("http.server.response.body.size"),
             http_server_active_requests_name: String::from("http.server.active_requests"),
+            http_server_requests_name: String::from("http.server.requests"),
             labels: LabelsConfig::default(),
         }
     }
@@ -566,25 +1592,46 @@ impl ActixWebMetricsConfig {
         self.http_server_active_requests_name = name.into();
         self
     }
+
+    /// Set name for `http.server.requests` metric
+    ///
+    /// See [`ActixWebMetricsBuilder::request_counter`]
+    pub fn http_server_requests_name<T: Into<String>>(mut self, name: T) -> Self {
+        self.http_server_requests_name = name.into();
+        self
+    }
 }
 
-/// Static references to variable metrics/label names.
-/// This config primarily exists to avoid allocations during execution.
+/// Shared references to variable metrics/label names.
+/// This config primarily exists to avoid allocations during execution, cloning a name only
+/// increments a reference count.
 #[derive(Debug, Clone)]
 struct MetricsMetadata {
     // metric names
-    http_server_request_duration: &'static str,
-    http_server_request_body_size: &'static str,
-    http_server_response_body_size: &'static str,
-    http_server_active_requests: &'static str,
+    http_server_request_duration: SharedString,
+    http_server_request_body_size: SharedString,
+    http_server_response_body_size: SharedString,
+    http_server_active_requests: SharedString,
+    http_server_requests: Option<SharedString>,
+    // histograms of the routes recorded apart, by route
+    route_histograms: HashMap<String, HistogramNames>,
     // label names
-    http_route: &'static str,
-    http_request_method: &'static str,
-    http_response_status_code: &'static str,
-    network_protocol_name: &'static str,
-    network_protocol_version: &'static str,
-    url_scheme: &'static str,
-    const_labels: Vec<(&'static str, String)>,
+    http_route: SharedString,
+    http_request_method: SharedString,
+    http_response_status_code: SharedString,
+    network_protocol_name: SharedString,
+    network_protocol_version: SharedString,
+    url_scheme: SharedString,
+    http_scope: Option<SharedString>,
+    const_labels: Vec<Label>,
+}
+
+/// Names of the request histograms of a route recorded apart.
+#[derive(Debug, Clone)]
+struct HistogramNames {
+    duration: SharedString,
+    request_body_size: SharedString,
+    response_body_size: SharedString,
 }
 
 /// An actix-web middleware the records metrics.
@@ -596,133 +1643,294 @@ pub struct ActixWebMetrics {
     inner: Arc<ActixWebMetricsInner>,
 }
 
+impl fmt::Debug for ActixWebMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ActixWebMetrics")
+            .field("names", &self.inner.names)
+            .finish_non_exhaustive()
+    }
+}
+
 struct ActixWebMetricsInner {
+    pub(crate) id: u64,
     pub(crate) names: MetricsMetadata,
-
-    pub(crate) exclude: HashSet<String>,
-    pub(crate) exclude_regex: RegexSet,
-    pub(crate) exclude_status: HashSet<StatusCode>,
-    pub(crate) unmatched_patterns_mask: Option<String>,
+    pub(crate) custom_names: NamespacedNames,
+    pub(crate) handles: HandleCache,
+    pub(crate) local_aggregation: Option<LocalAggregationConfig>,
+    pub(crate) sampler: Option<Sampler>,
+    pub(crate) use_resource_names: bool,
+    pub(crate) unmatched_paths: Option<UnmatchedPathNormalizer>,
+    pub(crate) top_unmatched_paths: Option<Arc<TopUnmatchedPaths>>,
+    pub(crate) route_normalizer: Option<Box<dyn RouteNormalizer>>,
+    pub(crate) scope_prefixes: Vec<String>,
+    pub(crate) access_log: Option<AccessLog>,
+    pub(crate) observers: Vec<Box<dyn MetricsObserver>>,
+    pub(crate) request_context: bool,
+    pub(crate) request_metrics: bool,
+    pub(crate) route_label_extension: bool,
+    pub(crate) rules: Arc<SharedRules>,
+    pub(crate) exclude_request_if: Vec<RequestPredicate>,
+    pub(crate) exclude_response_if: Vec<ResponsePredicate>,
 }
 
 impl ActixWebMetrics {
-    fn pre_request_update_metrics(&self, req: &ServiceRequest) {
+    /// Emit the unit and description of the metrics to the recorder.
+    ///
+    /// Metrics are also described when they are first recorded, this is only needed to expose
+    /// the descriptions before the first request.
+    pub fn describe(&self) {
+        self.inner.handles.describe(&self.inner.names);
+    }
+
+    /// Get a handle to change the excludes and the unmatched patterns mask at runtime.
+    pub fn handle(&self) -> ActixWebMetricsHandle {
+        ActixWebMetricsHandle::new(self.inner.rules.clone())
+    }
+
+    /// Get a labeler computing the `http.route` labels of requests like the middleware does.
+    pub fn route_labeler(&self) -> RouteLabeler {
+        RouteLabeler::new(self.clone())
+    }
+
+    /// Most requested paths of unmatched requests, most requested first.
+    ///
+    /// Empty unless [`ActixWebMetricsBuilder::track_unmatched_paths`] is enabled.
+    pub fn top_unmatched_paths(&self) -> Vec<UnmatchedPathCount> {
+        self.inner
+            .top_unmatched_paths
+            .as_ref()
+            .map(|tracker| tracker.top())
+            .unwrap_or_default()
+    }
+
+    /// Flush the metric updates buffered by the current thread.
+    ///
+    /// Only relevant when [`ActixWebMetricsBuilder::local_aggregation`] is enabled, updates are
+    /// otherwise recorded directly.
+    pub fn flush_local_aggregate(&self) {
+        if self.inner.local_aggregation.is_some() {
+            aggregation::flush(self.inner.id);
+        }
+    }
+
+    /// Increments the active requests gauge, returning it to be decremented once the request ends.
+    fn pre_request_update_metrics(&self, req: &ServiceRequest) -> Arc<Gauge> {
         let this = &*self.inner;
 
-        let mut labels = Vec::with_capacity(2 + this.names.const_labels.len());
-        labels.push((
-            this.names.http_request_method,
-            req.method().as_str().to_string(),
-        ));
-        labels.push((this.names.url_scheme, url_scheme(&req.uri()).to_string()));
-        for (k, v) in &this.names.const_labels {
-            labels.push((k, v.clone()));
+        let gauge = this
+            .handles
+            .active_requests(&this.names, req.method(), url_scheme(req.uri()));
+        gauge.increment(1);
+        gauge
+    }
+
+    /// Scope pattern of a request and its number of segments, if the scope label is enabled.
+    fn scope(&self, full_pattern: Option<&str>, mounted_depth: usize) -> Option<(String, usize)> {
+        self.inner.names.http_scope.as_ref()?;
+
+        let depth = match full_pattern {
+            None => 0,
+            Some(_) if mounted_depth > 0 => mounted_depth,
+            Some(pattern) => route::prefix_depth(&self.inner.scope_prefixes, pattern),
+        };
+        match full_pattern.and_then(|pattern| route::split_segments(pattern, depth)) {
+            Some((scope, _)) => Some((scope.to_string(), depth)),
+            None => Some((String::new(), 0)),
         }
+    }
 
-        gauge!(this.names.http_server_active_requests, &labels).increment(1);
+    fn is_request_excluded(&self, req: &ServiceRequest) -> bool {
+        self.inner
+            .exclude_request_if
+            .iter()
+            .any(|predicate| predicate(req))
+    }
+
+    fn is_response_excluded<B>(&self, res: &ServiceResponse<B>) -> bool {
+        self.inner
+            .exclude_response_if
+            .iter()
+            .any(|predicate| predicate(res.request(), res.response().head()))
+    }
+
+    /// Ends a request the service returned an error for, which is not recorded in the metrics.
+    fn request_failed(
+        &self,
+        active_requests: &Gauge,
+        head: Option<ObservedHead>,
+        err: &Error,
+        clock: &Instant,
+    ) {
+        active_requests.decrement(1);
+        let Some(head) = head else {
+            return;
+        };
+        let elapsed = clock.elapsed();
+        let status = err.as_response_error().status_code();
+        if let Some(access_log) = &self.inner.access_log {
+            access_log.write(&AccessLogEntry {
+                method: head.method.as_str(),
+                scope: None,
+                route: "",
+                status: status.as_u16(),
+                outcome: Outcome::Error,
+                duration: elapsed,
+                handler_duration: elapsed,
+                request_size: 0,
+                response_size: 0,
+            });
+        }
+        if self.inner.observers.is_empty() {
+            return;
+        }
+        let outcome = RequestOutcome {
+            route: "",
+            scope: None,
+            method: &head.method,
+            status,
+            scheme: &head.scheme,
+            version: head.version,
+            duration: elapsed,
+            handler_duration: elapsed,
+            request_size: 0,
+            response_size: 0,
+            matched: false,
+            completed: false,
+            excluded: false,
+            failed: true,
+        };
+        for observer in &self.inner.observers {
+            observer.on_request_end(&outcome);
+        }
     }
 
-    #[allow(clippy::too_many_arguments)]
     fn post_request_update_metrics(
         &self,
-        http_version: Version,
-        mixed_pattern: &str,
-        fallback_pattern: &str,
-        method: &Method,
-        status: StatusCode,
-        scheme: &str,
-        clock: Instant,
-        was_path_matched: bool,
-        request_size: usize,
+        record: &RequestRecord,
         response_size: usize,
+        completed: bool,
     ) {
         let this = &*self.inner;
+        let RequestRecord {
+            excluded,
+            ref label,
+            request_size,
+            clock,
+            handler_duration,
+            status,
+            ref scheme,
+            ref method,
+            version: http_version,
+            matched,
+            bounded,
+            ref span,
+            ref active_requests,
+            ..
+        } = *record;
 
         // NOTE: active_requests cannot be skips as we need to decrement the increment we did that
-        // the beginning of the request.
-        {
-            let mut active_request_labels = Vec::with_capacity(2 + this.names.const_labels.len());
-            active_request_labels
-                .push((this.names.http_request_method, method.as_str().to_string()));
-            active_request_labels.push((this.names.url_scheme, scheme.to_string()));
-            for (k, v) in &this.names.const_labels {
-                active_request_labels.push((k, v.clone()));
-            }
-            gauge!(
-                this.names.http_server_active_requests,
-                &active_request_labels
-            )
-            .decrement(1);
-        }
+        // the beginning of the request, with the same handle in case the recorder changed since.
+        active_requests.decrement(1);
+
+        let scope = label.scope();
+        let final_pattern = label.route();
+        let outcome = |duration| RequestOutcome {
+            route: final_pattern,
+            scope,
+            method,
+            status,
+            scheme,
+            version: http_version,
+            duration,
+            handler_duration,
+            request_size,
+            response_size,
+            matched,
+            completed,
+            excluded,
+            failed: false,
+        };
 
-        if this.exclude.contains(mixed_pattern)
-            || this.exclude_regex.is_match(mixed_pattern)
-            || this.exclude_status.contains(&status)
-        {
+        if excluded {
+            if !this.observers.is_empty() {
+                let outcome = outcome(clock.elapsed());
+                for observer in &this.observers {
+                    observer.on_request_end(&outcome);
+                }
+            }
             return;
         }
 
-        // do not record mixed patterns that were considered invalid by the server
-        let final_pattern = if fallback_pattern != mixed_pattern && (status == 404 || status == 405)
-        {
-            fallback_pattern
-        } else {
-            mixed_pattern
-        };
-
-        let final_pattern = if was_path_matched {
-            final_pattern
-        } else if let Some(mask) = &this.unmatched_patterns_mask {
-            mask
-        } else {
-            final_pattern
-        };
+        let handles = this.handles.route(
+            &this.names,
+            bounded,
+            scope,
+            final_pattern,
+            method,
+            status,
+            http_version,
+        );
 
-        let mut labels = Vec::with_capacity(5 + this.names.const_labels.len());
-        labels.push((this.names.http_route, final_pattern.to_string()));
-        labels.push((this.names.http_request_method, method.as_str().to_string()));
-        labels.push((
-            this.names.http_response_status_code,
-            status.as_str().to_string(),
-        ));
-        labels.push((this.names.network_protocol_name, "http".to_string()));
-
-        if let Some(http_version) = Self::http_version_label(http_version) {
-            labels.push((
-                this.names.network_protocol_version,
-                http_version.to_string(),
-            ));
+        let elapsed = clock.elapsed();
+        span.record(&RequestFields {
+            scope,
+            route: final_pattern,
+            method: method.as_str(),
+            status: status.as_u16(),
+            request_size,
+            response_size,
+            duration: elapsed.as_secs_f64(),
+        });
+        if let Some(access_log) = &this.access_log {
+            access_log.write(&AccessLogEntry {
+                method: method.as_str(),
+                scope,
+                route: final_pattern,
+                status: status.as_u16(),
+                outcome: Outcome::new(completed),
+                duration: elapsed,
+                handler_duration,
+                request_size,
+                response_size,
+            });
         }
-
-        for (k, v) in &this.names.const_labels {
-            labels.push((k, v.clone()));
+        if !this.observers.is_empty() {
+            let outcome = outcome(elapsed);
+            for observer in &this.observers {
+                observer.on_request_end(&outcome);
+            }
         }
 
-        let elapsed = clock.elapsed();
-        let duration =
-            (elapsed.as_secs() as f64) + f64::from(elapsed.subsec_nanos()) / 1_000_000_000_f64;
-        histogram!(this.names.http_server_request_duration, &labels).record(duration);
-        histogram!(this.names.http_server_request_body_size, &labels).record(request_size as f64);
-        histogram!(this.names.http_server_response_body_size, &labels).record(response_size as f64);
-    }
-
-    fn http_version_label(version: Version) -> Option<&'static str> {
-        let v = match version {
-            v if v == Version::HTTP_09 => "0.9",
-            v if v == Version::HTTP_10 => "1.0",
-            v if v == Version::HTTP_11 => "1.1",
-            v if v == Version::HTTP_2 => "2",
-            v if v == Version::HTTP_3 => "3",
-            _ => return None,
-        };
-
-        Some(v)
+        let sampled = this
+            .sampler
+            .as_ref()
+            .is_none_or(|sampler| sampler.sample(final_pattern));
+        let sample = sampled.then(|| RequestSample {
+            duration: (elapsed.as_secs() as f64)
+                + f64::from(elapsed.subsec_nanos()) / 1_000_000_000_f64,
+            request_size: request_size as f64,
+            response_size: response_size as f64,
+        });
+        match &this.local_aggregation {
+            Some(config) => aggregation::record_request(this.id, config, handles, sample),
+            None => {
+                if let Some(requests) = &handles.requests {
+                    requests.increment(1);
+                }
+                if let Some(sample) = sample {
+                    handles.duration.record(sample.duration);
+                    handles.request_body_size.record(sample.request_size);
+                    handles.response_body_size.record(sample.response_size);
+                }
+            }
+        }
     }
 }
 
 impl<S, B> Transform<S, ServiceRequest> for ActixWebMetrics
 where
     S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    B: MessageBody,
 {
     type Response = ServiceResponse<StreamLog<B>>;
     type Error = Error;
@@ -731,9 +1939,20 @@ where
     type Future = Ready<Result<Self::Transform, Self::InitError>>;
 
     fn new_transform(&self, service: S) -> Self::Future {
+        if let Some(tracker) = &self.inner.top_unmatched_paths {
+            tracker.spawn_periodic_log();
+        }
+
+        let flush_stopped = self.inner.local_aggregation.as_ref().map(|config| {
+            let stopped = Arc::new(AtomicBool::new(false));
+            aggregation::spawn_interval_flush(self.inner.id, config.interval(), stopped.clone());
+            stopped
+        });
+
         ready(Ok(MetricsMiddleware {
             service,
             inner: self.clone(),
+            flush_stopped,
         }))
     }
 }
@@ -745,9 +1964,15 @@ pin_project! {
         S: Service<ServiceRequest>,
     {
         #[pin]
-        fut: S::Future,
+        fut: TaskLocalFuture<RequestContext, S::Future>,
         time: Instant,
         inner: ActixWebMetrics,
+        // `None` if the request is excluded
+        rules: Option<(Arc<RequestRules>, Arc<Gauge>)>,
+        // `Some` if the request is observed or logged, to end it if the service returns an error
+        head: Option<ObservedHead>,
+        mounted_depth: usize,
+        span: RequestSpan,
         _t: PhantomData<()>,
     }
 }
@@ -755,56 +1980,69 @@ pin_project! {
 impl<S, B> Future for LoggerResponse<S>
 where
     S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    B: MessageBody,
 {
     type Output = Result<ServiceResponse<StreamLog<B>>, Error>;
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         let this = self.project();
 
-        let res = match ready!(this.fut.poll(cx)) {
+        let mut res = match ready!(this.fut.poll(cx)) {
             Ok(res) => res,
-            Err(e) => return Poll::Ready(Err(e)),
+            Err(err) => {
+                if let Some((_, active_requests)) = this.rules.take() {
+                    this.inner
+                        .request_failed(&active_requests, this.head.take(), &err, this.time);
+                }
+                return Poll::Ready(Err(err));
+            }
+        };
+
+        let Some((rules, active_requests)) = this.rules.take() else {
+            return Poll::Ready(Ok(res.map_body(|_, body| StreamLog {
+                body,
+                response_size: 0,
+                completed: false,
+                record: None,
+            })));
         };
+        let response_excluded = this.inner.is_response_excluded(&res);
+        #[cfg(feature = "prometheus")]
+        let response_excluded = response_excluded
+            || res
+                .response()
+                .extensions()
+                .contains::<prometheus::ExcludedResponse>();
 
         let time = *this.time;
+        let handler_duration = time.elapsed();
+        let status = res.status();
         let req = res.request();
         let method = req.method().clone();
         let version = req.version();
-        let was_path_matched = req.match_pattern().is_some();
-
-        // get metrics config for this specific route
-        // piece of code to allow for more cardinality
-        let params_keep_path_cardinality =
-            match req.extensions_mut().get::<ActixWebMetricsExtension>() {
-                Some(config) => config.cardinality_keep_params.clone(),
-                None => vec![],
-            };
 
         let full_pattern = req.match_pattern();
-        let path = req.path().to_string();
-        let fallback_pattern = full_pattern.clone().unwrap_or(path.clone());
-
-        // mixed_pattern is the final path used as label value in metrics
-        let mixed_pattern = match full_pattern {
-            None => path.clone(),
-            Some(full_pattern) => {
-                let mut params: HashMap<String, String> = HashMap::new();
-
-                for (key, val) in req.match_info().iter() {
-                    if params_keep_path_cardinality.contains(&key.to_string()) {
-                        params.insert(key.to_string(), val.to_string());
-                        continue;
-                    }
-                    params.insert(key.to_string(), format!("{{{key}}}"));
-                }
-
-                if let Ok(mixed_cardinality_pattern) = strfmt(&full_pattern, &params) {
-                    mixed_cardinality_pattern
-                } else {
-                    warn!("Cannot build mixed cardinality pattern {full_pattern}, with params {params:?}");
-                    full_pattern
-                }
+        let scope = this
+            .inner
+            .scope(full_pattern.as_deref(), *this.mounted_depth);
+        let patterns = RequestPatterns::new(this.inner, req, full_pattern);
+        let matched = patterns.matched;
+
+        // tracked even if excluded, so excluded 404s are still seen
+        if !matched {
+            if let Some(tracker) = &this.inner.inner.top_unmatched_paths {
+                tracker.record(&patterns.mixed);
             }
+        }
+        let excluded = response_excluded || rules.is_excluded(&patterns.mixed, status);
+        // unmatched paths are only bounded once masked or normalized
+        let bounded = matched
+            || rules.unmatched_patterns_mask.is_some()
+            || this.inner.inner.unmatched_paths.is_some();
+        let label = if excluded {
+            patterns.excluded_label(scope)
+        } else {
+            patterns.label(this.inner, &rules, status, scope)
         };
 
         // Get request size from Content-Length header
@@ -815,21 +2053,33 @@ where
             .and_then(|v| v.parse::<usize>().ok())
             .unwrap_or(0);
 
-        let scheme = url_scheme(&req.uri()).to_string();
+        let scheme = owned_scheme(req.uri());
+        if !excluded && this.inner.inner.route_label_extension {
+            res.response_mut().extensions_mut().insert(label.clone());
+        }
+
         let inner = this.inner.clone();
+        let span = this.span.clone();
         Poll::Ready(Ok(res.map_body(move |head, body| StreamLog {
+            completed: body.size().is_eof(),
             body,
             response_size: 0,
-            request_size,
-            clock: time,
-            inner,
-            status: head.status,
-            scheme,
-            mixed_pattern,
-            fallback_pattern,
-            method,
-            version,
-            was_path_matched,
+            record: Some(RequestRecord {
+                inner,
+                excluded,
+                label,
+                request_size,
+                clock: time,
+                handler_duration,
+                status: head.status,
+                scheme,
+                method,
+                version,
+                matched,
+                bounded,
+                span,
+                active_requests,
+            }),
         })))
     }
 }
@@ -839,11 +2089,23 @@ where
 pub struct MetricsMiddleware<S> {
     service: S,
     inner: ActixWebMetrics,
+    flush_stopped: Option<Arc<AtomicBool>>,
+}
+
+impl<S> Drop for MetricsMiddleware<S> {
+    fn drop(&mut self) {
+        // the service is dropped on its worker thread when the worker shuts down
+        if let Some(stopped) = &self.flush_stopped {
+            stopped.store(true, Ordering::Relaxed);
+            self.inner.flush_local_aggregate();
+        }
+    }
 }
 
 impl<S, B> Service<ServiceRequest> for MetricsMiddleware<S>
 where
     S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    B: MessageBody,
 {
     type Response = ServiceResponse<StreamLog<B>>;
     type Error = S::Error;
@@ -852,42 +2114,103 @@ where
     dev::forward_ready!(service);
 
     fn call(&self, req: ServiceRequest) -> Self::Future {
-        self.inner.pre_request_update_metrics(&req);
+        let mut head = None;
+        let rules = if self.inner.is_request_excluded(&req) {
+            None
+        } else {
+            let active_requests = self.inner.pre_request_update_metrics(&req);
+            for observer in &self.inner.inner.observers {
+                observer.on_request_start(&req);
+            }
+            if !self.inner.inner.observers.is_empty() || self.inner.inner.access_log.is_some() {
+                head = Some(ObservedHead {
+                    method: req.method().clone(),
+                    scheme: owned_scheme(req.uri()),
+                    version: req.version(),
+                });
+            }
+            // taken once so the request is recorded consistently if the rules change meanwhile
+            Some((self.inner.inner.rules.load(), active_requests))
+        };
+        // routing of the request is only known once it is handled, but the scopes the middleware
+        // is mounted in are already matched
+        let mounted_depth = route::mounted_depth(req.match_info());
+        let context = if !self.inner.inner.request_context {
+            RequestContext::current()
+        } else if let Some((rules, _)) = &rules {
+            RequestContext::for_request(&self.inner, &req, rules, mounted_depth)
+        } else {
+            let rules = self.inner.inner.rules.load();
+            RequestContext::for_request(&self.inner, &req, &rules, mounted_depth)
+        };
+
+        if self.inner.inner.request_metrics {
+            req.extensions_mut().insert(InstalledMiddleware {
+                metrics: self.inner.clone(),
+                mounted_depth,
+            });
+        }
 
         LoggerResponse {
-            fut: self.service.call(req),
+            fut: context.scope_future(self.service.call(req)),
             time: Instant::now(),
             inner: self.inner.clone(),
+            rules,
+            head,
+            mounted_depth,
+            span: RequestSpan::current(),
             _t: PhantomData,
         }
     }
 }
 
+/// Request line of an observed or logged request, kept to end it if the service returns an error.
+struct ObservedHead {
+    method: Method,
+    scheme: Cow<'static, str>,
+    version: Version,
+}
+
+/// What is known about a request once the handler returned, recorded when the body is dropped.
+struct RequestRecord {
+    inner: ActixWebMetrics,
+    excluded: bool,
+    label: RouteLabel,
+    request_size: usize,
+    clock: Instant,
+    handler_duration: Duration,
+    status: StatusCode,
+    scheme: Cow<'static, str>,
+    method: Method,
+    version: Version,
+    matched: bool,
+    // whether the label is one of a bounded set, so its handles can be cached
+    bounded: bool,
+    span: RequestSpan,
+    active_requests: Arc<Gauge>,
+}
+
 pin_project! {
     #[doc(hidden)]
     pub struct StreamLog<B> {
         #[pin]
         body: B,
         response_size: usize,
-        request_size: usize,
-        clock: Instant,
-        inner: ActixWebMetrics,
-        status: StatusCode,
-        scheme: String,
-        // a route pattern with some params not-filled and some params filled in by user-defined
-        mixed_pattern: String,
-        fallback_pattern: String,
-        method: Method,
-        version: Version,
-        was_path_matched: bool
+        // whether the body was polled to its end, empty bodies are not polled
+        completed: bool,
+        // `None` if the request is excluded
+        record: Option<RequestRecord>,
     }
 
 
     impl<B> PinnedDrop for StreamLog<B> {
         fn drop(this: Pin<&mut Self>) {
             // update the metrics for this request at the very end of responding
-            this.inner
-                .post_request_update_metrics(this.version, &this.mixed_pattern, &this.fallback_pattern, &this.method, this.status, &this.scheme, this.clock, this.was_path_matched, this.request_size, this.response_size);
+            if let Some(record) = &this.record {
+                record
+                    .inner
+                    .post_request_update_metrics(record, this.response_size, this.completed);
+            }
         }
     }
 }
@@ -910,11 +2233,26 @@ impl<B: MessageBody> MessageBody for StreamLog<B> {
                 Poll::Ready(Some(Ok(chunk)))
             }
             Some(Err(err)) => Poll::Ready(Some(Err(err))),
-            None => Poll::Ready(None),
+            None => {
+                *this.completed = true;
+                Poll::Ready(None)
+            }
         }
     }
 }
 
+fn shared_string(value: String) -> SharedString {
+    SharedString::from_shared(Arc::from(value))
+}
+
 fn url_scheme(uri: &Uri) -> &str {
     uri.scheme().map(|s| s.as_str()).unwrap_or("http")
 }
+
+fn owned_scheme(uri: &Uri) -> Cow<'static, str> {
+    match url_scheme(uri) {
+        "http" => Cow::Borrowed("http"),
+        "https" => Cow::Borrowed("https"),
+        scheme => Cow::Owned(scheme.to_string()),
+    }
+}
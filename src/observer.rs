@@ -0,0 +1,85 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::dev::ServiceRequest;
+use actix_web::http::{Method, StatusCode, Version};
+
+/// Callbacks on the lifecycle of the requests handled by the middleware.
+///
+/// Requests excluded by [`exclude_request_if`](crate::ActixWebMetricsBuilder::exclude_request_if)
+/// are not observed, every other request is observed once started and once ended.
+///
+/// ```rust
+/// use std::sync::atomic::{AtomicU64, Ordering};
+///
+/// use actix_web_metrics::{ActixWebMetricsBuilder, MetricsObserver, RequestOutcome};
+///
+/// #[derive(Default)]
+/// struct SlowRequests(AtomicU64);
+///
+/// impl MetricsObserver for SlowRequests {
+///     fn on_request_end(&self, outcome: &RequestOutcome<'_>) {
+///         if outcome.duration.as_secs() >= 1 {
+///             self.0.fetch_add(1, Ordering::Relaxed);
+///         }
+///     }
+/// }
+///
+/// let metrics = ActixWebMetricsBuilder::new()
+///     .observer(SlowRequests::default())
+///     .build();
+/// ```
+pub trait MetricsObserver: Send + Sync + 'static {
+    /// Called when the middleware is called, before the request is handled
+    fn on_request_start(&self, _req: &ServiceRequest) {}
+
+    /// Called once the response body is sent or dropped, or once the service returned an error
+    fn on_request_end(&self, _outcome: &RequestOutcome<'_>) {}
+}
+
+impl<O: MetricsObserver + ?Sized> MetricsObserver for Arc<O> {
+    fn on_request_start(&self, req: &ServiceRequest) {
+        (**self).on_request_start(req)
+    }
+
+    fn on_request_end(&self, outcome: &RequestOutcome<'_>) {
+        (**self).on_request_end(outcome)
+    }
+}
+
+/// What the middleware computed about an ended request.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct RequestOutcome<'a> {
+    /// Route of the `http.route` label, or the matched pattern if the request is excluded, empty
+    /// if the request failed
+    pub route: &'a str,
+    /// Scope of the `http.scope` label, if enabled
+    pub scope: Option<&'a str>,
+    /// Request method
+    pub method: &'a Method,
+    /// Response status, or the status of the error response if the request failed
+    pub status: StatusCode,
+    /// URL scheme of the request
+    pub scheme: &'a str,
+    /// HTTP version of the request
+    pub version: Version,
+    /// Time until the response body was sent or dropped
+    pub duration: Duration,
+    /// Time until the handler returned the response
+    pub handler_duration: Duration,
+    /// Request body size in bytes, from the `Content-Length` header
+    pub request_size: usize,
+    /// Number of response body bytes sent
+    pub response_size: usize,
+    /// Whether the request was matched to a handler
+    pub matched: bool,
+    /// Whether the response body was sent completely, `false` if it was dropped before, e.g. as
+    /// the client disconnected
+    pub completed: bool,
+    /// Whether the request is excluded from the metrics
+    pub excluded: bool,
+    /// Whether the service returned an error instead of a response, such requests are not recorded
+    /// in the metrics
+    pub failed: bool,
+}
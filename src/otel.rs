@@ -0,0 +1,299 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+
+use metrics::{
+    Counter, CounterFn, Gauge, GaugeFn, Histogram, HistogramFn, Key, KeyName, Metadata, Recorder,
+    SharedString, Unit,
+};
+use opentelemetry::metrics::Meter;
+use opentelemetry::KeyValue;
+
+use crate::MetricsMetadata;
+
+/// Boundaries recommended by the OpenTelemetry semantic conventions for request durations.
+const DURATION_BOUNDARIES: [f64; 14] = [
+    0.005, 0.01, 0.025, 0.05, 0.075, 0.1, 0.25, 0.5, 0.75, 1.0, 2.5, 5.0, 7.5, 10.0,
+];
+
+/// Records the metrics of the middleware into an OpenTelemetry [`Meter`].
+///
+/// Counters become `Counter<u64>`, gauges `UpDownCounter<i64>` and histograms `Histogram<f64>`.
+/// The metrics of the middleware itself, known by name, get the units of the semantic
+/// conventions, with the recommended boundaries for durations and `Histogram<u64>` for body
+/// sizes. Other metrics, e.g. those recorded through `RequestMetrics`, get no unit and the default
+/// boundaries. Metrics are described right before they are registered, so instruments are created
+/// with their description.
+pub(crate) struct OpenTelemetryRecorder {
+    meter: Meter,
+    own_metrics: HashMap<String, OwnMetric>,
+    descriptions: Mutex<HashMap<String, SharedString>>,
+    instruments: Mutex<HashMap<String, Instrument>>,
+    // values of the counters and gauges, shared by all the handles of a key as the instruments
+    // only take increments
+    counter_values: Mutex<HashMap<Key, Arc<AtomicU64>>>,
+    gauge_values: Mutex<HashMap<Key, Arc<AtomicI64>>>,
+}
+
+/// Kind of a metric of the middleware.
+#[derive(Clone, Copy)]
+enum OwnMetric {
+    Requests,
+    Duration,
+    BodySize,
+}
+
+#[derive(Clone)]
+enum Instrument {
+    Counter(opentelemetry::metrics::Counter<u64>),
+    UpDownCounter(opentelemetry::metrics::UpDownCounter<i64>),
+    Histogram(opentelemetry::metrics::Histogram<f64>),
+    Size(opentelemetry::metrics::Histogram<u64>),
+}
+
+impl OpenTelemetryRecorder {
+    pub(crate) fn new(meter: Meter, names: &MetricsMetadata) -> Self {
+        let mut own_metrics = HashMap::new();
+        let mut insert = |name: &SharedString, kind| {
+            own_metrics.insert(name.to_string(), kind);
+        };
+        insert(&names.http_server_active_requests, OwnMetric::Requests);
+        if let Some(name) = &names.http_server_requests {
+            insert(name, OwnMetric::Requests);
+        }
+        insert(&names.http_server_request_duration, OwnMetric::Duration);
+        insert(&names.http_server_request_body_size, OwnMetric::BodySize);
+        insert(&names.http_server_response_body_size, OwnMetric::BodySize);
+        for route in names.route_histograms.values() {
+            insert(&route.duration, OwnMetric::Duration);
+            insert(&route.request_body_size, OwnMetric::BodySize);
+            insert(&route.response_body_size, OwnMetric::BodySize);
+        }
+
+        Self {
+            meter,
+            own_metrics,
+            descriptions: Mutex::default(),
+            instruments: Mutex::default(),
+            counter_values: Mutex::default(),
+            gauge_values: Mutex::default(),
+        }
+    }
+
+    fn describe(&self, key: KeyName, description: SharedString) {
+        self.descriptions
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(key.as_str().to_string(), description);
+    }
+
+    /// Returns the instrument named after `key`, created with `create` on first use along with
+    /// the kind of the metric if it is one of the middleware.
+    fn instrument(
+        &self,
+        key: &Key,
+        create: impl FnOnce(
+            &Meter,
+            Cow<'static, str>,
+            Option<OwnMetric>,
+            Cow<'static, str>,
+        ) -> Instrument,
+    ) -> (Instrument, Arc<[KeyValue]>) {
+        let attributes = key
+            .labels()
+            .map(|label| KeyValue::new(label.key().to_string(), label.value().to_string()))
+            .collect();
+
+        let mut instruments = self
+            .instruments
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Some(instrument) = instruments.get(key.name()) {
+            return (instrument.clone(), attributes);
+        }
+        let description = self
+            .descriptions
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(key.name())
+            .cloned()
+            .unwrap_or_default();
+        let instrument = create(
+            &self.meter,
+            Cow::Owned(key.name().to_string()),
+            self.own_metrics.get(key.name()).copied(),
+            Cow::Owned(description.into_owned()),
+        );
+        instruments.insert(key.name().to_string(), instrument.clone());
+        (instrument, attributes)
+    }
+}
+
+/// Returns the value tracked for `key`, starting at zero.
+fn value<T: Default>(values: &Mutex<HashMap<Key, Arc<T>>>, key: &Key) -> Arc<T> {
+    values
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .entry(key.clone())
+        .or_default()
+        .clone()
+}
+
+impl Recorder for OpenTelemetryRecorder {
+    fn describe_counter(&self, key: KeyName, _unit: Option<Unit>, description: SharedString) {
+        self.describe(key, description);
+    }
+
+    fn describe_gauge(&self, key: KeyName, _unit: Option<Unit>, description: SharedString) {
+        self.describe(key, description);
+    }
+
+    fn describe_histogram(&self, key: KeyName, _unit: Option<Unit>, description: SharedString) {
+        self.describe(key, description);
+    }
+
+    fn register_counter(&self, key: &Key, _metadata: &Metadata<'_>) -> Counter {
+        let (instrument, attributes) = self.instrument(key, |meter, name, own, description| {
+            let counter = meter.u64_counter(name).with_description(description);
+            Instrument::Counter(match own {
+                Some(OwnMetric::Requests) => counter.with_unit("{request}").build(),
+                _ => counter.build(),
+            })
+        });
+        match instrument {
+            Instrument::Counter(counter) => Counter::from_arc(Arc::new(OtelCounter {
+                counter,
+                attributes,
+                value: value(&self.counter_values, key),
+            })),
+            _ => Counter::noop(),
+        }
+    }
+
+    fn register_gauge(&self, key: &Key, _metadata: &Metadata<'_>) -> Gauge {
+        let (instrument, attributes) = self.instrument(key, |meter, name, own, description| {
+            let counter = meter
+                .i64_up_down_counter(name)
+                .with_description(description);
+            Instrument::UpDownCounter(match own {
+                Some(OwnMetric::Requests) => counter.with_unit("{request}").build(),
+                _ => counter.build(),
+            })
+        });
+        match instrument {
+            Instrument::UpDownCounter(counter) => Gauge::from_arc(Arc::new(OtelGauge {
+                counter,
+                attributes,
+                value: value(&self.gauge_values, key),
+            })),
+            _ => Gauge::noop(),
+        }
+    }
+
+    fn register_histogram(&self, key: &Key, _metadata: &Metadata<'_>) -> Histogram {
+        let (instrument, attributes) =
+            self.instrument(key, |meter, name, own, description| match own {
+                Some(OwnMetric::BodySize) => Instrument::Size(
+                    meter
+                        .u64_histogram(name)
+                        .with_unit("By")
+                        .with_description(description)
+                        .build(),
+                ),
+                Some(OwnMetric::Duration) => Instrument::Histogram(
+                    meter
+                        .f64_histogram(name)
+                        .with_unit("s")
+                        .with_description(description)
+                        .with_boundaries(DURATION_BOUNDARIES.to_vec())
+                        .build(),
+                ),
+                _ => Instrument::Histogram(
+                    meter
+                        .f64_histogram(name)
+                        .with_description(description)
+                        .build(),
+                ),
+            });
+        match instrument {
+            Instrument::Histogram(histogram) => Histogram::from_arc(Arc::new(OtelHistogram {
+                histogram,
+                attributes,
+            })),
+            Instrument::Size(histogram) => Histogram::from_arc(Arc::new(OtelSize {
+                histogram,
+                attributes,
+            })),
+            _ => Histogram::noop(),
+        }
+    }
+}
+
+struct OtelCounter {
+    counter: opentelemetry::metrics::Counter<u64>,
+    attributes: Arc<[KeyValue]>,
+    // tracked so `absolute` can be recorded as an increment
+    value: Arc<AtomicU64>,
+}
+
+impl CounterFn for OtelCounter {
+    fn increment(&self, value: u64) {
+        self.value.fetch_add(value, Ordering::Relaxed);
+        self.counter.add(value, &self.attributes);
+    }
+
+    // the counter is monotonic, so values below the current one are ignored
+    fn absolute(&self, value: u64) {
+        let previous = self.value.fetch_max(value, Ordering::Relaxed);
+        if value > previous {
+            self.counter.add(value - previous, &self.attributes);
+        }
+    }
+}
+
+struct OtelGauge {
+    counter: opentelemetry::metrics::UpDownCounter<i64>,
+    attributes: Arc<[KeyValue]>,
+    // tracked so `set` can be recorded as a delta
+    value: Arc<AtomicI64>,
+}
+
+impl GaugeFn for OtelGauge {
+    fn increment(&self, value: f64) {
+        self.value.fetch_add(value as i64, Ordering::Relaxed);
+        self.counter.add(value as i64, &self.attributes);
+    }
+
+    fn decrement(&self, value: f64) {
+        self.value.fetch_sub(value as i64, Ordering::Relaxed);
+        self.counter.add(-(value as i64), &self.attributes);
+    }
+
+    fn set(&self, value: f64) {
+        let previous = self.value.swap(value as i64, Ordering::Relaxed);
+        self.counter.add(value as i64 - previous, &self.attributes);
+    }
+}
+
+struct OtelHistogram {
+    histogram: opentelemetry::metrics::Histogram<f64>,
+    attributes: Arc<[KeyValue]>,
+}
+
+impl HistogramFn for OtelHistogram {
+    fn record(&self, value: f64) {
+        self.histogram.record(value, &self.attributes);
+    }
+}
+
+struct OtelSize {
+    histogram: opentelemetry::metrics::Histogram<u64>,
+    attributes: Arc<[KeyValue]>,
+}
+
+impl HistogramFn for OtelSize {
+    fn record(&self, value: f64) {
+        self.histogram.record(value as u64, &self.attributes);
+    }
+}
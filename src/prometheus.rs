@@ -0,0 +1,464 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use actix_web::dev::{AppService, HttpServiceFactory};
+use actix_web::http::header::{self, Accept, Header};
+use actix_web::{web, HttpRequest, HttpResponse};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use log::warn;
+use metrics::SharedString;
+use metrics_exporter_prometheus::{BuildError, Matcher, PrometheusBuilder, PrometheusHandle};
+
+use crate::ActixWebMetrics;
+
+const TEXT_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+const OPENMETRICS_CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Marks the responses of the endpoint so the middleware doesn't record them.
+pub(crate) struct ExcludedResponse;
+
+/// Service exposing the metrics of a [`PrometheusHandle`] for Prometheus to scrape.
+///
+/// The responses of the endpoint are never recorded by the middleware. Its requests are only
+/// left out of `http.server.active_requests` and the observers once the middleware is built with
+/// [`exclude_endpoint`](crate::ActixWebMetricsBuilder::exclude_endpoint), as the route of a
+/// request isn't known before it is handled. The exposition is rendered in the OpenMetrics format
+/// when the `Accept` header prefers `application/openmetrics-text`, in the Prometheus text format
+/// otherwise.
+///
+/// The upkeep of the handle is run on every scrape, so there is no need to run it periodically.
+///
+/// ```rust
+/// use actix_web::App;
+/// use actix_web_metrics::{ActixWebMetricsBuilder, PrometheusEndpoint};
+/// use metrics_exporter_prometheus::PrometheusBuilder;
+///
+/// let prometheus = PrometheusBuilder::new().install_recorder().unwrap();
+/// let endpoint = PrometheusEndpoint::new(prometheus)
+///     .basic_auth("prometheus", "secret")
+///     .allow_network("10.0.0.0".parse().unwrap(), 8);
+/// let metrics = ActixWebMetricsBuilder::new().exclude_endpoint(&endpoint).build();
+///
+/// let app = App::new().wrap(metrics).service(endpoint);
+/// ```
+#[derive(Clone)]
+pub struct PrometheusEndpoint {
+    path: String,
+    handle: PrometheusHandle,
+    credentials: Option<(String, String)>,
+    allowed_networks: Vec<(IpAddr, u8)>,
+}
+
+impl fmt::Debug for PrometheusEndpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PrometheusEndpoint")
+            .field("path", &self.path)
+            .field(
+                "credentials",
+                &self
+                    .credentials
+                    .as_ref()
+                    .map(|(username, _)| (username, "***")),
+            )
+            .field("allowed_networks", &self.allowed_networks)
+            .finish_non_exhaustive()
+    }
+}
+
+impl PrometheusEndpoint {
+    /// Create an endpoint serving the metrics of `handle` at `/metrics`
+    pub fn new(handle: PrometheusHandle) -> Self {
+        Self {
+            path: "/metrics".to_string(),
+            handle,
+            credentials: None,
+            allowed_networks: Vec::new(),
+        }
+    }
+
+    /// Set the path of the endpoint
+    ///
+    /// Defaults to `/metrics`
+    pub fn path<T: Into<String>>(mut self, path: T) -> Self {
+        self.path = path.into();
+        self
+    }
+
+    /// Require HTTP basic authentication with the given credentials
+    ///
+    /// Unauthenticated requests are answered with `401 Unauthorized`.
+    pub fn basic_auth<U: Into<String>, P: Into<String>>(
+        mut self,
+        username: U,
+        password: P,
+    ) -> Self {
+        self.credentials = Some((username.into(), password.into()));
+        self
+    }
+
+    /// Allow requests from the given address
+    ///
+    /// Once an address or network is allowed, requests from other peers are answered with
+    /// `403 Forbidden`.
+    pub fn allow_ip(self, ip: IpAddr) -> Self {
+        let prefix_len = if ip.is_ipv4() { 32 } else { 128 };
+        self.allow_network(ip, prefix_len)
+    }
+
+    /// Allow requests from the network `ip/prefix_len`, e.g. `10.0.0.0/8`
+    ///
+    /// NOTE: The address of the peer is used, behind a reverse proxy it is the proxy address.
+    pub fn allow_network(mut self, ip: IpAddr, prefix_len: u8) -> Self {
+        self.allowed_networks.push((ip, prefix_len));
+        self
+    }
+
+    pub(crate) fn endpoint_path(&self) -> &str {
+        &self.path
+    }
+
+    fn is_allowed(&self, req: &HttpRequest) -> bool {
+        if self.allowed_networks.is_empty() {
+            return true;
+        }
+        let Some(peer) = req.peer_addr() else {
+            return false;
+        };
+        let peer = peer.ip().to_canonical();
+        self.allowed_networks
+            .iter()
+            .any(|&(network, prefix_len)| contains(network, prefix_len, peer))
+    }
+
+    fn is_authenticated(&self, req: &HttpRequest) -> bool {
+        let Some((username, password)) = &self.credentials else {
+            return true;
+        };
+        let Some(credentials) = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split_once(' '))
+            .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("basic"))
+            .and_then(|(_, credentials)| STANDARD.decode(credentials.trim()).ok())
+        else {
+            return false;
+        };
+        constant_time_eq(&credentials, format!("{username}:{password}").as_bytes())
+    }
+
+    fn render(&self, req: &HttpRequest) -> HttpResponse {
+        let mut res = if !self.is_allowed(req) {
+            HttpResponse::Forbidden().finish()
+        } else if !self.is_authenticated(req) {
+            HttpResponse::Unauthorized()
+                .insert_header((header::WWW_AUTHENTICATE, r#"Basic realm="metrics""#))
+                .finish()
+        } else {
+            // drains the histogram samples recorded since, which is up to the caller of
+            // `install_recorder` or `build_recorder`
+            self.handle.run_upkeep();
+            let text = self.handle.render();
+            if prefers_openmetrics(req) {
+                HttpResponse::Ok()
+                    .content_type(OPENMETRICS_CONTENT_TYPE)
+                    .body(to_openmetrics(&text))
+            } else {
+                HttpResponse::Ok()
+                    .content_type(TEXT_CONTENT_TYPE)
+                    .body(text)
+            }
+        };
+        res.extensions_mut().insert(ExcludedResponse);
+        res
+    }
+}
+
+impl HttpServiceFactory for PrometheusEndpoint {
+    fn register(self, config: &mut AppService) {
+        let endpoint = Arc::new(self);
+        web::resource(endpoint.path.clone())
+            .route(web::get().to(move |req: HttpRequest| {
+                let endpoint = Arc::clone(&endpoint);
+                async move { endpoint.render(&req) }
+            }))
+            .register(config);
+    }
+}
+
+/// Histogram buckets of the request metrics, see [`PrometheusBuilderExt`].
+///
+/// Durations default to the buckets recommended by the OpenTelemetry semantic conventions, from
+/// 5ms to 10s. Body sizes default to powers of 4, from 64B to 64MiB.
+#[derive(Debug, Clone)]
+pub struct HttpServerBuckets {
+    duration: Vec<f64>,
+    request_body_size: Vec<f64>,
+    response_body_size: Vec<f64>,
+    routes: Vec<(String, HttpServerBuckets)>,
+}
+
+impl Default for HttpServerBuckets {
+    fn default() -> Self {
+        let body_size: Vec<f64> = (3..=13).map(|i| 4_f64.powi(i)).collect();
+        Self {
+            duration: vec![
+                0.005, 0.01, 0.025, 0.05, 0.075, 0.1, 0.25, 0.5, 0.75, 1.0, 2.5, 5.0, 7.5, 10.0,
+            ],
+            request_body_size: body_size.clone(),
+            response_body_size: body_size,
+            routes: Vec::new(),
+        }
+    }
+}
+
+impl HttpServerBuckets {
+    /// Set the buckets of `http.server.request.duration`, in seconds
+    pub fn duration(mut self, buckets: &[f64]) -> Self {
+        self.duration = buckets.to_vec();
+        self
+    }
+
+    /// Set the buckets of `http.server.request.body.size`, in bytes
+    pub fn request_body_size(mut self, buckets: &[f64]) -> Self {
+        self.request_body_size = buckets.to_vec();
+        self
+    }
+
+    /// Set the buckets of `http.server.response.body.size`, in bytes
+    pub fn response_body_size(mut self, buckets: &[f64]) -> Self {
+        self.response_body_size = buckets.to_vec();
+        self
+    }
+
+    /// Set the buckets of the histograms of `route`, which the middleware must record apart with
+    /// [`route_histograms`](crate::ActixWebMetricsBuilder::route_histograms)
+    ///
+    /// The route overrides of `buckets` are ignored.
+    pub fn route<T: Into<String>>(mut self, route: T, buckets: HttpServerBuckets) -> Self {
+        self.routes.push((route.into(), buckets));
+        self
+    }
+}
+
+/// Configures a [`PrometheusBuilder`] to export the request metrics as histograms.
+///
+/// `metrics-exporter-prometheus` exports histograms as summaries unless buckets are set for their
+/// exact name, which depends on the namespace and the configured metric names. The buckets are set
+/// for the names resolved by the middleware:
+///
+/// ```rust
+/// use actix_web_metrics::{ActixWebMetricsBuilder, HttpServerBuckets, PrometheusBuilderExt};
+/// use metrics_exporter_prometheus::PrometheusBuilder;
+///
+/// let metrics = ActixWebMetricsBuilder::new().namespace("my_app").build();
+/// let prometheus = PrometheusBuilder::new()
+///     .http_server_buckets(&metrics, &HttpServerBuckets::default().duration(&[0.1, 0.5, 1.0]))
+///     .unwrap()
+///     .install_recorder()
+///     .unwrap();
+/// ```
+///
+/// The exporter selects buckets by metric name only, so the routes with buckets of their own must
+/// be recorded in metrics of their own by the middleware:
+///
+/// ```rust
+/// use actix_web_metrics::{ActixWebMetricsBuilder, HttpServerBuckets, PrometheusBuilderExt};
+/// use metrics_exporter_prometheus::PrometheusBuilder;
+///
+/// let metrics = ActixWebMetricsBuilder::new()
+///     .route_histograms("/upload", "upload")
+///     .build();
+/// let buckets = HttpServerBuckets::default().route(
+///     "/upload",
+///     HttpServerBuckets::default().duration(&[1.0, 10.0, 60.0, 300.0]),
+/// );
+/// let prometheus = PrometheusBuilder::new()
+///     .http_server_buckets(&metrics, &buckets)
+///     .unwrap()
+///     .build_recorder();
+/// ```
+///
+/// A warning is logged for the routes the middleware doesn't record apart, their buckets are
+/// ignored.
+pub trait PrometheusBuilderExt: Sized {
+    /// Set the buckets of the request histograms recorded by `metrics`
+    fn http_server_buckets(
+        self,
+        metrics: &ActixWebMetrics,
+        buckets: &HttpServerBuckets,
+    ) -> Result<Self, BuildError>;
+}
+
+impl PrometheusBuilderExt for PrometheusBuilder {
+    fn http_server_buckets(
+        self,
+        metrics: &ActixWebMetrics,
+        buckets: &HttpServerBuckets,
+    ) -> Result<Self, BuildError> {
+        let names = &metrics.inner.names;
+        let mut builder = set_buckets(
+            self,
+            [
+                &names.http_server_request_duration,
+                &names.http_server_request_body_size,
+                &names.http_server_response_body_size,
+            ],
+            buckets,
+        )?;
+        for (route, route_buckets) in &buckets.routes {
+            let Some(histograms) = names.route_histograms.get(route) else {
+                warn!("Buckets of route {route} ignored, its histograms are not recorded apart");
+                continue;
+            };
+            builder = set_buckets(
+                builder,
+                [
+                    &histograms.duration,
+                    &histograms.request_body_size,
+                    &histograms.response_body_size,
+                ],
+                route_buckets,
+            )?;
+        }
+        Ok(builder)
+    }
+}
+
+/// Sets the buckets of the duration, request and response body size histograms with the names.
+fn set_buckets(
+    builder: PrometheusBuilder,
+    [duration, request_body_size, response_body_size]: [&SharedString; 3],
+    buckets: &HttpServerBuckets,
+) -> Result<PrometheusBuilder, BuildError> {
+    builder
+        .set_buckets_for_metric(Matcher::Full(sanitize(duration)), &buckets.duration)?
+        .set_buckets_for_metric(
+            Matcher::Full(sanitize(request_body_size)),
+            &buckets.request_body_size,
+        )?
+        .set_buckets_for_metric(
+            Matcher::Full(sanitize(response_body_size)),
+            &buckets.response_body_size,
+        )
+}
+
+/// Exported name of a metric, the exporter replaces the characters Prometheus doesn't allow.
+fn sanitize(name: &str) -> String {
+    name.chars()
+        .enumerate()
+        .map(|(i, c)| match c {
+            'a'..='z' | 'A'..='Z' | '_' | ':' => c,
+            '0'..='9' if i > 0 => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+fn contains(network: IpAddr, prefix_len: u8, ip: IpAddr) -> bool {
+    let (network, ip, width) = match (network.to_canonical(), ip) {
+        (IpAddr::V4(network), IpAddr::V4(ip)) => {
+            (u32::from(network) as u128, u32::from(ip) as u128, 32)
+        }
+        (IpAddr::V6(network), IpAddr::V6(ip)) => (u128::from(network), u128::from(ip), 128),
+        _ => return false,
+    };
+    let prefix_len = u32::from(prefix_len).min(width);
+    prefix_len == 0 || (network ^ ip) >> (width - prefix_len) == 0
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+fn prefers_openmetrics(req: &HttpRequest) -> bool {
+    let Ok(accept) = Accept::parse(req) else {
+        return false;
+    };
+    accept
+        .ranked()
+        .iter()
+        .find(|mime| {
+            mime.subtype() == "openmetrics-text" || mime.type_() == "text" || mime.type_() == "*"
+        })
+        .is_some_and(|mime| mime.subtype() == "openmetrics-text")
+}
+
+/// Converts the Prometheus text format to OpenMetrics.
+///
+/// `metrics-exporter-prometheus` only renders the text format, its exposition is converted family
+/// by family: counter families are named without the `_total` suffix their samples get, `untyped`
+/// families become `unknown`, help texts are escaped again for OpenMetrics, which also escapes
+/// double quotes, other comments and blank lines are dropped and the exposition ends with `# EOF`.
+/// Label values are escaped the same way in both formats and are kept as-is.
+fn to_openmetrics(text: &str) -> String {
+    let counters: HashSet<&str> = text
+        .lines()
+        .filter_map(|line| line.strip_prefix("# TYPE "))
+        .filter_map(|line| line.strip_suffix(" counter"))
+        .collect();
+    let family = |name: &str| -> String {
+        match counters.contains(name) {
+            true => name.strip_suffix("_total").unwrap_or(name).to_string(),
+            false => name.to_string(),
+        }
+    };
+
+    let mut openmetrics = String::with_capacity(text.len() + 16);
+    for line in text.lines() {
+        if let Some(rest) = line.strip_prefix("# HELP ") {
+            let (name, help) = rest.split_once(' ').unwrap_or((rest, ""));
+            openmetrics.push_str("# HELP ");
+            openmetrics.push_str(&family(name));
+            openmetrics.push(' ');
+            escape_help(&mut openmetrics, help);
+        } else if let Some(rest) = line.strip_prefix("# TYPE ") {
+            let (name, kind) = rest.split_once(' ').unwrap_or((rest, "untyped"));
+            openmetrics.push_str("# TYPE ");
+            openmetrics.push_str(&family(name));
+            openmetrics.push(' ');
+            openmetrics.push_str(match kind {
+                "untyped" => "unknown",
+                kind => kind,
+            });
+        } else if line.is_empty() || line.starts_with('#') {
+            continue;
+        } else {
+            let end = line.find(['{', ' ']).unwrap_or(line.len());
+            let (name, rest) = line.split_at(end);
+            openmetrics.push_str(name);
+            if counters.contains(name) && !name.ends_with("_total") {
+                openmetrics.push_str("_total");
+            }
+            openmetrics.push_str(rest);
+        }
+        openmetrics.push('\n');
+    }
+    openmetrics.push_str("# EOF\n");
+    openmetrics
+}
+
+/// Escapes a help text of the Prometheus text format, where only backslashes and line feeds are
+/// escaped, for OpenMetrics.
+fn escape_help(out: &mut String, help: &str) {
+    let mut chars = help.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('n') => out.push_str("\\n"),
+                Some(c) => {
+                    out.push_str("\\\\");
+                    if c != '\\' {
+                        out.push(c);
+                    }
+                }
+                None => out.push_str("\\\\"),
+            },
+            '"' => out.push_str("\\\""),
+            c => out.push(c),
+        }
+    }
+}
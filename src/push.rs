@@ -0,0 +1,263 @@
+use std::fmt;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use log::warn;
+use metrics_exporter_prometheus::PrometheusHandle;
+use ureq::Agent;
+
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Shortest interval between pushes, so a zero interval doesn't push in a busy loop.
+const MIN_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Configuration for pushing the metrics to a Prometheus Pushgateway compatible endpoint.
+#[derive(Clone)]
+pub struct PushConfig {
+    url: String,
+    interval: Duration,
+    timeout: Duration,
+    retries: u32,
+    retry_backoff: Duration,
+    use_http_post_method: bool,
+    credentials: Option<(String, String)>,
+}
+
+impl fmt::Debug for PushConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PushConfig")
+            .field("url", &self.url)
+            .field("interval", &self.interval)
+            .field("timeout", &self.timeout)
+            .field("retries", &self.retries)
+            .field("retry_backoff", &self.retry_backoff)
+            .field("use_http_post_method", &self.use_http_post_method)
+            .field(
+                "credentials",
+                &self
+                    .credentials
+                    .as_ref()
+                    .map(|(username, _)| (username, "***")),
+            )
+            .finish()
+    }
+}
+
+impl PushConfig {
+    /// Create a configuration pushing to `url`, e.g. `http://pushgateway:9091/metrics/job/worker`
+    pub fn new<T: Into<String>>(url: T) -> Self {
+        Self {
+            url: url.into(),
+            interval: Duration::from_secs(10),
+            timeout: Duration::from_secs(5),
+            retries: 3,
+            retry_backoff: Duration::from_millis(500),
+            use_http_post_method: false,
+            credentials: None,
+        }
+    }
+
+    /// Set the interval between pushes, at least 10 milliseconds
+    ///
+    /// Defaults to 10 seconds
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval.max(MIN_INTERVAL);
+        self
+    }
+
+    /// Set the timeout of each push attempt
+    ///
+    /// Defaults to 5 seconds
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Set the number of retries of a failed push
+    ///
+    /// Defaults to 3
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Set the delay before the first retry, doubled for every following retry
+    ///
+    /// Defaults to 500 milliseconds
+    pub fn retry_backoff(mut self, backoff: Duration) -> Self {
+        self.retry_backoff = backoff;
+        self
+    }
+
+    /// Push with `POST`, only replacing the pushed metrics, instead of `PUT`, replacing every
+    /// metric of the group
+    ///
+    /// Defaults to false
+    pub fn use_http_post_method(mut self, value: bool) -> Self {
+        self.use_http_post_method = value;
+        self
+    }
+
+    /// Authenticate with HTTP basic authentication
+    pub fn basic_auth<U: Into<String>, P: Into<String>>(
+        mut self,
+        username: U,
+        password: P,
+    ) -> Self {
+        self.credentials = Some((username.into(), password.into()));
+        self
+    }
+}
+
+/// Pushes the metrics of a [`PrometheusHandle`] periodically and on shutdown.
+///
+/// Meant for short-lived servers and batch jobs that are never scraped. Pushes are done from a
+/// dedicated thread, a failed push is retried and logged at warn level once the retries are
+/// exhausted. A last push is done when the pusher is shut down, e.g. once the server has stopped.
+///
+/// [`shutdown`](Self::shutdown) blocks until the last push is done, up to `retries + 1` times the
+/// timeout plus the backoffs, so run it with `spawn_blocking` from async code. Dropping the pusher
+/// only signals the thread to push one last time without waiting for it, the push may not be done
+/// if the process exits first.
+///
+/// ```rust,no_run
+/// use actix_web::{App, HttpServer};
+/// use actix_web_metrics::{ActixWebMetricsBuilder, PrometheusPusher, PushConfig};
+/// use metrics_exporter_prometheus::PrometheusBuilder;
+///
+/// # #[actix_web::main]
+/// # async fn main() -> std::io::Result<()> {
+/// let prometheus = PrometheusBuilder::new().install_recorder().unwrap();
+/// let pusher = PrometheusPusher::start(
+///     prometheus,
+///     PushConfig::new("http://pushgateway:9091/metrics/job/worker"),
+/// );
+///
+/// let metrics = ActixWebMetricsBuilder::new().build();
+/// HttpServer::new(move || App::new().wrap(metrics.clone()))
+///     .bind("127.0.0.1:8080")?
+///     .run()
+///     .await?;
+///
+/// actix_web::rt::task::spawn_blocking(move || pusher.shutdown())
+///     .await
+///     .unwrap();
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct PrometheusPusher {
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl PrometheusPusher {
+    /// Start pushing the metrics of `handle`
+    pub fn start(handle: PrometheusHandle, config: PushConfig) -> Self {
+        let (stop, stopped) = mpsc::channel();
+        let thread = thread::Builder::new()
+            .name("actix-web-metrics-push".to_string())
+            .spawn(move || {
+                let push = Push::new(handle, config);
+                loop {
+                    match stopped.recv_timeout(push.config.interval) {
+                        Err(RecvTimeoutError::Timeout) => push.push(),
+                        Ok(()) | Err(RecvTimeoutError::Disconnected) => {
+                            push.push();
+                            break;
+                        }
+                    }
+                }
+            })
+            .expect("failed to spawn the push thread");
+
+        Self {
+            stop: Some(stop),
+            thread: Some(thread),
+        }
+    }
+
+    /// Stop pushing after a last push, blocking until it is done
+    pub fn shutdown(mut self) {
+        self.signal_stop();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+
+    fn signal_stop(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+    }
+}
+
+impl Drop for PrometheusPusher {
+    fn drop(&mut self) {
+        // joining could block an async runtime for as long as the last push takes
+        self.signal_stop();
+    }
+}
+
+struct Push {
+    handle: PrometheusHandle,
+    agent: Agent,
+    authorization: Option<String>,
+    config: PushConfig,
+}
+
+impl Push {
+    fn new(handle: PrometheusHandle, config: PushConfig) -> Self {
+        let agent = Agent::config_builder()
+            .timeout_global(Some(config.timeout))
+            .build()
+            .into();
+        let authorization = config.credentials.as_ref().map(|(username, password)| {
+            format!(
+                "Basic {}",
+                STANDARD.encode(format!("{username}:{password}"))
+            )
+        });
+        Self {
+            handle,
+            agent,
+            authorization,
+            config,
+        }
+    }
+
+    fn push(&self) {
+        let body = self.handle.render();
+        let mut backoff = self.config.retry_backoff;
+        for attempt in 0..=self.config.retries {
+            if attempt > 0 {
+                thread::sleep(backoff);
+                backoff = backoff.saturating_mul(2);
+            }
+            match self.send(&body) {
+                Ok(()) => return,
+                Err(err) if attempt == self.config.retries => {
+                    warn!("failed to push metrics to {}: {err}", self.config.url);
+                }
+                Err(_) => {}
+            }
+        }
+    }
+
+    fn send(&self, body: &str) -> Result<(), ureq::Error> {
+        let mut request = if self.config.use_http_post_method {
+            self.agent.post(&self.config.url)
+        } else {
+            self.agent.put(&self.config.url)
+        }
+        .header("Content-Type", CONTENT_TYPE);
+        if let Some(authorization) = &self.authorization {
+            request = request.header("Authorization", authorization);
+        }
+        request.send(body)?;
+        Ok(())
+    }
+}
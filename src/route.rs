@@ -0,0 +1,68 @@
+/// Rewrites the `http.route` label of matched requests before it is recorded.
+///
+/// Returning `None` keeps the route unchanged. Implemented for closures, e.g. to collapse API
+/// versions:
+///
+/// ```rust
+/// use actix_web_metrics::ActixWebMetricsBuilder;
+///
+/// let metrics = ActixWebMetricsBuilder::new()
+///     .route_normalizer(|route: &str| {
+///         let rest = route.strip_prefix("/v1/").or_else(|| route.strip_prefix("/v2/"))?;
+///         Some(format!("/{{version}}/{rest}"))
+///     })
+///     .build();
+/// ```
+///
+/// NOTE: The normalized routes are used as label values as-is, so a normalizer must not introduce
+/// unbounded cardinality.
+pub trait RouteNormalizer: Send + Sync + 'static {
+    /// Returns the route to record instead of `route`, if any
+    fn normalize(&self, route: &str) -> Option<String>;
+}
+
+impl<F> RouteNormalizer for F
+where
+    F: Fn(&str) -> Option<String> + Send + Sync + 'static,
+{
+    fn normalize(&self, route: &str) -> Option<String> {
+        self(route)
+    }
+}
+
+/// Number of path segments already matched by the scopes the middleware is mounted in, e.g. 2 for
+/// a middleware wrapping `web::scope("/api/v1")`, 0 when it wraps the `App`.
+pub(crate) fn mounted_depth(match_info: &actix_web::dev::Path<actix_web::dev::Url>) -> usize {
+    let path = match_info.as_str();
+    let processed = &path[..path.len() - match_info.unprocessed().len()];
+    processed.split('/').filter(|s| !s.is_empty()).count()
+}
+
+/// Number of segments of the longest prefix `pattern` starts with, 0 if none.
+pub(crate) fn prefix_depth(prefixes: &[String], pattern: &str) -> usize {
+    prefixes
+        .iter()
+        .filter(|prefix| {
+            pattern
+                .strip_prefix(prefix.as_str())
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+        })
+        .map(|prefix| prefix.split('/').filter(|s| !s.is_empty()).count())
+        .max()
+        .unwrap_or(0)
+}
+
+/// Splits `path` after its first `depth` segments, `None` if it isn't a path that deep.
+pub(crate) fn split_segments(path: &str, depth: usize) -> Option<(&str, &str)> {
+    if depth == 0 {
+        return Some(("", path));
+    }
+    if !path.starts_with('/') {
+        return None;
+    }
+    match path.match_indices('/').nth(depth) {
+        Some((i, _)) => Some(path.split_at(i)),
+        None if path[1..].split('/').count() == depth => Some((path, "")),
+        None => None,
+    }
+}
@@ -0,0 +1,149 @@
+use std::cell::RefCell;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::error::ConfigError;
+
+/// Configuration for sampling the request histograms.
+///
+/// Only a fraction of the requests record `http.server.request.duration`,
+/// `http.server.request.body.size` and `http.server.response.body.size`, the other requests are
+/// only counted in the exact `http.server.requests` counter. Histogram counts should therefore not
+/// be used as request counts when sampling is enabled.
+///
+/// Rates range from `0.0` (no request is sampled) to `1.0` (every request is sampled).
+#[derive(Debug, Clone)]
+pub struct SamplingConfig {
+    rate: f64,
+    routes: HashMap<String, f64>,
+    seed: Option<u64>,
+}
+
+impl Default for SamplingConfig {
+    fn default() -> Self {
+        Self {
+            rate: 1.0,
+            routes: HashMap::new(),
+            seed: None,
+        }
+    }
+}
+
+impl SamplingConfig {
+    /// Set the rate of sampled requests for routes without an override
+    ///
+    /// Defaults to 1.0
+    pub fn rate(mut self, rate: f64) -> Self {
+        self.rate = rate;
+        self
+    }
+
+    /// Set the rate of sampled requests for a route, e.g. `/resource/{id}`
+    ///
+    /// The route is matched against the `http.route` label value, after masking.
+    pub fn route<T: Into<String>>(mut self, route: T, rate: f64) -> Self {
+        self.routes.insert(route.into(), rate);
+        self
+    }
+
+    /// Set the seed of the random number generators, e.g. for reproducible tests
+    ///
+    /// Each worker draws from its own generator, seeded from this seed and the order in which the
+    /// workers sample their first request. Defaults to a random seed
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), ConfigError> {
+        let is_valid = |rate: f64| (0.0..=1.0).contains(&rate);
+        if !is_valid(self.rate) {
+            return Err(ConfigError::InvalidSampleRate {
+                route: None,
+                rate: self.rate,
+            });
+        }
+        for (route, &rate) in &self.routes {
+            if !is_valid(rate) {
+                return Err(ConfigError::InvalidSampleRate {
+                    route: Some(route.clone()),
+                    rate,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+thread_local! {
+    // SplitMix64 state of the current worker, keyed by the id of the sampler. Ids are never
+    // reused, the few bytes left by dropped samplers are not worth tracking the workers for.
+    static STATES: RefCell<HashMap<u64, u64>> = RefCell::new(HashMap::new());
+}
+
+/// Decides which requests are sampled, shared by all workers.
+///
+/// Each worker draws from its own SplitMix64 state, seeded from the seed and the index of the
+/// worker, so workers neither contend on a shared state nor draw the same numbers.
+pub(crate) struct Sampler {
+    id: u64,
+    rate: f64,
+    routes: HashMap<String, f64>,
+    seed: u64,
+    // index given to the next worker drawing its first number
+    next_thread: AtomicU64,
+}
+
+impl Sampler {
+    pub(crate) fn new(config: SamplingConfig) -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+        let seed = config
+            .seed
+            .unwrap_or_else(|| RandomState::new().hash_one(0_u64));
+        Self {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            rate: config.rate,
+            routes: config.routes,
+            seed,
+            next_thread: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn sample(&self, route: &str) -> bool {
+        let rate = self.routes.get(route).copied().unwrap_or(self.rate);
+        if rate >= 1.0 {
+            return true;
+        }
+        if rate <= 0.0 {
+            return false;
+        }
+        self.next_f64() < rate
+    }
+
+    /// Uniformly distributed in `[0, 1)`.
+    fn next_f64(&self) -> f64 {
+        let z = STATES
+            .try_with(|states| {
+                let mut states = states.borrow_mut();
+                let state = states.entry(self.id).or_insert_with(|| {
+                    let thread = self.next_thread.fetch_add(1, Ordering::Relaxed);
+                    self.seed.wrapping_add(mix(thread))
+                });
+                *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+                mix(*state)
+            })
+            // the worker is shutting down
+            .unwrap_or(0);
+        (z >> 11) as f64 * (1.0 / (1_u64 << 53) as f64)
+    }
+}
+
+/// SplitMix64 output function.
+fn mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
@@ -0,0 +1,114 @@
+use std::collections::BTreeMap;
+
+use actix_web::http::StatusCode;
+use serde::{Deserialize, Serialize};
+
+use crate::{ActixWebMetricsBuilder, ActixWebMetricsConfig, ConfigError};
+
+/// Serializable configuration of the middleware, e.g. loaded from a TOML or YAML file.
+///
+/// Every field is optional and defaults to the same value as [`ActixWebMetricsBuilder::new`].
+/// Convert it into a builder with `ActixWebMetricsBuilder::try_from`.
+///
+/// ```toml
+/// namespace = "my_app"
+/// exclude = ["/health"]
+/// exclude_regex = ["/readyz/.*"]
+/// exclude_status = [404]
+/// unmatched_patterns_mask = "UNMATCHED"
+/// request_counter = true
+///
+/// [const_labels]
+/// service = "api"
+///
+/// [metrics]
+/// http_server_request_duration_name = "http_request_duration"
+///
+/// [metrics.labels]
+/// http_route = "route"
+/// ```
+#[cfg_attr(docsrs, doc(cfg(feature = "serde")))]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+#[non_exhaustive]
+pub struct ActixWebMetricsSettings {
+    /// Namespace prefixed to every metric name
+    pub namespace: Option<String>,
+    /// Labels added to every metric
+    pub const_labels: BTreeMap<String, String>,
+    /// Paths for which no metrics are recorded
+    pub exclude: Vec<String>,
+    /// Regexes of paths for which no metrics are recorded
+    pub exclude_regex: Vec<String>,
+    /// Status codes for which no metrics are recorded
+    pub exclude_status: Vec<u16>,
+    /// Whether requests not matched to a handler are masked
+    pub mask_unmatched_patterns: bool,
+    /// Mask for requests not matched to a handler, ignored unless `mask_unmatched_patterns` is set
+    pub unmatched_patterns_mask: String,
+    /// Whether requests are counted in the `http.server.requests` counter
+    pub request_counter: bool,
+    /// Whether the scope of requests is recorded in a `http.scope` label
+    pub scope_label: bool,
+    /// Scope patterns used for the `http.scope` label when the middleware wraps the `App`
+    pub scope_prefixes: Vec<String>,
+    /// Metric and label names
+    pub metrics: ActixWebMetricsConfig,
+}
+
+impl Default for ActixWebMetricsSettings {
+    fn default() -> Self {
+        Self {
+            namespace: None,
+            const_labels: BTreeMap::new(),
+            exclude: Vec::new(),
+            exclude_regex: Vec::new(),
+            exclude_status: Vec::new(),
+            mask_unmatched_patterns: true,
+            unmatched_patterns_mask: "UNKNOWN".to_string(),
+            request_counter: false,
+            scope_label: false,
+            scope_prefixes: Vec::new(),
+            metrics: ActixWebMetricsConfig::default(),
+        }
+    }
+}
+
+/// Fails with [`ConfigError::InvalidStatusCode`] for a status code outside `100..=999`, the
+/// remaining settings are validated by [`ActixWebMetricsBuilder::try_build`].
+impl TryFrom<ActixWebMetricsSettings> for ActixWebMetricsBuilder {
+    type Error = ConfigError;
+
+    fn try_from(settings: ActixWebMetricsSettings) -> Result<Self, Self::Error> {
+        let mut builder = ActixWebMetricsBuilder::new()
+            .const_labels(settings.const_labels.into_iter().collect())
+            .request_counter(settings.request_counter)
+            .scope_label(settings.scope_label)
+            .metrics_config(settings.metrics);
+
+        if let Some(namespace) = settings.namespace {
+            builder = builder.namespace(namespace);
+        }
+        for path in settings.exclude {
+            builder = builder.exclude(path);
+        }
+        for pattern in settings.exclude_regex {
+            builder = builder.exclude_regex(pattern);
+        }
+        if !settings.scope_prefixes.is_empty() {
+            builder = builder.scope_prefixes(settings.scope_prefixes);
+        }
+        for status in settings.exclude_status {
+            let status =
+                StatusCode::from_u16(status).map_err(|_| ConfigError::InvalidStatusCode(status))?;
+            builder = builder.exclude_status(status);
+        }
+        builder = if settings.mask_unmatched_patterns {
+            builder.mask_unmatched_patterns(settings.unmatched_patterns_mask)
+        } else {
+            builder.disable_unmatched_pattern_masking()
+        };
+
+        Ok(builder)
+    }
+}
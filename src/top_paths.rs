@@ -0,0 +1,352 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, Weak};
+use std::time::Duration;
+
+use log::info;
+
+/// Longest path kept, longer paths are truncated so scanners can't exhaust memory.
+const MAX_PATH_LENGTH: usize = 256;
+
+/// Configuration for tracking the most requested unmatched paths.
+///
+/// The raw paths of requests not matched to a handler are counted with the Space-Saving
+/// algorithm, which keeps `capacity` paths at most: the counts of frequent paths are accurate,
+/// a path evicting another inherits its count as over-estimation `error`. Paths are only tracked
+/// in memory, they never become metric labels.
+#[derive(Debug, Clone)]
+pub struct TopUnmatchedPathsConfig {
+    capacity: usize,
+    log_interval: Option<Duration>,
+}
+
+impl Default for TopUnmatchedPathsConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 64,
+            log_interval: None,
+        }
+    }
+}
+
+impl TopUnmatchedPathsConfig {
+    /// Set the number of tracked paths
+    ///
+    /// Defaults to 64
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    /// Log the top paths at info level every `interval`
+    ///
+    /// Logged from the first worker, disabled by default
+    pub fn log_interval(mut self, interval: Duration) -> Self {
+        self.log_interval = Some(interval);
+        self
+    }
+}
+
+/// Number of requests to an unmatched path.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct UnmatchedPathCount {
+    /// Raw request path, truncated to 256 bytes
+    pub path: String,
+    /// Number of requests, over-estimated by at most `error`
+    pub count: u64,
+    /// Maximum over-estimation of `count`
+    pub error: u64,
+}
+
+/// Space-Saving counters, shared by all workers.
+pub(crate) struct TopUnmatchedPaths {
+    capacity: usize,
+    log_interval: Option<Duration>,
+    counters: Mutex<StreamSummary>,
+    log_started: AtomicBool,
+}
+
+impl TopUnmatchedPaths {
+    pub(crate) fn new(config: &TopUnmatchedPathsConfig) -> Self {
+        Self {
+            capacity: config.capacity,
+            log_interval: config.log_interval,
+            counters: Mutex::new(StreamSummary::with_capacity(config.capacity)),
+            log_started: AtomicBool::new(false),
+        }
+    }
+
+    pub(crate) fn record(&self, path: &str) {
+        if self.capacity == 0 {
+            return;
+        }
+        let path = truncate(path);
+
+        if self.lock().increment(path) {
+            return;
+        }
+
+        // allocated and freed without holding the lock
+        let path: Arc<str> = Arc::from(path);
+        let evicted = self.lock().insert(path, self.capacity);
+        drop(evicted);
+    }
+
+    /// Tracked paths, most requested first.
+    pub(crate) fn top(&self) -> Vec<UnmatchedPathCount> {
+        let mut top = self.lock().counts();
+        top.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.path.cmp(&b.path)));
+        top
+    }
+
+    fn lock(&self) -> MutexGuard<'_, StreamSummary> {
+        self.counters.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Logs the top paths periodically on the current arbiter, if enabled and not already done by
+    /// another worker.
+    pub(crate) fn spawn_periodic_log(self: &Arc<Self>) {
+        let Some(interval) = self.log_interval else {
+            return;
+        };
+        let Some(arbiter) = actix_rt::Arbiter::try_current() else {
+            return;
+        };
+        if self.log_started.swap(true, Ordering::Relaxed) {
+            return;
+        }
+
+        let tracker = Arc::downgrade(self);
+        arbiter.spawn(async move {
+            // lets the next worker take over if this one shuts down
+            let _guard = LogGuard(tracker.clone());
+            let mut ticks = actix_rt::time::interval(interval);
+            // the first tick completes immediately
+            ticks.tick().await;
+            loop {
+                ticks.tick().await;
+                let Some(tracker) = tracker.upgrade() else {
+                    break;
+                };
+                tracker.log(10);
+            }
+        });
+    }
+
+    fn log(&self, limit: usize) {
+        let top = self.top();
+        if top.is_empty() {
+            return;
+        }
+        let mut message = String::new();
+        for (i, entry) in top.iter().take(limit).enumerate() {
+            if i > 0 {
+                message.push_str(", ");
+            }
+            let _ = write!(message, "{} ({})", entry.path, entry.count);
+        }
+        info!("top unmatched paths: {message}");
+    }
+}
+
+/// Stream-Summary of the Space-Saving algorithm.
+///
+/// Paths are grouped in buckets of equal counts, kept in a list sorted by count, so incrementing
+/// a path and evicting one of the least requested paths are done in constant time.
+struct StreamSummary {
+    index: HashMap<Arc<str>, usize>,
+    entries: Vec<Entry>,
+    buckets: Vec<Bucket>,
+    // slots of `buckets` no longer in the list
+    free_buckets: Vec<usize>,
+    // bucket with the lowest count
+    min_bucket: Option<usize>,
+}
+
+struct Entry {
+    path: Arc<str>,
+    error: u64,
+    bucket: usize,
+    // siblings in the bucket
+    prev: Option<usize>,
+    next: Option<usize>,
+}
+
+struct Bucket {
+    count: u64,
+    first: Option<usize>,
+    // neighbours with a lower and higher count
+    prev: Option<usize>,
+    next: Option<usize>,
+}
+
+impl StreamSummary {
+    fn with_capacity(capacity: usize) -> Self {
+        Self {
+            index: HashMap::with_capacity(capacity),
+            entries: Vec::with_capacity(capacity),
+            buckets: Vec::new(),
+            free_buckets: Vec::new(),
+            min_bucket: None,
+        }
+    }
+
+    /// Increments the count of `path`, returning whether it is tracked.
+    fn increment(&mut self, path: &str) -> bool {
+        match self.index.get(path) {
+            Some(&entry) => {
+                self.move_up(entry);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Tracks `path` once counted, evicting a least requested path if `capacity` paths are
+    /// tracked, which is returned.
+    fn insert(&mut self, path: Arc<str>, capacity: usize) -> Option<Arc<str>> {
+        // may have been tracked by another worker while the lock was released
+        if self.increment(&path) {
+            return None;
+        }
+
+        if self.entries.len() < capacity {
+            let bucket = match self.min_bucket {
+                Some(min) if self.buckets[min].count == 1 => min,
+                min => self.new_bucket(1, None, min),
+            };
+            let entry = self.entries.len();
+            self.entries.push(Entry {
+                path: path.clone(),
+                error: 0,
+                bucket,
+                prev: None,
+                next: None,
+            });
+            self.attach(entry, bucket);
+            self.index.insert(path, entry);
+            return None;
+        }
+
+        // the new path replaces a least requested one and inherits its count
+        let min = self.min_bucket?;
+        let entry = self.buckets[min].first?;
+        let evicted = std::mem::replace(&mut self.entries[entry].path, path.clone());
+        self.entries[entry].error = self.buckets[min].count;
+        self.index.remove(&evicted);
+        self.index.insert(path, entry);
+        self.move_up(entry);
+        Some(evicted)
+    }
+
+    fn counts(&self) -> Vec<UnmatchedPathCount> {
+        self.entries
+            .iter()
+            .map(|entry| UnmatchedPathCount {
+                path: entry.path.to_string(),
+                count: self.buckets[entry.bucket].count,
+                error: entry.error,
+            })
+            .collect()
+    }
+
+    /// Moves `entry` to the bucket of the next count.
+    fn move_up(&mut self, entry: usize) {
+        let bucket = self.entries[entry].bucket;
+        let count = self.buckets[bucket].count + 1;
+        let target = match self.buckets[bucket].next {
+            Some(next) if self.buckets[next].count == count => next,
+            next => self.new_bucket(count, Some(bucket), next),
+        };
+        self.detach(entry);
+        self.attach(entry, target);
+    }
+
+    fn attach(&mut self, entry: usize, bucket: usize) {
+        let first = self.buckets[bucket].first.replace(entry);
+        if let Some(first) = first {
+            self.entries[first].prev = Some(entry);
+        }
+        let entry = &mut self.entries[entry];
+        entry.bucket = bucket;
+        entry.prev = None;
+        entry.next = first;
+    }
+
+    /// Removes `entry` from its bucket, and the bucket from the list once empty.
+    fn detach(&mut self, entry: usize) {
+        let Entry {
+            bucket, prev, next, ..
+        } = self.entries[entry];
+        match prev {
+            Some(prev) => self.entries[prev].next = next,
+            None => self.buckets[bucket].first = next,
+        }
+        if let Some(next) = next {
+            self.entries[next].prev = prev;
+        }
+
+        if self.buckets[bucket].first.is_none() {
+            let Bucket { prev, next, .. } = self.buckets[bucket];
+            match prev {
+                Some(prev) => self.buckets[prev].next = next,
+                None => self.min_bucket = next,
+            }
+            if let Some(next) = next {
+                self.buckets[next].prev = prev;
+            }
+            self.free_buckets.push(bucket);
+        }
+    }
+
+    /// Adds an empty bucket between `prev` and `next`.
+    fn new_bucket(&mut self, count: u64, prev: Option<usize>, next: Option<usize>) -> usize {
+        let bucket = Bucket {
+            count,
+            first: None,
+            prev,
+            next,
+        };
+        let index = match self.free_buckets.pop() {
+            Some(index) => {
+                self.buckets[index] = bucket;
+                index
+            }
+            None => {
+                self.buckets.push(bucket);
+                self.buckets.len() - 1
+            }
+        };
+        match prev {
+            Some(prev) => self.buckets[prev].next = Some(index),
+            None => self.min_bucket = Some(index),
+        }
+        if let Some(next) = next {
+            self.buckets[next].prev = Some(index);
+        }
+        index
+    }
+}
+
+struct LogGuard(Weak<TopUnmatchedPaths>);
+
+impl Drop for LogGuard {
+    fn drop(&mut self) {
+        if let Some(tracker) = self.0.upgrade() {
+            tracker.log_started.store(false, Ordering::Relaxed);
+        }
+    }
+}
+
+fn truncate(path: &str) -> &str {
+    if path.len() <= MAX_PATH_LENGTH {
+        return path;
+    }
+    let mut end = MAX_PATH_LENGTH;
+    while !path.is_char_boundary(end) {
+        end -= 1;
+    }
+    &path[..end]
+}
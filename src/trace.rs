@@ -0,0 +1,64 @@
+/// Span of a request, captured when the middleware is called as the body may be dropped outside
+/// of it.
+#[derive(Clone)]
+pub(crate) struct RequestSpan {
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+}
+
+/// Label values and timings of a recorded request.
+#[cfg_attr(not(feature = "tracing"), allow(dead_code))]
+pub(crate) struct RequestFields<'a> {
+    pub(crate) scope: Option<&'a str>,
+    pub(crate) route: &'a str,
+    pub(crate) method: &'a str,
+    pub(crate) status: u16,
+    pub(crate) request_size: usize,
+    pub(crate) response_size: usize,
+    pub(crate) duration: f64,
+}
+
+impl RequestSpan {
+    #[cfg(feature = "tracing")]
+    pub(crate) fn current() -> Self {
+        Self {
+            span: tracing::Span::current(),
+        }
+    }
+
+    #[cfg(not(feature = "tracing"))]
+    pub(crate) fn current() -> Self {
+        Self {}
+    }
+
+    /// Records the fields on the span, if it declares them, and in a debug event.
+    #[cfg(feature = "tracing")]
+    pub(crate) fn record(&self, fields: &RequestFields<'_>) {
+        let span = &self.span;
+        if let Some(scope) = fields.scope {
+            span.record("http.scope", scope);
+        }
+        span.record("http.route", fields.route);
+        span.record("http.response.status_code", fields.status);
+        span.record("http.request.body.size", fields.request_size);
+        span.record("http.response.body.size", fields.response_size);
+        span.record("http.server.request.duration", fields.duration);
+
+        tracing::event!(
+            target: "actix_web_metrics",
+            parent: span,
+            tracing::Level::DEBUG,
+            http.scope = fields.scope,
+            http.route = fields.route,
+            http.request.method = fields.method,
+            http.response.status_code = fields.status,
+            http.request.body.size = fields.request_size,
+            http.response.body.size = fields.response_size,
+            http.server.request.duration = fields.duration,
+            "request recorded"
+        );
+    }
+
+    #[cfg(not(feature = "tracing"))]
+    pub(crate) fn record(&self, _fields: &RequestFields<'_>) {}
+}
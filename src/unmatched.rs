@@ -0,0 +1,170 @@
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::sync::{PoisonError, RwLock};
+
+use regex::Regex;
+
+use crate::error::ConfigError;
+
+/// Configuration for normalizing the paths of requests not matched to a handler.
+///
+/// Instead of masking every unmatched request, each segment of the path is replaced by the
+/// placeholder of the first rule matching the whole segment, e.g. `/users/42/avatar` is labelled
+/// `/users/{id}/avatar`. Segments longer than `max_segment_length` become `{segment}` and paths
+/// deeper than `max_depth` are truncated, ending with `/...`.
+///
+/// Once `max_distinct` different paths have been labelled, paths not seen before are labelled
+/// with the unmatched patterns mask (`UNKNOWN` if masking is disabled), so the cardinality stays
+/// bounded whatever the requests.
+///
+/// The default rules, applied in this order, are:
+///
+/// | Segment                       | Placeholder |
+/// |-------------------------------|-------------|
+/// | digits                        | `{id}`      |
+/// | UUID                          | `{uuid}`    |
+/// | 16 or more hexadecimal digits | `{hash}`    |
+#[derive(Debug, Clone)]
+pub struct UnmatchedPathsConfig {
+    rules: Vec<(String, String)>,
+    max_segment_length: usize,
+    max_depth: usize,
+    max_distinct: usize,
+}
+
+impl Default for UnmatchedPathsConfig {
+    fn default() -> Self {
+        Self {
+            rules: vec![
+                ("[0-9]+".to_string(), "{id}".to_string()),
+                (
+                    "[0-9a-fA-F]{8}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{12}"
+                        .to_string(),
+                    "{uuid}".to_string(),
+                ),
+                ("[0-9a-fA-F]{16,}".to_string(), "{hash}".to_string()),
+            ],
+            max_segment_length: 32,
+            max_depth: 4,
+            max_distinct: 100,
+        }
+    }
+}
+
+impl UnmatchedPathsConfig {
+    /// Add a rule replacing the segments fully matching `pattern` by `placeholder`
+    ///
+    /// Rules are applied in the order they were added, after the default rules.
+    pub fn rule<P: Into<String>, T: Into<String>>(mut self, pattern: P, placeholder: T) -> Self {
+        self.rules.push((pattern.into(), placeholder.into()));
+        self
+    }
+
+    /// Remove all rules, including the default ones
+    pub fn clear_rules(mut self) -> Self {
+        self.rules.clear();
+        self
+    }
+
+    /// Set the length above which a segment is replaced by `{segment}`
+    ///
+    /// Defaults to 32
+    pub fn max_segment_length(mut self, length: usize) -> Self {
+        self.max_segment_length = length;
+        self
+    }
+
+    /// Set the number of segments kept, deeper paths are truncated
+    ///
+    /// Defaults to 4
+    pub fn max_depth(mut self, depth: usize) -> Self {
+        self.max_depth = depth;
+        self
+    }
+
+    /// Set the number of distinct normalized paths, other paths are masked
+    ///
+    /// Defaults to 100
+    pub fn max_distinct(mut self, max_distinct: usize) -> Self {
+        self.max_distinct = max_distinct;
+        self
+    }
+}
+
+/// Labels unmatched paths according to an [`UnmatchedPathsConfig`].
+pub(crate) struct UnmatchedPathNormalizer {
+    rules: Vec<(Regex, String)>,
+    max_segment_length: usize,
+    max_depth: usize,
+    max_distinct: usize,
+    seen: RwLock<HashSet<String>>,
+}
+
+impl UnmatchedPathNormalizer {
+    pub(crate) fn new(config: UnmatchedPathsConfig) -> Result<Self, ConfigError> {
+        let rules = config
+            .rules
+            .into_iter()
+            .map(
+                |(pattern, placeholder)| match Regex::new(&format!("^(?:{pattern})$")) {
+                    Ok(regex) => Ok((regex, placeholder)),
+                    Err(source) => Err(ConfigError::InvalidNormalizationRule { pattern, source }),
+                },
+            )
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            rules,
+            max_segment_length: config.max_segment_length,
+            max_depth: config.max_depth,
+            max_distinct: config.max_distinct,
+            seen: RwLock::default(),
+        })
+    }
+
+    /// Returns the label of `path`, or `overflow` once the distinct paths limit is reached.
+    pub(crate) fn label<'a>(&self, path: &str, overflow: &'a str) -> Cow<'a, str> {
+        let normalized = self.normalize(path);
+
+        if self
+            .seen
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .contains(&normalized)
+        {
+            return Cow::Owned(normalized);
+        }
+
+        let mut seen = self.seen.write().unwrap_or_else(PoisonError::into_inner);
+        if seen.len() < self.max_distinct || seen.contains(&normalized) {
+            seen.insert(normalized.clone());
+            Cow::Owned(normalized)
+        } else {
+            Cow::Borrowed(overflow)
+        }
+    }
+
+    fn normalize(&self, path: &str) -> String {
+        let mut normalized = String::with_capacity(path.len());
+        let segments = path.strip_prefix('/').unwrap_or(path).split('/');
+        for (depth, segment) in segments.enumerate() {
+            normalized.push('/');
+            if depth == self.max_depth {
+                normalized.push_str("...");
+                break;
+            }
+            normalized.push_str(self.normalize_segment(segment));
+        }
+        normalized
+    }
+
+    fn normalize_segment<'a>(&'a self, segment: &'a str) -> &'a str {
+        if let Some((_, placeholder)) = self.rules.iter().find(|(rule, _)| rule.is_match(segment)) {
+            return placeholder;
+        }
+        if segment.chars().count() > self.max_segment_length {
+            return "{segment}";
+        }
+        segment
+    }
+}
@@ -0,0 +1,61 @@
+//! Lives in its own test binary as it installs a counting global allocator.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicIsize, Ordering};
+
+use actix_web_metrics::{ActixWebMetricsBuilder, ActixWebMetricsConfig, LabelsConfig};
+
+struct CountingAllocator;
+
+static LIVE_BYTES: AtomicIsize = AtomicIsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        LIVE_BYTES.fetch_add(layout.size() as isize, Ordering::SeqCst);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        LIVE_BYTES.fetch_sub(layout.size() as isize, Ordering::SeqCst);
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn build_metrics(i: usize) {
+    let metrics = ActixWebMetricsBuilder::new()
+        .namespace(format!("tenant_{i}"))
+        .const_labels(HashMap::from([
+            ("tenant".to_string(), i.to_string()),
+            ("region".to_string(), "local".to_string()),
+        ]))
+        .exclude("/health")
+        .metrics_config(
+            ActixWebMetricsConfig::default()
+                .http_server_request_duration_name(format!("duration_{i}"))
+                .labels(LabelsConfig::default().http_route(format!("route_{i}"))),
+        )
+        .build();
+    drop(metrics);
+}
+
+#[test]
+fn build_does_not_leak() {
+    // warm up lazily initialized statics
+    build_metrics(0);
+
+    let before = LIVE_BYTES.load(Ordering::SeqCst);
+    for i in 0..5_000 {
+        build_metrics(i);
+    }
+    let after = LIVE_BYTES.load(Ordering::SeqCst);
+
+    assert!(
+        after - before < 1024,
+        "building 5000 instances retained {} bytes",
+        after - before
+    );
+}
@@ -1,15 +1,24 @@
-use std::collections::HashMap;
+// `middleware_http_version` predates the lint
+#![allow(clippy::match_ref_pats)]
 
-use actix_web::dev::Service;
-use actix_web::http::{StatusCode, Version};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{Service, ServiceFactory, ServiceRequest, ServiceResponse};
+use actix_web::http::{Method, StatusCode, Version};
 use actix_web::test::{call_service, init_service, read_body, TestRequest};
 use actix_web::{web, App, HttpMessage, HttpResponse, Resource, Scope};
 use actix_web_metrics::{
-    ActixWebMetricsBuilder, ActixWebMetricsConfig, ActixWebMetricsExtension, LabelsConfig,
+    AccessLogConfig, ActixWebMetricsBuilder, ActixWebMetricsConfig, ActixWebMetricsExtension,
+    ConfigError, LabelsConfig, LocalAggregationConfig, MetricsObserver, RequestContext,
+    RequestContextRecorder, RequestMetrics, RequestOutcome, RouteLabel, SamplingConfig,
+    UnmatchedPathsConfig,
 };
-use metrics::{counter, set_default_local_recorder};
+use metrics::{counter, set_default_local_recorder, Unit};
 use metrics_exporter_prometheus::PrometheusBuilder;
-use metrics_util::debugging::DebuggingRecorder;
+use metrics_util::debugging::{DebugValue, DebuggingRecorder, Snapshot};
 
 const SNAPSHOT_FILTERS: [(&str, &str); 2] =
     [(r"\d\.\d+e-\d+", "[VALUE]"), (r"\d\.\d{5, 20}", "[VALUE]")];
@@ -426,3 +435,1736 @@ async fn middleware_with_size_metrics() {
         insta::assert_debug_snapshot!(snapshot);
     });
 }
+
+/// Metric recorded in a snapshot.
+#[derive(Debug)]
+struct Recorded {
+    name: String,
+    // sorted by key
+    labels: Vec<(String, String)>,
+    value: DebugValue,
+}
+
+impl Recorded {
+    fn label(&self, key: &str) -> Option<String> {
+        self.labels
+            .iter()
+            .find(|(label, _)| label == key)
+            .map(|(_, value)| value.clone())
+    }
+
+    fn is_histogram(&self) -> bool {
+        matches!(self.value, DebugValue::Histogram(_))
+    }
+
+    /// Counter value or number of histogram samples, `0` for a gauge.
+    fn count(&self) -> u64 {
+        match &self.value {
+            DebugValue::Counter(value) => *value,
+            DebugValue::Gauge(_) => 0,
+            DebugValue::Histogram(values) => values.len() as u64,
+        }
+    }
+
+    /// Counter or gauge value, or sum of the histogram samples.
+    fn sum(&self) -> f64 {
+        match &self.value {
+            DebugValue::Counter(value) => *value as f64,
+            DebugValue::Gauge(value) => value.0,
+            DebugValue::Histogram(values) => values.iter().map(|v| v.0).sum(),
+        }
+    }
+}
+
+/// Metrics recorded in a snapshot, sorted by name and labels.
+fn recorded(snapshot: Snapshot) -> Vec<Recorded> {
+    let mut recorded: Vec<Recorded> = snapshot
+        .into_vec()
+        .into_iter()
+        .map(|(key, _, _, value)| {
+            let mut labels: Vec<(String, String)> = key
+                .key()
+                .labels()
+                .map(|label| (label.key().to_string(), label.value().to_string()))
+                .collect();
+            labels.sort();
+            Recorded {
+                name: key.key().name().to_string(),
+                labels,
+                value,
+            }
+        })
+        .collect();
+    recorded.sort_by(|a, b| (&a.name, &a.labels).cmp(&(&b.name, &b.labels)));
+    recorded
+}
+
+/// Number of recorded requests per key, e.g. the route label value, of their
+/// `http.server.request.duration` samples.
+fn recorded_requests<K: Ord>(
+    snapshot: Snapshot,
+    key: impl Fn(&Recorded) -> Option<K>,
+) -> BTreeMap<K, u64> {
+    recorded(snapshot)
+        .into_iter()
+        .filter(|metric| metric.name == "http.server.request.duration" && metric.count() > 0)
+        .filter_map(|metric| Some((key(&metric)?, metric.count())))
+        .collect()
+}
+
+async fn call_mixed_requests<S, B>(app: &S)
+where
+    S: Service<actix_http::Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody,
+{
+    for _ in 0..5 {
+        let res = call_service(app, TestRequest::with_uri("/health_check").to_request()).await;
+        assert_eq!(read_body(res).await, "test response");
+    }
+    for id in 0..3 {
+        let req = TestRequest::post()
+            .uri(&format!("/resource/{id}"))
+            .set_payload("payload")
+            .to_request();
+        let res = call_service(app, req).await;
+        assert!(res.status().is_success());
+        read_body(res).await;
+    }
+    for _ in 0..2 {
+        let res = call_service(app, TestRequest::with_uri("/missing").to_request()).await;
+        assert!(res.status().is_client_error());
+        read_body(res).await;
+    }
+}
+
+fn mixed_requests_app() -> App<
+    impl ServiceFactory<
+        ServiceRequest,
+        Config = (),
+        Response = ServiceResponse<impl MessageBody>,
+        Error = actix_web::Error,
+        InitError = (),
+    >,
+> {
+    App::new()
+        .service(
+            web::resource("/health_check")
+                .to(|| async { HttpResponse::Ok().body("test response") }),
+        )
+        .service(web::resource("/resource/{id}").to(HttpResponse::Ok))
+}
+
+#[actix_web::test]
+async fn middleware_local_aggregation_matches_direct() {
+    let direct_recorder = DebuggingRecorder::new();
+    let direct_snapshotter = direct_recorder.snapshotter();
+    {
+        let _guard = set_default_local_recorder(&direct_recorder);
+        let app =
+            init_service(mixed_requests_app().wrap(ActixWebMetricsBuilder::new().build())).await;
+        call_mixed_requests(&app).await;
+    }
+
+    let aggregated_recorder = DebuggingRecorder::new();
+    let aggregated_snapshotter = aggregated_recorder.snapshotter();
+    {
+        let _guard = set_default_local_recorder(&aggregated_recorder);
+        let metrics = ActixWebMetricsBuilder::new()
+            .local_aggregation(
+                LocalAggregationConfig::default()
+                    .flush_interval(Duration::from_secs(3600))
+                    .flush_threshold(4),
+            )
+            .build();
+        let app = init_service(mixed_requests_app().wrap(metrics.clone())).await;
+        call_mixed_requests(&app).await;
+        metrics.flush_local_aggregate();
+    }
+
+    // durations differ between runs
+    let totals = |snapshot| {
+        recorded(snapshot)
+            .into_iter()
+            .map(|metric| {
+                let sum = if metric.name == "http.server.request.duration" {
+                    0.0
+                } else {
+                    metric.sum()
+                };
+                let count = metric.count();
+                (metric.name, metric.labels, count, sum)
+            })
+            .collect::<Vec<_>>()
+    };
+    let direct = totals(direct_snapshotter.snapshot());
+    let aggregated = totals(aggregated_snapshotter.snapshot());
+    assert_eq!(direct.len(), 11);
+    assert_eq!(direct, aggregated);
+}
+
+#[actix_web::test]
+async fn middleware_local_aggregation_counts_per_bucket() {
+    let recorder = DebuggingRecorder::new();
+    let snapshotter = recorder.snapshotter();
+    let _guard = set_default_local_recorder(&recorder);
+
+    let metrics = ActixWebMetricsBuilder::new()
+        .local_aggregation(
+            LocalAggregationConfig::default()
+                .flush_interval(Duration::from_secs(3600))
+                .duration_buckets(&[60.0])
+                .body_size_buckets(&[10.0, 0.0]),
+        )
+        .build();
+    let app = init_service(mixed_requests_app().wrap(metrics.clone())).await;
+    call_mixed_requests(&app).await;
+    metrics.flush_local_aggregate();
+
+    let mut histograms: BTreeMap<String, Vec<f64>> = BTreeMap::new();
+    for (key, _, _, value) in snapshotter.snapshot().into_vec() {
+        if let DebugValue::Histogram(values) = value {
+            let name = key.key().name().to_string();
+            histograms
+                .entry(name)
+                .or_default()
+                .extend(values.iter().map(|value| value.0));
+        }
+    }
+    for values in histograms.values_mut() {
+        values.sort_by(f64::total_cmp);
+    }
+
+    // values are recorded as the upper bound of their bucket, or as-is above the last one
+    assert_eq!(
+        histograms,
+        BTreeMap::from([
+            ("http.server.request.body.size".to_string(), vec![0.0; 10]),
+            ("http.server.request.duration".to_string(), vec![60.0; 10]),
+            (
+                "http.server.response.body.size".to_string(),
+                [[0.0; 5].as_slice(), &[13.0; 5]].concat()
+            ),
+        ])
+    );
+}
+
+#[actix_web::test]
+async fn middleware_local_aggregation_flushes_on_threshold() {
+    let recorder = DebuggingRecorder::new();
+    let snapshotter = recorder.snapshotter();
+    let _guard = set_default_local_recorder(&recorder);
+
+    let metrics = ActixWebMetricsBuilder::new()
+        .local_aggregation(
+            LocalAggregationConfig::default()
+                .flush_interval(Duration::from_secs(3600))
+                .flush_threshold(2),
+        )
+        .build();
+    let app = init_service(
+        App::new()
+            .wrap(metrics)
+            .service(web::resource("/health_check").to(HttpResponse::Ok)),
+    )
+    .await;
+
+    call_service(&app, TestRequest::with_uri("/health_check").to_request()).await;
+    let recorded_histograms = recorded(snapshotter.snapshot())
+        .into_iter()
+        .filter(|metric| metric.is_histogram() && metric.count() > 0)
+        .count();
+    assert_eq!(recorded_histograms, 0);
+
+    call_service(&app, TestRequest::with_uri("/health_check").to_request()).await;
+    let histogram_counts: Vec<_> = recorded(snapshotter.snapshot())
+        .iter()
+        .filter(|metric| metric.is_histogram())
+        .map(Recorded::count)
+        .collect();
+    assert_eq!(histogram_counts, vec![2, 2, 2]);
+}
+
+#[actix_web::test]
+async fn middleware_local_aggregation_flushes_on_interval() {
+    let recorder = DebuggingRecorder::new();
+    let snapshotter = recorder.snapshotter();
+    let _guard = set_default_local_recorder(&recorder);
+
+    let metrics = ActixWebMetricsBuilder::new()
+        .local_aggregation(
+            LocalAggregationConfig::default().flush_interval(Duration::from_millis(50)),
+        )
+        .build();
+    let app = init_service(
+        App::new()
+            .wrap(metrics)
+            .service(web::resource("/health_check").to(HttpResponse::Ok)),
+    )
+    .await;
+
+    call_service(&app, TestRequest::with_uri("/health_check").to_request()).await;
+    actix_web::rt::time::sleep(Duration::from_millis(200)).await;
+
+    let histogram_counts: Vec<_> = recorded(snapshotter.snapshot())
+        .iter()
+        .filter(|metric| metric.is_histogram())
+        .map(Recorded::count)
+        .collect();
+    assert_eq!(histogram_counts, vec![1, 1, 1]);
+}
+
+#[test]
+fn middleware_local_aggregation_flushes_on_worker_shutdown() {
+    let recorder = DebuggingRecorder::new();
+    let snapshotter = recorder.snapshotter();
+
+    std::thread::scope(|scope| {
+        scope.spawn(|| {
+            let _guard = set_default_local_recorder(&recorder);
+            actix_web::rt::System::new().block_on(async {
+                let metrics = ActixWebMetricsBuilder::new()
+                    .local_aggregation(
+                        LocalAggregationConfig::default().flush_interval(Duration::from_secs(3600)),
+                    )
+                    .build();
+                let app = init_service(mixed_requests_app().wrap(metrics)).await;
+                call_mixed_requests(&app).await;
+            });
+        });
+    });
+
+    let recorded = recorded(snapshotter.snapshot());
+    let requests: u64 = recorded
+        .iter()
+        .filter(|metric| metric.name == "http.server.request.duration")
+        .map(Recorded::count)
+        .sum();
+    assert_eq!(requests, 10);
+    assert!(recorded
+        .iter()
+        .filter(|metric| matches!(metric.value, DebugValue::Gauge(_)))
+        .all(|metric| metric.sum() == 0.0));
+}
+
+#[actix_web::test]
+async fn middleware_bound_recorder() {
+    let bound_recorder = Arc::new(DebuggingRecorder::new());
+    let bound_snapshotter = bound_recorder.snapshotter();
+
+    let default_recorder = DebuggingRecorder::new();
+    let default_snapshotter = default_recorder.snapshotter();
+    let _guard = set_default_local_recorder(&default_recorder);
+
+    let metrics = ActixWebMetricsBuilder::new()
+        .recorder(bound_recorder)
+        .build();
+
+    let app = init_service(
+        App::new()
+            .wrap(metrics)
+            .service(web::resource("/health_check").to(|| async {
+                counter!("business_counter").increment(1);
+                HttpResponse::Ok().finish()
+            })),
+    )
+    .await;
+
+    let res = call_service(&app, TestRequest::with_uri("/health_check").to_request()).await;
+    assert!(res.status().is_success());
+    assert_eq!(read_body(res).await, "");
+
+    let names = |snapshot: Snapshot| {
+        snapshot
+            .into_vec()
+            .into_iter()
+            .map(|(key, unit, _, _)| (key.key().name().to_string(), unit))
+            .collect::<BTreeMap<_, _>>()
+    };
+    assert_eq!(
+        names(bound_snapshotter.snapshot()),
+        BTreeMap::from([
+            ("http.server.active_requests".to_string(), None),
+            (
+                "http.server.request.body.size".to_string(),
+                Some(Unit::Bytes)
+            ),
+            (
+                "http.server.request.duration".to_string(),
+                Some(Unit::Seconds)
+            ),
+            (
+                "http.server.response.body.size".to_string(),
+                Some(Unit::Bytes)
+            ),
+        ])
+    );
+    assert_eq!(
+        names(default_snapshotter.snapshot()),
+        BTreeMap::from([("business_counter".to_string(), None)])
+    );
+}
+
+#[test]
+fn try_build_rejects_invalid_exclude_regex() {
+    let err = ActixWebMetricsBuilder::new()
+        .exclude_regex("/readyz/.*")
+        .exclude_regex("/healthz/(")
+        .try_build()
+        .unwrap_err();
+    assert!(
+        matches!(&err, ConfigError::InvalidExcludeRegex { pattern, .. } if pattern == "/healthz/(")
+    );
+    assert!(std::error::Error::source(&err).is_some());
+}
+
+#[test]
+fn try_build_rejects_invalid_namespace() {
+    let err = ActixWebMetricsBuilder::new()
+        .namespace("my-app")
+        .try_build()
+        .unwrap_err();
+    assert!(matches!(&err, ConfigError::InvalidNamespace(namespace) if namespace == "my-app"));
+}
+
+#[test]
+fn try_build_rejects_invalid_metric_name() {
+    let err = ActixWebMetricsBuilder::new()
+        .metrics_config(ActixWebMetricsConfig::default().http_server_request_body_size_name(""))
+        .try_build()
+        .unwrap_err();
+    assert!(matches!(
+        &err,
+        ConfigError::InvalidMetricName { metric: "http.server.request.body.size", name } if name.is_empty()
+    ));
+
+    let err = ActixWebMetricsBuilder::new()
+        .metrics_config(
+            ActixWebMetricsConfig::default().http_server_request_duration_name("1st_duration"),
+        )
+        .try_build()
+        .unwrap_err();
+    assert!(matches!(
+        &err,
+        ConfigError::InvalidMetricName {
+            metric: "http.server.request.duration",
+            ..
+        }
+    ));
+}
+
+#[test]
+fn try_build_rejects_duplicate_metric_name() {
+    let err = ActixWebMetricsBuilder::new()
+        .metrics_config(
+            ActixWebMetricsConfig::default()
+                .http_server_request_body_size_name("http.server.response.body.size"),
+        )
+        .try_build()
+        .unwrap_err();
+    assert!(
+        matches!(&err, ConfigError::DuplicateMetricName(name) if name == "http.server.response.body.size")
+    );
+}
+
+#[test]
+fn try_build_rejects_invalid_label_name() {
+    let err = ActixWebMetricsBuilder::new()
+        .metrics_config(
+            ActixWebMetricsConfig::default().labels(LabelsConfig::default().http_route("__route")),
+        )
+        .try_build()
+        .unwrap_err();
+    assert!(matches!(
+        &err,
+        ConfigError::InvalidLabelName { label: "http.route", name } if name == "__route"
+    ));
+}
+
+#[test]
+fn try_build_rejects_duplicate_label_name() {
+    let err = ActixWebMetricsBuilder::new()
+        .metrics_config(
+            ActixWebMetricsConfig::default()
+                .labels(LabelsConfig::default().url_scheme("http.route")),
+        )
+        .try_build()
+        .unwrap_err();
+    assert!(matches!(&err, ConfigError::DuplicateLabelName(name) if name == "http.route"));
+}
+
+#[test]
+fn try_build_rejects_invalid_const_label_name() {
+    let err = ActixWebMetricsBuilder::new()
+        .const_labels(HashMap::from([(
+            "my label".to_string(),
+            "value".to_string(),
+        )]))
+        .try_build()
+        .unwrap_err();
+    assert!(matches!(&err, ConfigError::InvalidConstLabelName(name) if name == "my label"));
+}
+
+#[test]
+fn try_build_rejects_const_label_collision() {
+    let err = ActixWebMetricsBuilder::new()
+        .const_labels(HashMap::from([(
+            "http.route".to_string(),
+            "value".to_string(),
+        )]))
+        .try_build()
+        .unwrap_err();
+    assert!(matches!(&err, ConfigError::ConstLabelCollision(name) if name == "http.route"));
+}
+
+#[test]
+fn try_build_rejects_empty_unmatched_patterns_mask() {
+    let err = ActixWebMetricsBuilder::new()
+        .mask_unmatched_patterns("")
+        .try_build()
+        .unwrap_err();
+    assert!(matches!(err, ConfigError::EmptyUnmatchedPatternsMask));
+}
+
+#[test]
+fn try_build_rejects_zero_flush_interval() {
+    let err = ActixWebMetricsBuilder::new()
+        .local_aggregation(LocalAggregationConfig::default().flush_interval(Duration::ZERO))
+        .try_build()
+        .unwrap_err();
+    assert!(matches!(err, ConfigError::ZeroFlushInterval));
+}
+
+#[test]
+fn try_build_accepts_valid_configuration() {
+    assert!(ActixWebMetricsBuilder::new()
+        .namespace("my_app")
+        .exclude_regex("/readyz/.*")
+        .const_labels(HashMap::from([("service".to_string(), "api".to_string())]))
+        .try_build()
+        .is_ok());
+}
+
+#[test]
+#[should_panic(expected = "invalid actix-web-metrics configuration: invalid exclude regex `(`")]
+fn build_panics_on_invalid_configuration() {
+    let _ = ActixWebMetricsBuilder::new().exclude_regex("(").build();
+}
+
+#[test]
+#[should_panic(expected = "invalid actix-web-metrics configuration: invalid namespace `my-app`")]
+fn build_panics_on_invalid_namespace() {
+    let _ = ActixWebMetricsBuilder::new().namespace("my-app").build();
+}
+
+#[actix_web::test]
+async fn middleware_handle_reconfigures_rules() {
+    let recorder = Arc::new(DebuggingRecorder::new());
+    let snapshotter = recorder.snapshotter();
+
+    let metrics = ActixWebMetricsBuilder::new()
+        .exclude("/ping")
+        .recorder(recorder)
+        .build();
+    let handle = metrics.handle();
+
+    let app = init_service(
+        App::new()
+            .wrap(metrics)
+            .service(web::resource("/health_check").to(HttpResponse::Ok))
+            .service(web::resource("/ping").to(HttpResponse::Ok))
+            .service(web::resource("/readyz/{subsystem}").to(HttpResponse::Ok)),
+    )
+    .await;
+
+    let call_all = || async {
+        for uri in ["/health_check", "/ping", "/readyz/database", "/missing"] {
+            let res = call_service(&app, TestRequest::with_uri(uri).to_request()).await;
+            read_body(res).await;
+        }
+    };
+
+    call_all().await;
+    assert_eq!(
+        recorded_requests(snapshotter.snapshot(), |metric| metric.label("http.route")),
+        BTreeMap::from([
+            ("/health_check".to_string(), 1),
+            ("/readyz/{subsystem}".to_string(), 1),
+            ("UNKNOWN".to_string(), 1),
+        ])
+    );
+
+    handle.set_exclude(["/health_check"]);
+    handle.set_exclude_regex(["^/readyz/.*"]).unwrap();
+    handle
+        .set_unmatched_patterns_mask(Some("UNMATCHED"))
+        .unwrap();
+    call_all().await;
+    assert_eq!(
+        recorded_requests(snapshotter.snapshot(), |metric| metric.label("http.route")),
+        BTreeMap::from([("/ping".to_string(), 1), ("UNMATCHED".to_string(), 1),])
+    );
+
+    handle.set_exclude(Vec::<String>::new());
+    handle.set_exclude_regex(Vec::<String>::new()).unwrap();
+    handle.set_exclude_status([StatusCode::NOT_FOUND]);
+    handle.set_unmatched_patterns_mask(None::<String>).unwrap();
+    call_all().await;
+    assert_eq!(
+        recorded_requests(snapshotter.snapshot(), |metric| metric.label("http.route")),
+        BTreeMap::from([
+            ("/health_check".to_string(), 1),
+            ("/ping".to_string(), 1),
+            ("/readyz/{subsystem}".to_string(), 1),
+        ])
+    );
+
+    assert!(matches!(
+        handle.set_exclude_regex(["("]),
+        Err(ConfigError::InvalidExcludeRegex { .. })
+    ));
+    assert!(matches!(
+        handle.set_unmatched_patterns_mask(Some("")),
+        Err(ConfigError::EmptyUnmatchedPatternsMask)
+    ));
+}
+
+#[actix_web::test]
+async fn middleware_handle_in_flight_requests_keep_rules() {
+    let recorder = Arc::new(DebuggingRecorder::new());
+    let snapshotter = recorder.snapshotter();
+
+    let metrics = ActixWebMetricsBuilder::new().recorder(recorder).build();
+    let handle = metrics.handle();
+
+    let app = init_service(
+        App::new()
+            .wrap(metrics)
+            .service(web::resource("/health_check").to(HttpResponse::Ok)),
+    )
+    .await;
+
+    // both requests have started when the rules change
+    let matched = app.call(TestRequest::with_uri("/health_check").to_request());
+    let unmatched = app.call(TestRequest::with_uri("/missing").to_request());
+
+    handle.set_exclude(["/health_check"]);
+    handle.set_exclude_status([StatusCode::NOT_FOUND]);
+    handle
+        .set_unmatched_patterns_mask(Some("UNMATCHED"))
+        .unwrap();
+
+    read_body(matched.await.unwrap()).await;
+    read_body(unmatched.await.unwrap()).await;
+
+    // a request started afterwards sees the new rules
+    let res = call_service(&app, TestRequest::with_uri("/health_check").to_request()).await;
+    read_body(res).await;
+
+    assert_eq!(
+        recorded_requests(snapshotter.snapshot(), |metric| metric.label("http.route")),
+        BTreeMap::from([("/health_check".to_string(), 1), ("UNKNOWN".to_string(), 1),])
+    );
+}
+
+#[actix_web::test]
+async fn middleware_handle_concurrent_updates() {
+    let recorder = Arc::new(DebuggingRecorder::new());
+    let snapshotter = recorder.snapshotter();
+
+    let metrics = ActixWebMetricsBuilder::new().recorder(recorder).build();
+    let handle = metrics.handle();
+
+    let app = init_service(
+        App::new()
+            .wrap(metrics)
+            .service(web::resource("/health_check").to(HttpResponse::Ok)),
+    )
+    .await;
+
+    let writers = [("A", "^/a$"), ("B", "^/b$")].map(|(mask, pattern)| {
+        let handle = handle.clone();
+        std::thread::spawn(move || {
+            for _ in 0..1_000 {
+                handle.set_unmatched_patterns_mask(Some(mask)).unwrap();
+                handle.set_exclude_regex([pattern]).unwrap();
+            }
+        })
+    });
+
+    for _ in 0..200 {
+        let res = call_service(&app, TestRequest::with_uri("/missing").to_request()).await;
+        read_body(res).await;
+    }
+    for writer in writers {
+        writer.join().unwrap();
+    }
+
+    let routes = recorded_requests(snapshotter.snapshot(), |metric| metric.label("http.route"));
+    assert!(routes
+        .keys()
+        .all(|route| ["UNKNOWN", "A", "B"].contains(&route.as_str())));
+    assert_eq!(routes.values().sum::<u64>(), 200);
+
+    handle.set_unmatched_patterns_mask(Some("C")).unwrap();
+    let res = call_service(&app, TestRequest::with_uri("/missing").to_request()).await;
+    read_body(res).await;
+    assert_eq!(
+        recorded_requests(snapshotter.snapshot(), |metric| metric.label("http.route")).get("C"),
+        Some(&1)
+    );
+}
+
+#[actix_web::test]
+async fn middleware_exclude_request_if() {
+    let recorder = Arc::new(DebuggingRecorder::new());
+    let snapshotter = recorder.snapshotter();
+
+    let metrics = ActixWebMetricsBuilder::new()
+        .exclude_request_if(|req| req.headers().contains_key("x-synthetic"))
+        .exclude_methods([Method::OPTIONS, Method::HEAD])
+        .exclude_user_agents(["kube-probe/", "ELB-HealthChecker/"])
+        .recorder(recorder)
+        .build();
+
+    let app = init_service(
+        App::new()
+            .wrap(metrics)
+            .service(web::resource("/health_check").to(HttpResponse::Ok)),
+    )
+    .await;
+
+    let excluded = [
+        TestRequest::with_uri("/health_check").insert_header(("x-synthetic", "1")),
+        TestRequest::with_uri("/health_check").method(Method::OPTIONS),
+        TestRequest::with_uri("/health_check").method(Method::HEAD),
+        TestRequest::with_uri("/health_check").insert_header(("user-agent", "kube-probe/1.29")),
+        TestRequest::with_uri("/missing").insert_header(("user-agent", "ELB-HealthChecker/2.0")),
+    ];
+    for req in excluded {
+        let res = call_service(&app, req.to_request()).await;
+        read_body(res).await;
+    }
+
+    // excluded requests don't touch any metric, not even the active requests gauge
+    assert!(snapshotter.snapshot().into_vec().is_empty());
+
+    let res = call_service(
+        &app,
+        TestRequest::with_uri("/health_check")
+            .insert_header(("user-agent", "curl/8.5.0"))
+            .to_request(),
+    )
+    .await;
+    read_body(res).await;
+
+    assert_eq!(
+        recorded_requests(snapshotter.snapshot(), |metric| metric.label("http.route")),
+        BTreeMap::from([("/health_check".to_string(), 1)])
+    );
+}
+
+#[actix_web::test]
+async fn middleware_exclude_response_if() {
+    let recorder = Arc::new(DebuggingRecorder::new());
+    let snapshotter = recorder.snapshotter();
+
+    let metrics = ActixWebMetricsBuilder::new()
+        .exclude_response_if(|_, res| res.headers().contains_key("x-cache-hit"))
+        .exclude_response_if(|_, res| res.status == StatusCode::NO_CONTENT)
+        .recorder(recorder)
+        .build();
+
+    let app = init_service(
+        App::new()
+            .wrap(metrics)
+            .service(web::resource("/cached").to(|| async {
+                HttpResponse::Ok()
+                    .insert_header(("x-cache-hit", "1"))
+                    .body("cached response")
+            }))
+            .service(web::resource("/empty").to(HttpResponse::NoContent))
+            .service(
+                web::resource("/health_check")
+                    .to(|| async { HttpResponse::Ok().body("test response") }),
+            ),
+    )
+    .await;
+
+    let res = call_service(&app, TestRequest::with_uri("/cached").to_request()).await;
+    assert_eq!(read_body(res).await, "cached response");
+    let res = call_service(&app, TestRequest::with_uri("/empty").to_request()).await;
+    assert_eq!(read_body(res).await, "");
+    let res = call_service(&app, TestRequest::with_uri("/health_check").to_request()).await;
+    assert_eq!(read_body(res).await, "test response");
+
+    let snapshot = snapshotter.snapshot().into_vec();
+    // excluded responses still leave the active requests gauge
+    let active_requests = snapshot
+        .iter()
+        .filter(|(key, ..)| key.key().name() == "http.server.active_requests")
+        .map(|(.., value)| value)
+        .collect::<Vec<_>>();
+    assert_eq!(active_requests, vec![&DebugValue::Gauge(0.0.into())]);
+
+    let mut recorded = snapshot
+        .into_iter()
+        .filter_map(|(key, _, _, value)| match value {
+            DebugValue::Histogram(values) if !values.is_empty() => {
+                Some(key.key().name().to_string())
+            }
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    recorded.sort();
+    assert_eq!(
+        recorded,
+        vec![
+            "http.server.request.body.size",
+            "http.server.request.duration",
+            "http.server.response.body.size",
+        ]
+    );
+}
+
+/// Counter values and histogram sample counts per metric name and route.
+async fn sampled_totals(sampling: SamplingConfig) -> BTreeMap<(String, String), u64> {
+    let recorder = Arc::new(DebuggingRecorder::new());
+    let snapshotter = recorder.snapshotter();
+
+    let metrics = ActixWebMetricsBuilder::new()
+        .sampling(sampling)
+        .recorder(recorder)
+        .build();
+    let app = init_service(mixed_requests_app().wrap(metrics)).await;
+
+    for id in 0..1_000 {
+        for uri in ["/health_check".to_string(), format!("/resource/{id}")] {
+            let res = call_service(&app, TestRequest::with_uri(&uri).to_request()).await;
+            read_body(res).await;
+        }
+    }
+
+    recorded(snapshotter.snapshot())
+        .into_iter()
+        .filter_map(|metric| {
+            Some((
+                (metric.name.clone(), metric.label("http.route")?),
+                metric.count(),
+            ))
+        })
+        .collect()
+}
+
+#[actix_web::test]
+async fn middleware_sampling() {
+    let sampling = SamplingConfig::default()
+        .rate(0.25)
+        .route("/health_check", 0.0)
+        .seed(42);
+    let totals = sampled_totals(sampling.clone()).await;
+
+    let total = |name: &str, route: &str| totals[&(name.to_string(), route.to_string())];
+    // the request counter is exact
+    assert_eq!(total("http.server.requests", "/health_check"), 1_000);
+    assert_eq!(total("http.server.requests", "/resource/{id}"), 1_000);
+    // histograms are only recorded for sampled requests
+    assert_eq!(total("http.server.request.duration", "/health_check"), 0);
+    let sampled = total("http.server.request.duration", "/resource/{id}");
+    assert!((200..300).contains(&sampled), "sampled {sampled} requests");
+    assert_eq!(
+        total("http.server.request.body.size", "/resource/{id}"),
+        sampled
+    );
+    assert_eq!(
+        total("http.server.response.body.size", "/resource/{id}"),
+        sampled
+    );
+
+    // the same seed samples the same requests
+    assert_eq!(sampled_totals(sampling).await, totals);
+}
+
+#[actix_web::test]
+async fn middleware_sampling_with_local_aggregation() {
+    let recorder = Arc::new(DebuggingRecorder::new());
+    let snapshotter = recorder.snapshotter();
+
+    let metrics = ActixWebMetricsBuilder::new()
+        .sampling(SamplingConfig::default().rate(0.0))
+        .local_aggregation(LocalAggregationConfig::default())
+        .recorder(recorder)
+        .build();
+    let app = init_service(mixed_requests_app().wrap(metrics.clone())).await;
+
+    for _ in 0..10 {
+        let res = call_service(&app, TestRequest::with_uri("/health_check").to_request()).await;
+        read_body(res).await;
+    }
+    metrics.flush_local_aggregate();
+
+    let totals = recorded(snapshotter.snapshot());
+    let count = |name: &str| {
+        totals
+            .iter()
+            .find(|metric| {
+                metric.name == name
+                    && metric.label("http.route").as_deref() == Some("/health_check")
+            })
+            .map(Recorded::count)
+    };
+    assert_eq!(count("http.server.requests"), Some(10));
+    assert_eq!(count("http.server.request.duration"), Some(0));
+}
+
+#[test]
+fn sampling_validates_rates() {
+    let err = ActixWebMetricsBuilder::new()
+        .sampling(SamplingConfig::default().rate(1.5))
+        .try_build()
+        .unwrap_err();
+    assert!(matches!(
+        err,
+        ConfigError::InvalidSampleRate { route: None, .. }
+    ));
+    assert_eq!(err.to_string(), "invalid sample rate `1.5`");
+
+    let err = ActixWebMetricsBuilder::new()
+        .sampling(SamplingConfig::default().route("/health_check", -0.1))
+        .try_build()
+        .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "invalid sample rate `-0.1` for route `/health_check`"
+    );
+}
+
+#[actix_web::test]
+async fn middleware_request_counter() {
+    let recorder = Arc::new(DebuggingRecorder::new());
+    let snapshotter = recorder.snapshotter();
+
+    let metrics = ActixWebMetricsBuilder::new()
+        .request_counter(true)
+        .metrics_config(ActixWebMetricsConfig::default().http_server_requests_name("requests"))
+        .namespace("app")
+        .exclude("/ping")
+        .exclude_status(StatusCode::NOT_FOUND)
+        .exclude_methods([Method::OPTIONS])
+        .recorder(recorder)
+        .build();
+
+    let app = init_service(
+        mixed_requests_app()
+            .wrap(metrics)
+            .service(web::resource("/ping").to(HttpResponse::Ok)),
+    )
+    .await;
+
+    let requests = [
+        TestRequest::with_uri("/health_check"),
+        TestRequest::with_uri("/health_check"),
+        TestRequest::with_uri("/resource/1"),
+        TestRequest::with_uri("/missing"),
+        TestRequest::with_uri("/ping"),
+        TestRequest::with_uri("/health_check").method(Method::OPTIONS),
+    ];
+    for req in requests {
+        let res = call_service(&app, req.to_request()).await;
+        read_body(res).await;
+    }
+
+    let counters = recorded(snapshotter.snapshot())
+        .into_iter()
+        .filter(|metric| metric.name == "app_requests")
+        .filter_map(|metric| Some((metric.label("http.route")?, metric.count())))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        counters,
+        vec![
+            ("/health_check".to_string(), 2),
+            ("/resource/{id}".to_string(), 1)
+        ]
+    );
+}
+
+#[test]
+fn request_counter_name_is_validated() {
+    let err = ActixWebMetricsBuilder::new()
+        .request_counter(true)
+        .metrics_config(
+            ActixWebMetricsConfig::default()
+                .http_server_requests_name("http.server.request.duration"),
+        )
+        .try_build()
+        .unwrap_err();
+    assert!(matches!(err, ConfigError::DuplicateMetricName(_)));
+
+    // the name is only used, and validated, if the counter is enabled
+    assert!(ActixWebMetricsBuilder::new()
+        .metrics_config(ActixWebMetricsConfig::default().http_server_requests_name(""))
+        .try_build()
+        .is_ok());
+}
+
+#[actix_web::test]
+async fn middleware_resource_names() {
+    let recorder = Arc::new(DebuggingRecorder::new());
+    let snapshotter = recorder.snapshotter();
+
+    let metrics = ActixWebMetricsBuilder::new()
+        .use_resource_names(true)
+        .exclude("internal_status")
+        .recorder(recorder)
+        .build();
+
+    let app = init_service(
+        App::new()
+            .wrap(metrics)
+            .service(
+                web::resource("/api/{tenant}/orders/{order_id}/items/{item_id}")
+                    .name("order_item")
+                    .to(HttpResponse::Ok),
+            )
+            .service(
+                web::resource("/internal/status")
+                    .name("internal_status")
+                    .to(HttpResponse::Ok),
+            )
+            .service(web::resource("/health_check").to(HttpResponse::Ok)),
+    )
+    .await;
+
+    for uri in [
+        "/api/acme/orders/1/items/2",
+        "/api/acme/orders/1/items/3",
+        "/internal/status",
+        "/health_check",
+        "/missing",
+    ] {
+        let res = call_service(&app, TestRequest::with_uri(uri).to_request()).await;
+        read_body(res).await;
+    }
+
+    assert_eq!(
+        recorded_requests(snapshotter.snapshot(), |metric| metric.label("http.route")),
+        BTreeMap::from([
+            ("/health_check".to_string(), 1),
+            ("UNKNOWN".to_string(), 1),
+            ("order_item".to_string(), 2),
+        ])
+    );
+}
+
+#[actix_web::test]
+async fn middleware_route_normalizer() {
+    let recorder = Arc::new(DebuggingRecorder::new());
+    let snapshotter = recorder.snapshotter();
+
+    let metrics = ActixWebMetricsBuilder::new()
+        .route_normalizer(|route: &str| {
+            let rest = route
+                .strip_prefix("/v1/")
+                .or_else(|| route.strip_prefix("/v2/"))?;
+            Some(format!("/{{version}}/{rest}"))
+        })
+        .recorder(recorder)
+        .build();
+
+    let app = init_service(
+        App::new()
+            .wrap(metrics)
+            .service(web::resource("/v1/users/{id}").to(HttpResponse::Ok))
+            .service(web::resource("/v2/users/{id}").to(HttpResponse::Ok))
+            .service(web::resource("/health_check").to(HttpResponse::Ok)),
+    )
+    .await;
+
+    for uri in [
+        "/v1/users/1",
+        "/v2/users/2",
+        "/health_check",
+        // unmatched requests are masked, not normalized
+        "/v1/missing",
+    ] {
+        let res = call_service(&app, TestRequest::with_uri(uri).to_request()).await;
+        read_body(res).await;
+    }
+
+    assert_eq!(
+        recorded_requests(snapshotter.snapshot(), |metric| metric.label("http.route")),
+        BTreeMap::from([
+            ("/health_check".to_string(), 1),
+            ("/{version}/users/{id}".to_string(), 2),
+            ("UNKNOWN".to_string(), 1),
+        ])
+    );
+}
+
+#[actix_web::test]
+async fn middleware_normalize_unmatched_paths() {
+    let recorder = Arc::new(DebuggingRecorder::new());
+    let snapshotter = recorder.snapshotter();
+
+    let metrics = ActixWebMetricsBuilder::new()
+        .normalize_unmatched_paths(
+            UnmatchedPathsConfig::default()
+                .rule("v[0-9]+", "{version}")
+                .max_distinct(6),
+        )
+        .mask_unmatched_patterns("OVERFLOW")
+        .recorder(recorder)
+        .build();
+
+    let app = init_service(
+        App::new()
+            .wrap(metrics)
+            .service(web::resource("/health_check").to(HttpResponse::Ok)),
+    )
+    .await;
+
+    for uri in [
+        "/health_check",
+        "/users/42/avatar",
+        "/users/43/avatar",
+        "/orders/0b6f5c3e-2a4d-4e8f-9c1b-7d3e5f6a8b9c",
+        "/blobs/d41d8cd98f00b204e9800998ecf8427e",
+        "/api/v2/search",
+        "/pages/this-is-a-very-long-slug-for-a-blog-post",
+        "/a/b/c/d/e/f",
+        // the distinct paths limit is reached
+        "/other",
+        "/users/44/avatar",
+    ] {
+        let res = call_service(&app, TestRequest::with_uri(uri).to_request()).await;
+        read_body(res).await;
+    }
+
+    assert_eq!(
+        recorded_requests(snapshotter.snapshot(), |metric| metric.label("http.route")),
+        BTreeMap::from([
+            ("/a/b/c/d/...".to_string(), 1),
+            ("/api/{version}/search".to_string(), 1),
+            ("/blobs/{hash}".to_string(), 1),
+            ("/health_check".to_string(), 1),
+            ("/orders/{uuid}".to_string(), 1),
+            ("/pages/{segment}".to_string(), 1),
+            ("/users/{id}/avatar".to_string(), 3),
+            ("OVERFLOW".to_string(), 1),
+        ])
+    );
+}
+
+#[test]
+fn normalization_rules_are_validated() {
+    let err = ActixWebMetricsBuilder::new()
+        .normalize_unmatched_paths(UnmatchedPathsConfig::default().rule("(", "{x}"))
+        .try_build()
+        .unwrap_err();
+    assert!(matches!(
+        &err,
+        ConfigError::InvalidNormalizationRule { pattern, .. } if pattern == "("
+    ));
+    assert!(std::error::Error::source(&err).is_some());
+}
+
+fn users_scope(path: &str) -> Scope {
+    web::scope(path)
+        .service(web::resource("/users/{id}").to(HttpResponse::Ok))
+        .service(web::resource("").to(HttpResponse::Ok))
+}
+
+#[actix_web::test]
+async fn middleware_scope_label_wrapping_scopes() {
+    let recorder = Arc::new(DebuggingRecorder::new());
+    let snapshotter = recorder.snapshotter();
+
+    let metrics = ActixWebMetricsBuilder::new()
+        .scope_label(true)
+        .recorder(recorder)
+        .build();
+
+    let app = init_service(
+        App::new()
+            .service(users_scope("/api/v1").wrap(metrics.clone()))
+            .service(users_scope("/internal").wrap(metrics.clone()))
+            .service(users_scope("/tenants/{tenant}").wrap(metrics)),
+    )
+    .await;
+
+    for uri in [
+        "/api/v1/users/1",
+        "/api/v1/users/2",
+        "/api/v1",
+        "/internal/users/3",
+        "/tenants/acme/users/4",
+        "/api/v1/missing",
+    ] {
+        let res = call_service(&app, TestRequest::with_uri(uri).to_request()).await;
+        read_body(res).await;
+    }
+
+    let labels = |scope: &str, route: &str| (scope.to_string(), route.to_string());
+    let scope_and_route =
+        |metric: &Recorded| Some((metric.label("http.scope")?, metric.label("http.route")?));
+    assert_eq!(
+        recorded_requests(snapshotter.snapshot(), scope_and_route),
+        BTreeMap::from([
+            (labels("", "UNKNOWN"), 1),
+            (labels("/api/v1", "/"), 1),
+            (labels("/api/v1", "/users/{id}"), 2),
+            (labels("/internal", "/users/{id}"), 1),
+            (labels("/tenants/{tenant}", "/users/{id}"), 1),
+        ])
+    );
+}
+
+#[actix_web::test]
+async fn middleware_scope_label_wrapping_app() {
+    let recorder = Arc::new(DebuggingRecorder::new());
+    let snapshotter = recorder.snapshotter();
+
+    let metrics = ActixWebMetricsBuilder::new()
+        .scope_prefixes(["/api", "/api/v1/", "/tenants/{tenant}"])
+        .metrics_config(
+            ActixWebMetricsConfig::default().labels(LabelsConfig::default().http_scope("scope")),
+        )
+        .recorder(recorder)
+        .build();
+
+    let app = init_service(
+        App::new()
+            .wrap(metrics)
+            .service(users_scope("/api/v1"))
+            .service(users_scope("/api/v10"))
+            .service(users_scope("/tenants/{tenant}"))
+            .service(web::resource("/health_check").to(HttpResponse::Ok)),
+    )
+    .await;
+
+    for uri in [
+        "/api/v1/users/1",
+        "/api/v10/users/2",
+        "/tenants/acme/users/3",
+        "/health_check",
+    ] {
+        let res = call_service(&app, TestRequest::with_uri(uri).to_request()).await;
+        read_body(res).await;
+    }
+
+    let scopes = snapshotter
+        .snapshot()
+        .into_vec()
+        .into_iter()
+        .filter(|(key, ..)| key.key().name() == "http.server.request.duration")
+        .map(|(key, ..)| {
+            let label = |name: &str| {
+                key.key()
+                    .labels()
+                    .find(|label| label.key() == name)
+                    .map(|label| label.value().to_string())
+                    .unwrap()
+            };
+            (label("scope"), label("http.route"))
+        })
+        .collect::<BTreeMap<_, _>>();
+
+    assert_eq!(
+        scopes,
+        BTreeMap::from([
+            ("".to_string(), "/health_check".to_string()),
+            ("/api".to_string(), "/v10/users/{id}".to_string()),
+            ("/api/v1".to_string(), "/users/{id}".to_string()),
+            ("/tenants/{tenant}".to_string(), "/users/{id}".to_string()),
+        ])
+    );
+}
+
+/// Access log config writing into the returned lines.
+fn captured_access_log(config: AccessLogConfig) -> (AccessLogConfig, Arc<Mutex<Vec<String>>>) {
+    let lines = Arc::new(Mutex::new(Vec::new()));
+    let sink = lines.clone();
+    let config = config.sink(move |line: &str| sink.lock().unwrap().push(line.to_string()));
+    (config, lines)
+}
+
+#[actix_web::test]
+async fn middleware_access_log() {
+    let (config, lines) = captured_access_log(AccessLogConfig::format(
+        "{method} {scope}{route} {status} {outcome} {request_size} {response_size} {{}}",
+    ));
+    let metrics = ActixWebMetricsBuilder::new()
+        .exclude("/api/health_check")
+        .scope_label(true)
+        .access_log(config)
+        .build();
+
+    let app = init_service(
+        App::new().service(
+            web::scope("/api")
+                .wrap(metrics)
+                .service(
+                    web::resource("/resource/{id}")
+                        .to(|body: String| async move { HttpResponse::Created().body(body) }),
+                )
+                .service(web::resource("/health_check").to(HttpResponse::Ok)),
+        ),
+    )
+    .await;
+
+    for req in [
+        TestRequest::post()
+            .uri("/api/resource/123")
+            .insert_header(("content-length", "7"))
+            .set_payload("payload"),
+        TestRequest::get().uri("/api/health_check"),
+        TestRequest::get().uri("/api/missing"),
+    ] {
+        let res = call_service(&app, req.to_request()).await;
+        read_body(res).await;
+    }
+    // the body is dropped without being sent
+    let res = call_service(
+        &app,
+        TestRequest::post()
+            .uri("/api/resource/1")
+            .set_payload("payload")
+            .to_request(),
+    )
+    .await;
+    drop(res);
+
+    assert_eq!(
+        *lines.lock().unwrap(),
+        [
+            "POST /api/resource/{id} 201 completed 7 7 {}",
+            "GET UNKNOWN 404 completed 0 0 {}",
+            "POST /api/resource/{id} 201 aborted 0 0 {}",
+        ]
+    );
+}
+
+#[actix_web::test]
+async fn middleware_access_log_json() {
+    let (config, lines) = captured_access_log(AccessLogConfig::json());
+    let metrics = ActixWebMetricsBuilder::new()
+        .route_normalizer(|route: &str| Some(route.replace("/v1", "/{version}")))
+        .access_log(config)
+        .build();
+
+    let app = init_service(App::new().wrap(metrics).service(
+        web::resource("/v1/resource/{id}").to(|| async {
+            actix_web::rt::time::sleep(Duration::from_millis(10)).await;
+            HttpResponse::InternalServerError().body("error")
+        }),
+    ))
+    .await;
+
+    let res = call_service(&app, TestRequest::with_uri("/v1/resource/1").to_request()).await;
+    read_body(res).await;
+
+    let lines = lines.lock().unwrap();
+    assert_eq!(lines.len(), 1);
+    let mut line: serde_json::Value = serde_json::from_str(&lines[0]).unwrap();
+    let duration = line["duration"].take().as_f64().unwrap();
+    let handler_duration = line["handler_duration"].take().as_f64().unwrap();
+    assert!(handler_duration >= 0.01 && duration >= handler_duration);
+    assert_eq!(
+        line,
+        serde_json::json!({
+            "method": "GET",
+            "route": "/{version}/resource/{id}",
+            "status": 500,
+            "outcome": "completed",
+            "duration": null,
+            "handler_duration": null,
+            "request_size": 0,
+            "response_size": 5,
+        })
+    );
+}
+
+#[actix_web::test]
+async fn middleware_access_log_failed_request() {
+    let (config, lines) = captured_access_log(AccessLogConfig::format(
+        "{method} [{route}] {status} {outcome} {response_size}",
+    ));
+    let metrics = ActixWebMetricsBuilder::new().access_log(config).build();
+
+    let app = init_service(
+        App::new()
+            .wrap_fn(|req, srv| {
+                let denied = req.path() == "/denied";
+                let res = srv.call(req);
+                async move {
+                    if denied {
+                        return Err(actix_web::error::ErrorForbidden("denied"));
+                    }
+                    res.await
+                }
+            })
+            .wrap(metrics)
+            .service(web::resource("/denied").to(HttpResponse::Ok)),
+    )
+    .await;
+
+    app.call(TestRequest::with_uri("/denied").to_request())
+        .await
+        .unwrap_err();
+
+    assert_eq!(*lines.lock().unwrap(), ["GET [] 403 error 0"]);
+}
+
+#[test]
+fn access_log_format_is_validated() {
+    let err = ActixWebMetricsBuilder::new()
+        .access_log(AccessLogConfig::format("{method} {path}"))
+        .try_build()
+        .unwrap_err();
+    assert!(matches!(
+        &err,
+        ConfigError::InvalidAccessLogFormat { format, reason }
+            if format == "{method} {path}" && reason == "unknown field `path`"
+    ));
+    assert_eq!(
+        err.to_string(),
+        "invalid access log format `{method} {path}`: unknown field `path`"
+    );
+}
+
+#[derive(Default)]
+struct RecordingObserver {
+    events: Mutex<Vec<String>>,
+}
+
+impl MetricsObserver for RecordingObserver {
+    fn on_request_start(&self, req: &ServiceRequest) {
+        self.events
+            .lock()
+            .unwrap()
+            .push(format!("start {} {}", req.method(), req.path()));
+    }
+
+    fn on_request_end(&self, outcome: &RequestOutcome<'_>) {
+        assert!(outcome.duration >= outcome.handler_duration);
+        if outcome.failed {
+            self.events.lock().unwrap().push(format!(
+                "failed {} {}",
+                outcome.method,
+                outcome.status.as_u16()
+            ));
+            return;
+        }
+        self.events.lock().unwrap().push(format!(
+            "end {} {} {} {} {:?} {} {} matched={} completed={} excluded={}",
+            outcome.method,
+            outcome.route,
+            outcome.status.as_u16(),
+            outcome.scheme,
+            outcome.version,
+            outcome.request_size,
+            outcome.response_size,
+            outcome.matched,
+            outcome.completed,
+            outcome.excluded,
+        ));
+    }
+}
+
+#[actix_web::test]
+async fn middleware_observer() {
+    let observer = Arc::new(RecordingObserver::default());
+    let metrics = ActixWebMetricsBuilder::new()
+        .exclude_status(StatusCode::CONFLICT)
+        .exclude_request_if(|req| req.path() == "/ignored")
+        .observer(observer.clone())
+        .build();
+
+    let app = init_service(
+        App::new()
+            .wrap(metrics)
+            .service(
+                web::resource("/resource/{id}")
+                    .to(|body: String| async move { HttpResponse::Created().body(body) }),
+            )
+            .service(web::resource("/empty").to(HttpResponse::NoContent))
+            .service(web::resource("/conflict").to(HttpResponse::Conflict))
+            .service(web::resource("/ignored").to(HttpResponse::Ok)),
+    )
+    .await;
+
+    let res = call_service(
+        &app,
+        TestRequest::post()
+            .uri("/resource/123")
+            .insert_header(("content-length", "7"))
+            .set_payload("payload")
+            .to_request(),
+    )
+    .await;
+    read_body(res).await;
+    // the body is dropped without being sent
+    let res = call_service(
+        &app,
+        TestRequest::post()
+            .uri("/resource/1")
+            .set_payload("payload")
+            .to_request(),
+    )
+    .await;
+    drop(res);
+    for uri in ["/empty", "/conflict", "/ignored"] {
+        let res = call_service(&app, TestRequest::with_uri(uri).to_request()).await;
+        read_body(res).await;
+    }
+
+    assert_eq!(
+        *observer.events.lock().unwrap(),
+        [
+            "start POST /resource/123",
+            "end POST /resource/{id} 201 http HTTP/1.1 7 7 matched=true completed=true excluded=false",
+            "start POST /resource/1",
+            "end POST /resource/{id} 201 http HTTP/1.1 0 0 matched=true completed=false excluded=false",
+            "start GET /empty",
+            "end GET /empty 204 http HTTP/1.1 0 0 matched=true completed=true excluded=false",
+            "start GET /conflict",
+            "end GET /conflict 409 http HTTP/1.1 0 0 matched=true completed=true excluded=true",
+        ]
+    );
+}
+
+#[actix_web::test]
+async fn middleware_observer_failed_request() {
+    let recorder = Arc::new(DebuggingRecorder::new());
+    let snapshotter = recorder.snapshotter();
+    let observer = Arc::new(RecordingObserver::default());
+    let metrics = ActixWebMetricsBuilder::new()
+        .observer(observer.clone())
+        .recorder(recorder)
+        .build();
+
+    let app = init_service(
+        App::new()
+            .wrap_fn(|req, srv| {
+                let denied = req.path() == "/denied";
+                let res = srv.call(req);
+                async move {
+                    if denied {
+                        return Err(actix_web::error::ErrorForbidden("denied"));
+                    }
+                    res.await
+                }
+            })
+            .wrap(metrics)
+            .service(web::resource("/denied").to(HttpResponse::Ok)),
+    )
+    .await;
+
+    let err = app
+        .call(TestRequest::with_uri("/denied").to_request())
+        .await
+        .unwrap_err();
+    assert_eq!(err.as_response_error().status_code(), StatusCode::FORBIDDEN);
+
+    assert_eq!(
+        *observer.events.lock().unwrap(),
+        ["start GET /denied", "failed GET 403"]
+    );
+    let snapshot = snapshotter.snapshot().into_vec();
+    let active_requests = snapshot
+        .iter()
+        .filter(|(key, ..)| key.key().name() == "http.server.active_requests")
+        .map(|(.., value)| value)
+        .collect::<Vec<_>>();
+    assert_eq!(active_requests, vec![&DebugValue::Gauge(0.0.into())]);
+}
+
+#[actix_web::test]
+async fn route_labeler_matches_middleware() {
+    let metrics = ActixWebMetricsBuilder::new()
+        .mask_unmatched_patterns("UNKNOWN")
+        .scope_prefixes(["/api"])
+        .exclude("/api/health_check")
+        .route_label_extension(true)
+        .build();
+    let labeler = metrics.route_labeler();
+    let labels = Arc::new(Mutex::new(Vec::new()));
+
+    let captured = labels.clone();
+    let app = init_service(
+        App::new()
+            .wrap(metrics)
+            .wrap_fn(move |req, srv| {
+                let labeler = labeler.clone();
+                let captured = captured.clone();
+                let fut = srv.call(req);
+                async move {
+                    let res = fut.await?;
+                    let stored = res.response().extensions().get::<RouteLabel>().cloned();
+                    if let Some(stored) = &stored {
+                        assert_eq!(*stored, labeler.label(res.request(), res.status()));
+                    }
+                    captured.lock().unwrap().push(stored.map(|label| {
+                        (label.scope().map(str::to_string), label.route().to_string())
+                    }));
+                    Ok(res)
+                }
+            })
+            .service(
+                web::scope("/api")
+                    .service(
+                        web::resource("/resource/{cheap}/{expensive}")
+                            .wrap_fn(|req, srv| {
+                                req.extensions_mut().insert(ActixWebMetricsExtension {
+                                    cardinality_keep_params: vec!["cheap".to_string()],
+                                });
+                                srv.call(req)
+                            })
+                            .to(HttpResponse::Ok),
+                    )
+                    .service(web::resource("/health_check").to(HttpResponse::Ok)),
+            ),
+    )
+    .await;
+
+    for uri in ["/api/resource/foo/123", "/api/health_check", "/missing"] {
+        let res = call_service(&app, TestRequest::with_uri(uri).to_request()).await;
+        read_body(res).await;
+    }
+
+    let api = Some("/api".to_string());
+    assert_eq!(
+        *labels.lock().unwrap(),
+        [
+            Some((api.clone(), "/resource/foo/{expensive}".to_string())),
+            None,
+            Some((Some(String::new()), "UNKNOWN".to_string())),
+        ]
+    );
+}
+
+#[actix_web::test]
+async fn middleware_request_context() {
+    let recorder = DebuggingRecorder::new();
+    let snapshotter = recorder.snapshotter();
+    let recorder = RequestContextRecorder::new(recorder);
+    let _guard = set_default_local_recorder(&recorder);
+
+    let metrics = ActixWebMetricsBuilder::new()
+        .request_counter(true)
+        .request_context(true)
+        .build();
+    let app = init_service(
+        App::new()
+            .wrap(metrics)
+            .service(web::resource("/orders/{id}").to(|| async {
+                counter!("orders_created").increment(1);
+                actix_web::rt::spawn(RequestContext::current().scope(async {
+                    counter!("emails_sent").increment(1);
+                }))
+                .await
+                .unwrap();
+                HttpResponse::Created().finish()
+            })),
+    )
+    .await;
+
+    let res = call_service(&app, TestRequest::post().uri("/orders/1").to_request()).await;
+    read_body(res).await;
+    counter!("outside_request").increment(1);
+
+    let context = vec![
+        ("http.request.method".to_string(), "POST".to_string()),
+        ("http.route".to_string(), "/orders/{id}".to_string()),
+    ];
+    let recorded = recorded(snapshotter.snapshot());
+    let counters = |name: &str| {
+        recorded
+            .iter()
+            .filter(|metric| metric.name == name)
+            .map(|metric| metric.labels.clone())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(counters("orders_created"), [context]);
+    assert_eq!(counters("emails_sent"), counters("orders_created"));
+    assert_eq!(counters("outside_request"), [vec![]]);
+    // the labels of the middleware metrics are not duplicated
+    let requests = counters("http.server.requests");
+    assert_eq!(requests.len(), 1);
+    assert_eq!(
+        requests[0]
+            .iter()
+            .filter(|(key, _)| key == "http.route")
+            .count(),
+        1
+    );
+}
+
+#[actix_web::test]
+async fn request_metrics_extractor() {
+    let recorder = DebuggingRecorder::new();
+    let snapshotter = recorder.snapshotter();
+    let _guard = set_default_local_recorder(&recorder);
+
+    let metrics = ActixWebMetricsBuilder::new()
+        .namespace("shop")
+        .const_labels(HashMap::from([("region".to_string(), "eu".to_string())]))
+        .request_metrics(true)
+        .build();
+    let app = init_service(
+        App::new()
+            .wrap(metrics)
+            .service(
+                web::resource("/orders/{id}").to(|metrics: RequestMetrics| async move {
+                    assert_eq!(metrics.route(), "/orders/{id}");
+                    assert_eq!(metrics.method(), Method::PUT);
+                    assert_eq!(metrics.scope(), None);
+                    metrics.counter("orders_updated").increment(2);
+                    HttpResponse::Ok().finish()
+                }),
+            ),
+    )
+    .await;
+
+    let res = call_service(&app, TestRequest::put().uri("/orders/1").to_request()).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    read_body(res).await;
+
+    let counters = recorded(snapshotter.snapshot())
+        .into_iter()
+        .filter(|metric| metric.name == "shop_orders_updated")
+        .map(|metric| metric.labels)
+        .collect::<Vec<_>>();
+    assert_eq!(
+        counters,
+        [vec![
+            ("http.request.method".to_string(), "PUT".to_string()),
+            ("http.route".to_string(), "/orders/{id}".to_string()),
+            ("region".to_string(), "eu".to_string()),
+        ]]
+    );
+}
+
+#[actix_web::test]
+async fn request_metrics_extractor_requires_middleware() {
+    let handler = |metrics: RequestMetrics| async move {
+        metrics.counter("orders_created").increment(1);
+        HttpResponse::Ok().finish()
+    };
+    let error = "RequestMetrics requires the ActixWebMetrics middleware with request_metrics \
+                 enabled to wrap the handler";
+
+    let app = init_service(App::new().service(web::resource("/orders").to(handler))).await;
+    let res = call_service(&app, TestRequest::with_uri("/orders").to_request()).await;
+    assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(read_body(res).await, error);
+
+    let app = init_service(
+        App::new()
+            .wrap(ActixWebMetricsBuilder::new().build())
+            .service(web::resource("/orders").to(handler)),
+    )
+    .await;
+    let res = call_service(&app, TestRequest::with_uri("/orders").to_request()).await;
+    assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(read_body(res).await, error);
+}
@@ -0,0 +1,221 @@
+#![cfg(feature = "opentelemetry")]
+
+use std::collections::BTreeMap;
+
+use actix_web::test::{call_service, init_service, read_body, TestRequest};
+use actix_web::{web, App, HttpResponse};
+use actix_web_metrics::{
+    ActixWebMetricsBuilder, ActixWebMetricsConfig, LabelsConfig, RequestMetrics,
+};
+use opentelemetry::metrics::MeterProvider;
+use opentelemetry_sdk::metrics::data::{AggregatedMetrics, MetricData};
+use opentelemetry_sdk::metrics::{InMemoryMetricExporter, PeriodicReader, SdkMeterProvider};
+
+/// Instrument kind, unit and data points, with their sorted attributes, of every exported metric.
+fn exported(exporter: &InMemoryMetricExporter) -> BTreeMap<String, (String, String, Vec<String>)> {
+    let mut metrics = BTreeMap::new();
+    let resource_metrics = exporter.get_finished_metrics().unwrap();
+    for metric in resource_metrics
+        .iter()
+        .flat_map(|resource| resource.scope_metrics())
+        .flat_map(|scope| scope.metrics())
+    {
+        let attributes = |attributes: &mut dyn Iterator<Item = &opentelemetry::KeyValue>| {
+            let mut attributes: Vec<_> = attributes
+                .map(|kv| format!("{}={}", kv.key, kv.value))
+                .collect();
+            attributes.sort();
+            attributes.join(",")
+        };
+        let (kind, points) = match metric.data() {
+            AggregatedMetrics::F64(MetricData::Histogram(histogram)) => (
+                "Histogram<f64>",
+                histogram
+                    .data_points()
+                    .map(|point| {
+                        let bounds: Vec<_> = point.bounds().map(|b| b.to_string()).collect();
+                        format!(
+                            "{{{}}} count={} bounds=[{}]",
+                            attributes(&mut point.attributes()),
+                            point.count(),
+                            bounds.join(",")
+                        )
+                    })
+                    .collect(),
+            ),
+            AggregatedMetrics::U64(MetricData::Histogram(histogram)) => (
+                "Histogram<u64>",
+                histogram
+                    .data_points()
+                    .map(|point| {
+                        format!(
+                            "{{{}}} count={} sum={}",
+                            attributes(&mut point.attributes()),
+                            point.count(),
+                            point.sum()
+                        )
+                    })
+                    .collect(),
+            ),
+            AggregatedMetrics::I64(MetricData::Sum(sum)) if !sum.is_monotonic() => (
+                "UpDownCounter<i64>",
+                sum.data_points()
+                    .map(|point| {
+                        format!(
+                            "{{{}}} {}",
+                            attributes(&mut point.attributes()),
+                            point.value()
+                        )
+                    })
+                    .collect(),
+            ),
+            AggregatedMetrics::U64(MetricData::Sum(sum)) if sum.is_monotonic() => (
+                "Counter<u64>",
+                sum.data_points()
+                    .map(|point| {
+                        format!(
+                            "{{{}}} {}",
+                            attributes(&mut point.attributes()),
+                            point.value()
+                        )
+                    })
+                    .collect(),
+            ),
+            data => panic!("unexpected data {data:?}"),
+        };
+        metrics.insert(
+            metric.name().to_string(),
+            (kind.to_string(), metric.unit().to_string(), points),
+        );
+    }
+    metrics
+}
+
+#[actix_web::test]
+async fn records_into_meter() {
+    let exporter = InMemoryMetricExporter::default();
+    let provider = SdkMeterProvider::builder()
+        .with_reader(PeriodicReader::builder(exporter.clone()).build())
+        .build();
+
+    let metrics = ActixWebMetricsBuilder::new()
+        .const_labels([("service".to_string(), "api".to_string())].into())
+        .request_counter(true)
+        .metrics_config(
+            ActixWebMetricsConfig::default()
+                .labels(LabelsConfig::default().http_route("route"))
+                .http_server_request_duration_name("request.duration"),
+        )
+        .opentelemetry_meter(provider.meter("actix-web-metrics"))
+        .build();
+
+    let app =
+        init_service(App::new().wrap(metrics).service(
+            web::resource("/resource/{id}").to(|| async { HttpResponse::Ok().body("ok") }),
+        ))
+        .await;
+
+    for uri in ["/resource/1", "/resource/2"] {
+        let res = call_service(&app, TestRequest::with_uri(uri).to_request()).await;
+        read_body(res).await;
+    }
+    provider.force_flush().unwrap();
+
+    let labels = "http.request.method=GET,http.response.status_code=200,network.protocol.name=http,network.protocol.version=1.1,route=/resource/{id},service=api";
+    let expected = |kind: &str, unit: &str, points: &[String]| {
+        (kind.to_string(), unit.to_string(), points.to_vec())
+    };
+    assert_eq!(
+        exported(&exporter),
+        BTreeMap::from([
+            (
+                "http.server.active_requests".to_string(),
+                expected(
+                    "UpDownCounter<i64>",
+                    "{request}",
+                    &["{http.request.method=GET,service=api,url.scheme=http} 0".to_string()]
+                )
+            ),
+            (
+                "http.server.request.body.size".to_string(),
+                expected("Histogram<u64>", "By", &[format!("{{{labels}}} count=2 sum=0")])
+            ),
+            (
+                "http.server.requests".to_string(),
+                expected("Counter<u64>", "{request}", &[format!("{{{labels}}} 2")])
+            ),
+            (
+                "http.server.response.body.size".to_string(),
+                expected("Histogram<u64>", "By", &[format!("{{{labels}}} count=2 sum=4")])
+            ),
+            (
+                "request.duration".to_string(),
+                expected(
+                    "Histogram<f64>",
+                    "s",
+                    &[format!("{{{labels}}} count=2 bounds=[0.005,0.01,0.025,0.05,0.075,0.1,0.25,0.5,0.75,1,2.5,5,7.5,10]")]
+                )
+            ),
+        ])
+    );
+}
+
+#[actix_web::test]
+async fn records_request_metrics_without_units() {
+    let exporter = InMemoryMetricExporter::default();
+    let provider = SdkMeterProvider::builder()
+        .with_reader(PeriodicReader::builder(exporter.clone()).build())
+        .build();
+
+    let metrics = ActixWebMetricsBuilder::new()
+        .request_metrics(true)
+        .opentelemetry_meter(provider.meter("actix-web-metrics"))
+        .build();
+
+    let app = init_service(
+        App::new()
+            .wrap(metrics)
+            .service(
+                web::resource("/orders").to(|metrics: RequestMetrics| async move {
+                    metrics.counter("orders.created").increment(2);
+                    metrics.counter("orders.seen").absolute(5);
+                    metrics.counter("orders.seen").absolute(3);
+                    metrics.histogram("orders.amount").record(120.0);
+                    HttpResponse::Ok().finish()
+                }),
+            ),
+    )
+    .await;
+
+    let res = call_service(&app, TestRequest::with_uri("/orders").to_request()).await;
+    read_body(res).await;
+    provider.force_flush().unwrap();
+
+    let labels = "http.request.method=GET,http.route=/orders";
+    let exported = exported(&exporter);
+    assert_eq!(
+        exported["orders.created"],
+        (
+            "Counter<u64>".to_string(),
+            String::new(),
+            vec![format!("{{{labels}}} 2")]
+        )
+    );
+    assert_eq!(
+        exported["orders.seen"],
+        (
+            "Counter<u64>".to_string(),
+            String::new(),
+            vec![format!("{{{labels}}} 5")]
+        )
+    );
+    assert_eq!(
+        exported["orders.amount"],
+        (
+            "Histogram<f64>".to_string(),
+            String::new(),
+            vec![format!("{{{labels}}} count=1 bounds=[0,5,10,25,50,75,100,250,500,750,1000,2500,5000,7500,10000]")]
+        )
+    );
+    assert_eq!(exported["http.server.request.duration"].1, "s");
+}
@@ -0,0 +1,225 @@
+#![cfg(feature = "push")]
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use actix_web::test::{call_service, init_service, read_body, TestRequest};
+use actix_web::{web, App, HttpResponse};
+use actix_web_metrics::{ActixWebMetricsBuilder, PrometheusPusher, PushConfig};
+use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
+
+#[derive(Debug, Clone)]
+struct PushRequest {
+    method: String,
+    path: String,
+    authorization: Option<String>,
+    body: String,
+}
+
+/// HTTP server answering with the given statuses in turn, then `200 OK`, or never answering.
+struct MockServer {
+    url: String,
+    requests: Arc<Mutex<Vec<PushRequest>>>,
+}
+
+impl MockServer {
+    fn start(statuses: Vec<u16>, respond: bool) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+
+        let received = requests.clone();
+        thread::spawn(move || {
+            let mut statuses = statuses.into_iter();
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                if !respond {
+                    // keep the connection open without answering
+                    thread::spawn(move || {
+                        thread::sleep(Duration::from_secs(5));
+                        drop(stream);
+                    });
+                    continue;
+                }
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut parts = request_line.split_whitespace();
+                let (method, path) = (parts.next().unwrap(), parts.next().unwrap());
+
+                let mut content_length = 0;
+                let mut authorization = None;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    let line = line.trim_end();
+                    if line.is_empty() {
+                        break;
+                    }
+                    let (name, value) = line.split_once(':').unwrap();
+                    match name.to_ascii_lowercase().as_str() {
+                        "content-length" => content_length = value.trim().parse().unwrap(),
+                        "authorization" => authorization = Some(value.trim().to_string()),
+                        _ => {}
+                    }
+                }
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).unwrap();
+
+                received.lock().unwrap().push(PushRequest {
+                    method: method.to_string(),
+                    path: path.to_string(),
+                    authorization,
+                    body: String::from_utf8(body).unwrap(),
+                });
+                let status = statuses.next().unwrap_or(200);
+                write!(
+                    stream,
+                    "HTTP/1.1 {status} Status\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                )
+                .unwrap();
+            }
+        });
+
+        Self { url, requests }
+    }
+
+    fn requests(&self) -> Vec<PushRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+async fn record_requests(count: usize) -> PrometheusHandle {
+    let recorder = PrometheusBuilder::new().build_recorder();
+    let handle = recorder.handle();
+    let metrics = ActixWebMetricsBuilder::new()
+        .request_counter(true)
+        .recorder(Arc::new(recorder))
+        .build();
+
+    let app = init_service(
+        App::new()
+            .wrap(metrics)
+            .service(web::resource("/job").to(HttpResponse::Ok)),
+    )
+    .await;
+    for _ in 0..count {
+        let res = call_service(&app, TestRequest::with_uri("/job").to_request()).await;
+        read_body(res).await;
+    }
+    handle
+}
+
+#[actix_web::test]
+async fn pushes_periodically_and_on_shutdown() {
+    let server = MockServer::start(vec![], true);
+    let handle = record_requests(2).await;
+
+    let pusher = PrometheusPusher::start(
+        handle,
+        PushConfig::new(format!("{}/metrics/job/worker", server.url))
+            .interval(Duration::from_millis(50)),
+    );
+    thread::sleep(Duration::from_millis(180));
+    pusher.shutdown();
+
+    let requests = server.requests();
+    // at least two periodic pushes and the final one
+    assert!(requests.len() >= 3, "{requests:?}");
+    for request in &requests {
+        assert_eq!(request.method, "PUT");
+        assert_eq!(request.path, "/metrics/job/worker");
+        assert_eq!(request.authorization, None);
+        assert!(request
+            .body
+            .contains(r#"http_server_requests{http_route="/job""#));
+    }
+
+    // nothing is pushed once shut down
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(server.requests().len(), requests.len());
+}
+
+#[actix_web::test]
+async fn retries_failed_pushes() {
+    let server = MockServer::start(vec![503, 500], true);
+    let handle = record_requests(1).await;
+
+    let pusher = PrometheusPusher::start(
+        handle,
+        PushConfig::new(format!("{}/metrics/job/batch", server.url))
+            .interval(Duration::from_secs(3600))
+            .retries(2)
+            .retry_backoff(Duration::from_millis(10))
+            .use_http_post_method(true)
+            .basic_auth("pusher", "secret"),
+    );
+    pusher.shutdown();
+
+    let requests = server.requests();
+    assert_eq!(requests.len(), 3);
+    for request in &requests {
+        assert_eq!(request.method, "POST");
+        assert_eq!(
+            request.authorization.as_deref(),
+            Some("Basic cHVzaGVyOnNlY3JldA==")
+        );
+    }
+}
+
+#[actix_web::test]
+async fn push_attempts_time_out() {
+    let server = MockServer::start(vec![], false);
+    let handle = record_requests(1).await;
+
+    let pusher = PrometheusPusher::start(
+        handle,
+        PushConfig::new(format!("{}/metrics/job/stuck", server.url))
+            .interval(Duration::from_secs(3600))
+            .timeout(Duration::from_millis(100))
+            .retries(1)
+            .retry_backoff(Duration::from_millis(10)),
+    );
+
+    let start = Instant::now();
+    pusher.shutdown();
+    assert!(start.elapsed() < Duration::from_secs(2));
+}
+
+#[actix_web::test]
+async fn dropping_does_not_wait_for_the_last_push() {
+    let server = MockServer::start(vec![], false);
+    let handle = record_requests(1).await;
+
+    let pusher = PrometheusPusher::start(
+        handle,
+        PushConfig::new(format!("{}/metrics/job/dropped", server.url))
+            .interval(Duration::from_secs(3600))
+            .timeout(Duration::from_secs(1))
+            .retries(1),
+    );
+
+    let start = Instant::now();
+    drop(pusher);
+    assert!(start.elapsed() < Duration::from_millis(500));
+}
+
+#[actix_web::test]
+async fn zero_interval_does_not_push_in_a_busy_loop() {
+    let server = MockServer::start(vec![], true);
+    let handle = record_requests(1).await;
+
+    let pusher = PrometheusPusher::start(
+        handle,
+        PushConfig::new(format!("{}/metrics/job/worker", server.url)).interval(Duration::ZERO),
+    );
+    thread::sleep(Duration::from_millis(100));
+    pusher.shutdown();
+
+    // one push every 10 milliseconds at most, and the final one
+    let pushes = server.requests().len();
+    assert!((2..=11).contains(&pushes), "{pushes} pushes");
+}
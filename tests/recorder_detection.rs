@@ -0,0 +1,221 @@
+//! Lives in its own test binary as it relies on no global recorder being installed.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+
+use actix_web::test::{call_service, init_service, TestRequest};
+use actix_web::{web, App, HttpResponse};
+use actix_web_metrics::ActixWebMetricsBuilder;
+use metrics::{
+    set_default_local_recorder, Counter, Gauge, Histogram, Key, KeyName, Metadata, Recorder,
+    SharedString, Unit,
+};
+use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+
+/// Recorder keeping track of described metrics.
+#[derive(Default)]
+struct DescribeRecorder {
+    described: Mutex<Vec<(String, Option<Unit>)>>,
+}
+
+impl DescribeRecorder {
+    fn described(&self) -> Vec<(String, Option<Unit>)> {
+        let mut described = self.described.lock().unwrap().clone();
+        described.sort_by(|a, b| a.0.cmp(&b.0));
+        described.dedup();
+        described
+    }
+}
+
+impl Recorder for DescribeRecorder {
+    fn describe_counter(&self, key: KeyName, unit: Option<Unit>, _: SharedString) {
+        self.described
+            .lock()
+            .unwrap()
+            .push((key.as_str().to_string(), unit));
+    }
+
+    fn describe_gauge(&self, key: KeyName, unit: Option<Unit>, _: SharedString) {
+        self.described
+            .lock()
+            .unwrap()
+            .push((key.as_str().to_string(), unit));
+    }
+
+    fn describe_histogram(&self, key: KeyName, unit: Option<Unit>, _: SharedString) {
+        self.described
+            .lock()
+            .unwrap()
+            .push((key.as_str().to_string(), unit));
+    }
+
+    fn register_counter(&self, _: &Key, _: &Metadata<'_>) -> Counter {
+        Counter::noop()
+    }
+
+    fn register_gauge(&self, _: &Key, _: &Metadata<'_>) -> Gauge {
+        Gauge::noop()
+    }
+
+    fn register_histogram(&self, _: &Key, _: &Metadata<'_>) -> Histogram {
+        Histogram::noop()
+    }
+}
+
+fn all_descriptions() -> Vec<(String, Option<Unit>)> {
+    vec![
+        ("http.server.active_requests".to_string(), None),
+        (
+            "http.server.request.body.size".to_string(),
+            Some(Unit::Bytes),
+        ),
+        (
+            "http.server.request.duration".to_string(),
+            Some(Unit::Seconds),
+        ),
+        (
+            "http.server.response.body.size".to_string(),
+            Some(Unit::Bytes),
+        ),
+    ]
+}
+
+/// Zero-sized recorder forwarding to a shared one, e.g. like a recorder backed by statics.
+struct ForwardingRecorder;
+
+fn forwarded() -> &'static DebuggingRecorder {
+    static RECORDER: OnceLock<DebuggingRecorder> = OnceLock::new();
+    RECORDER.get_or_init(DebuggingRecorder::new)
+}
+
+impl Recorder for ForwardingRecorder {
+    fn describe_counter(&self, key: KeyName, unit: Option<Unit>, description: SharedString) {
+        forwarded().describe_counter(key, unit, description);
+    }
+
+    fn describe_gauge(&self, key: KeyName, unit: Option<Unit>, description: SharedString) {
+        forwarded().describe_gauge(key, unit, description);
+    }
+
+    fn describe_histogram(&self, key: KeyName, unit: Option<Unit>, description: SharedString) {
+        forwarded().describe_histogram(key, unit, description);
+    }
+
+    fn register_counter(&self, key: &Key, metadata: &Metadata<'_>) -> Counter {
+        forwarded().register_counter(key, metadata)
+    }
+
+    fn register_gauge(&self, key: &Key, metadata: &Metadata<'_>) -> Gauge {
+        forwarded().register_gauge(key, metadata)
+    }
+
+    fn register_histogram(&self, key: &Key, metadata: &Metadata<'_>) -> Histogram {
+        forwarded().register_histogram(key, metadata)
+    }
+}
+
+/// Logger keeping the warnings of the current thread, as tests run concurrently.
+struct CapturingLogger;
+
+thread_local! {
+    static WARNINGS: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+}
+
+impl log::Log for CapturingLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::Level::Warn
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            WARNINGS.with(|warnings| warnings.borrow_mut().push(record.args().to_string()));
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+#[actix_web::test]
+async fn descriptions_are_emitted_when_recorder_is_installed_after_build() {
+    let metrics = ActixWebMetricsBuilder::new().build();
+
+    let recorder = DescribeRecorder::default();
+    let _guard = set_default_local_recorder(&recorder);
+    assert!(recorder.described().is_empty());
+
+    let app = init_service(
+        App::new()
+            .wrap(metrics)
+            .service(web::resource("/health_check").to(HttpResponse::Ok)),
+    )
+    .await;
+    call_service(&app, TestRequest::with_uri("/health_check").to_request()).await;
+
+    assert_eq!(recorder.described(), all_descriptions());
+}
+
+#[actix_web::test]
+async fn describe_emits_descriptions_before_first_request() {
+    let metrics = ActixWebMetricsBuilder::new().build();
+
+    let recorder = DescribeRecorder::default();
+    let _guard = set_default_local_recorder(&recorder);
+    metrics.describe();
+
+    assert_eq!(recorder.described(), all_descriptions());
+}
+
+#[actix_web::test]
+async fn zero_sized_recorder_installed_after_first_requests() {
+    log::set_logger(&CapturingLogger).unwrap();
+    log::set_max_level(log::LevelFilter::Warn);
+
+    let metrics = ActixWebMetricsBuilder::new().build();
+    let app = init_service(
+        App::new()
+            .wrap(metrics)
+            .service(web::resource("/health_check").to(HttpResponse::Ok)),
+    )
+    .await;
+
+    // recorded into the no-op recorder, which is warned about once
+    for _ in 0..3 {
+        call_service(&app, TestRequest::with_uri("/health_check").to_request()).await;
+    }
+    let warnings = WARNINGS.with(|warnings| warnings.borrow().clone());
+    assert_eq!(warnings.len(), 1, "{warnings:?}");
+    assert!(warnings[0].contains("no-op recorder"));
+
+    let snapshotter = forwarded().snapshotter();
+    let _guard = set_default_local_recorder(&ForwardingRecorder);
+    call_service(&app, TestRequest::with_uri("/health_check").to_request()).await;
+
+    let recorded = snapshotter
+        .snapshot()
+        .into_vec()
+        .into_iter()
+        .map(|(key, unit, _, value)| (key.key().name().to_string(), (unit, value)))
+        .collect::<HashMap<_, _>>();
+    assert!(matches!(
+        &recorded["http.server.request.duration"],
+        (Some(Unit::Seconds), DebugValue::Histogram(values)) if values.len() == 1
+    ));
+    assert!(matches!(
+        recorded["http.server.active_requests"],
+        (None, DebugValue::Gauge(value)) if value.into_inner() == 0.0
+    ));
+    // the zero-sized recorder is not mistaken for the no-op recorder
+    assert_eq!(WARNINGS.with(|warnings| warnings.borrow().len()), 1);
+}
+
+#[test]
+fn bound_recorder_is_described_on_build() {
+    let recorder = Arc::new(DescribeRecorder::default());
+
+    let _metrics = ActixWebMetricsBuilder::new()
+        .recorder(recorder.clone())
+        .build();
+
+    assert_eq!(recorder.described(), all_descriptions());
+}
@@ -0,0 +1,196 @@
+#![cfg(feature = "serde")]
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use actix_web::test::{call_service, init_service, TestRequest};
+use actix_web::{web, App, HttpResponse};
+use actix_web_metrics::{
+    ActixWebMetricsBuilder, ActixWebMetricsConfig, ActixWebMetricsSettings, ConfigError,
+    LabelsConfig,
+};
+use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+
+const TOML_SETTINGS: &str = r#"
+namespace = "my_app"
+exclude = ["/health"]
+exclude_regex = ["^/internal/.*"]
+exclude_status = [404]
+unmatched_patterns_mask = "UNMATCHED"
+request_counter = true
+
+[const_labels]
+service = "api"
+
+[metrics]
+http_server_request_duration_name = "request_duration"
+http_server_requests_name = "requests"
+
+[metrics.labels]
+http_route = "route"
+http_request_method = "method"
+"#;
+
+fn expected_settings() -> ActixWebMetricsSettings {
+    let mut settings = ActixWebMetricsSettings::default();
+    settings.namespace = Some("my_app".to_string());
+    settings.const_labels = BTreeMap::from([("service".to_string(), "api".to_string())]);
+    settings.exclude = vec!["/health".to_string()];
+    settings.exclude_regex = vec!["^/internal/.*".to_string()];
+    settings.exclude_status = vec![404];
+    settings.unmatched_patterns_mask = "UNMATCHED".to_string();
+    settings.request_counter = true;
+    settings.metrics = ActixWebMetricsConfig::default()
+        .http_server_request_duration_name("request_duration")
+        .http_server_requests_name("requests")
+        .labels(
+            LabelsConfig::default()
+                .http_route("route")
+                .http_request_method("method"),
+        );
+    settings
+}
+
+#[test]
+fn settings_from_toml() {
+    let settings: ActixWebMetricsSettings = toml::from_str(TOML_SETTINGS).unwrap();
+    assert_eq!(settings, expected_settings());
+}
+
+#[test]
+fn settings_from_json() {
+    let settings: ActixWebMetricsSettings = serde_json::from_str(
+        r#"{
+            "namespace": "my_app",
+            "const_labels": { "service": "api" },
+            "exclude": ["/health"],
+            "exclude_regex": ["^/internal/.*"],
+            "exclude_status": [404],
+            "unmatched_patterns_mask": "UNMATCHED",
+            "request_counter": true,
+            "metrics": {
+                "http_server_request_duration_name": "request_duration",
+                "http_server_requests_name": "requests",
+                "labels": { "http_route": "route", "http_request_method": "method" }
+            }
+        }"#,
+    )
+    .unwrap();
+    assert_eq!(settings, expected_settings());
+}
+
+#[test]
+fn settings_defaults() {
+    let settings: ActixWebMetricsSettings = toml::from_str("").unwrap();
+    assert_eq!(settings, ActixWebMetricsSettings::default());
+    assert!(settings.mask_unmatched_patterns);
+    assert_eq!(settings.unmatched_patterns_mask, "UNKNOWN");
+}
+
+#[test]
+fn settings_disable_masking_round_trip() {
+    let settings: ActixWebMetricsSettings =
+        toml::from_str("mask_unmatched_patterns = false").unwrap();
+    assert!(!settings.mask_unmatched_patterns);
+
+    let toml = toml::to_string(&settings).unwrap();
+    assert_eq!(
+        toml::from_str::<ActixWebMetricsSettings>(&toml).unwrap(),
+        settings
+    );
+}
+
+#[test]
+fn settings_round_trip() {
+    let settings = expected_settings();
+
+    let toml = toml::to_string(&settings).unwrap();
+    assert_eq!(
+        toml::from_str::<ActixWebMetricsSettings>(&toml).unwrap(),
+        settings
+    );
+
+    let json = serde_json::to_string(&settings).unwrap();
+    assert_eq!(
+        serde_json::from_str::<ActixWebMetricsSettings>(&json).unwrap(),
+        settings
+    );
+}
+
+#[test]
+fn settings_reject_unknown_fields() {
+    assert!(toml::from_str::<ActixWebMetricsSettings>("namespaces = \"my_app\"").is_err());
+    assert!(toml::from_str::<ActixWebMetricsSettings>("[metrics]\nduration = \"d\"").is_err());
+    assert!(toml::from_str::<ActixWebMetricsSettings>("[metrics.labels]\nroute = \"r\"").is_err());
+}
+
+#[test]
+fn settings_reject_invalid_status() {
+    let settings: ActixWebMetricsSettings = toml::from_str("exclude_status = [1000]").unwrap();
+    assert!(matches!(
+        ActixWebMetricsBuilder::try_from(settings),
+        Err(ConfigError::InvalidStatusCode(1000))
+    ));
+}
+
+#[actix_web::test]
+async fn settings_into_builder() {
+    let settings: ActixWebMetricsSettings = toml::from_str(TOML_SETTINGS).unwrap();
+
+    let recorder = Arc::new(DebuggingRecorder::new());
+    let snapshotter = recorder.snapshotter();
+    let metrics = ActixWebMetricsBuilder::try_from(settings)
+        .unwrap()
+        .recorder(recorder)
+        .build();
+
+    let app = init_service(
+        App::new()
+            .wrap(metrics)
+            .service(web::resource("/health").to(HttpResponse::Ok))
+            .service(web::resource("/internal/status").to(HttpResponse::Ok))
+            .service(web::resource("/orders").to(HttpResponse::Ok)),
+    )
+    .await;
+
+    for uri in [
+        "/health",
+        "/internal/status",
+        "/orders",
+        "/missing",
+        "/orders",
+    ] {
+        call_service(&app, TestRequest::with_uri(uri).to_request()).await;
+    }
+
+    let snapshot = snapshotter.snapshot().into_vec();
+    let requests = snapshot
+        .iter()
+        .filter(|(key, ..)| key.key().name() == "my_app_requests")
+        .map(|(.., value)| value)
+        .collect::<Vec<_>>();
+    assert_eq!(requests, vec![&DebugValue::Counter(2)]);
+
+    let durations = snapshot
+        .into_iter()
+        .filter(|(key, ..)| key.key().name() == "my_app_request_duration")
+        .map(|(key, ..)| {
+            key.key()
+                .labels()
+                .map(|label| format!("{}={}", label.key(), label.value()))
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+
+    assert_eq!(
+        durations,
+        vec![vec![
+            "route=/orders".to_string(),
+            "method=GET".to_string(),
+            "http.response.status_code=200".to_string(),
+            "network.protocol.name=http".to_string(),
+            "network.protocol.version=1.1".to_string(),
+            "service=api".to_string(),
+        ]]
+    );
+}
@@ -0,0 +1,174 @@
+//! Lives in its own test binary as it installs a global logger.
+
+use std::sync::Mutex;
+use std::time::Duration;
+
+use actix_web::http::StatusCode;
+use actix_web::test::{call_service, init_service, read_body, TestRequest};
+use actix_web::{web, App, HttpResponse};
+use actix_web_metrics::{
+    ActixWebMetrics, ActixWebMetricsBuilder, TopUnmatchedPathsConfig, UnmatchedPathCount,
+};
+
+struct CapturingLogger;
+
+static MESSAGES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+impl log::Log for CapturingLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::Level::Info
+    }
+
+    fn log(&self, record: &log::Record) {
+        if record.level() <= log::Level::Info {
+            MESSAGES.lock().unwrap().push(record.args().to_string());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+async fn call_paths(metrics: ActixWebMetrics, paths: &[&str]) {
+    let app = init_service(
+        App::new()
+            .wrap(metrics)
+            .service(web::resource("/health_check").to(HttpResponse::Ok)),
+    )
+    .await;
+
+    for path in paths {
+        let res = call_service(&app, TestRequest::with_uri(path).to_request()).await;
+        read_body(res).await;
+    }
+}
+
+fn top(paths: &[(&str, u64, u64)]) -> Vec<(String, u64, u64)> {
+    paths
+        .iter()
+        .map(|(path, count, error)| (path.to_string(), *count, *error))
+        .collect()
+}
+
+fn entries(top: Vec<UnmatchedPathCount>) -> Vec<(String, u64, u64)> {
+    top.into_iter()
+        .map(|entry| (entry.path, entry.count, entry.error))
+        .collect()
+}
+
+#[actix_web::test]
+async fn tracks_top_unmatched_paths() {
+    let metrics = ActixWebMetricsBuilder::new()
+        .track_unmatched_paths(TopUnmatchedPathsConfig::default().capacity(3))
+        .build();
+
+    call_paths(
+        metrics.clone(),
+        &[
+            "/health_check",
+            "/.env",
+            "/.env",
+            "/.env",
+            "/wp-admin",
+            "/wp-admin",
+            "/a",
+            // evicts `/a`, the least requested path
+            "/b",
+        ],
+    )
+    .await;
+
+    assert_eq!(
+        entries(metrics.top_unmatched_paths()),
+        top(&[("/.env", 3, 0), ("/b", 2, 1), ("/wp-admin", 2, 0)])
+    );
+}
+
+#[actix_web::test]
+async fn evicted_paths_pass_on_their_count() {
+    let metrics = ActixWebMetricsBuilder::new()
+        .track_unmatched_paths(TopUnmatchedPathsConfig::default().capacity(2))
+        .build();
+
+    call_paths(
+        metrics.clone(),
+        &[
+            "/a", "/a", "/a", "/a", "/b", // evicts `/b`, then counted again
+            "/c", "/c", // evicts `/c`, ending with the count of `/a`
+            "/d",
+        ],
+    )
+    .await;
+
+    assert_eq!(
+        entries(metrics.top_unmatched_paths()),
+        top(&[("/a", 4, 0), ("/d", 4, 3)])
+    );
+}
+
+#[actix_web::test]
+async fn tracks_excluded_requests_and_truncates_paths() {
+    let metrics = ActixWebMetricsBuilder::new()
+        .exclude("/favicon.ico")
+        .track_unmatched_paths(TopUnmatchedPathsConfig::default())
+        .build();
+
+    let long_path = format!("/{}", "x".repeat(300));
+    call_paths(
+        metrics.clone(),
+        &["/favicon.ico", "/favicon.ico", &long_path],
+    )
+    .await;
+
+    assert_eq!(
+        entries(metrics.top_unmatched_paths()),
+        top(&[("/favicon.ico", 2, 0), (&long_path[..256], 1, 0)])
+    );
+
+    // nothing is tracked unless enabled
+    let metrics = ActixWebMetricsBuilder::new().build();
+    call_paths(metrics.clone(), &["/.env"]).await;
+    assert!(metrics.top_unmatched_paths().is_empty());
+}
+
+#[actix_web::test]
+async fn tracks_unmatched_paths_with_excluded_status() {
+    let metrics = ActixWebMetricsBuilder::new()
+        .exclude_status(StatusCode::NOT_FOUND)
+        .track_unmatched_paths(TopUnmatchedPathsConfig::default())
+        .build();
+
+    call_paths(metrics.clone(), &["/.env", "/.env", "/wp-admin"]).await;
+
+    assert_eq!(
+        entries(metrics.top_unmatched_paths()),
+        top(&[("/.env", 2, 0), ("/wp-admin", 1, 0)])
+    );
+}
+
+#[actix_web::test]
+async fn logs_top_unmatched_paths_periodically() {
+    log::set_logger(&CapturingLogger).unwrap();
+    log::set_max_level(log::LevelFilter::Info);
+
+    let metrics = ActixWebMetricsBuilder::new()
+        .track_unmatched_paths(
+            TopUnmatchedPathsConfig::default().log_interval(Duration::from_millis(50)),
+        )
+        .build();
+
+    call_paths(metrics.clone(), &["/.env", "/.env", "/wp-admin"]).await;
+    actix_web::rt::time::sleep(Duration::from_millis(120)).await;
+
+    let messages = MESSAGES
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|message| message.starts_with("top unmatched paths"))
+        .cloned()
+        .collect::<Vec<_>>();
+    assert!(!messages.is_empty());
+    assert!(messages
+        .iter()
+        .all(|message| message == "top unmatched paths: /.env (2), /wp-admin (1)"));
+    drop(metrics);
+}
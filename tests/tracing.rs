@@ -0,0 +1,156 @@
+#![cfg(feature = "tracing")]
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use actix_web::dev::Service;
+use actix_web::test::{call_service, init_service, read_body, TestRequest};
+use actix_web::{web, App, HttpResponse};
+use actix_web_metrics::ActixWebMetricsBuilder;
+use tracing::field::{Empty, Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Metadata, Subscriber};
+use tracing_core::span::Current;
+
+type Fields = BTreeMap<String, String>;
+
+/// Records the fields of every span and event.
+#[derive(Default)]
+struct Captured {
+    next_id: AtomicU64,
+    spans: Mutex<HashMap<u64, Fields>>,
+    metadata: Mutex<HashMap<u64, &'static Metadata<'static>>>,
+    entered: Mutex<Vec<u64>>,
+    events: Mutex<Vec<(String, Fields)>>,
+}
+
+struct CapturingSubscriber(Arc<Captured>);
+
+struct FieldVisitor<'a>(&'a mut Fields);
+
+impl Visit for FieldVisitor<'_> {
+    fn record_f64(&mut self, field: &Field, _value: f64) {
+        // durations vary
+        self.0
+            .insert(field.name().to_string(), "[DURATION]".to_string());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), value.to_string());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0
+            .insert(field.name().to_string(), format!("{value:?}"));
+    }
+}
+
+impl Subscriber for CapturingSubscriber {
+    fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+        true
+    }
+
+    fn new_span(&self, span: &Attributes<'_>) -> Id {
+        let id = self.0.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let mut fields = Fields::new();
+        span.record(&mut FieldVisitor(&mut fields));
+        self.0.spans.lock().unwrap().insert(id, fields);
+        self.0.metadata.lock().unwrap().insert(id, span.metadata());
+        Id::from_u64(id)
+    }
+
+    fn record(&self, span: &Id, values: &Record<'_>) {
+        let mut spans = self.0.spans.lock().unwrap();
+        values.record(&mut FieldVisitor(spans.get_mut(&span.into_u64()).unwrap()));
+    }
+
+    fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let mut fields = Fields::new();
+        event.record(&mut FieldVisitor(&mut fields));
+        self.0
+            .events
+            .lock()
+            .unwrap()
+            .push((event.metadata().target().to_string(), fields));
+    }
+
+    fn enter(&self, span: &Id) {
+        self.0.entered.lock().unwrap().push(span.into_u64());
+    }
+
+    fn exit(&self, _span: &Id) {
+        self.0.entered.lock().unwrap().pop();
+    }
+
+    fn current_span(&self) -> Current {
+        match self.0.entered.lock().unwrap().last() {
+            Some(&id) => Current::new(Id::from_u64(id), self.0.metadata.lock().unwrap()[&id]),
+            None => Current::none(),
+        }
+    }
+}
+
+fn fields(fields: &[(&str, &str)]) -> Fields {
+    fields
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect()
+}
+
+#[actix_web::test]
+async fn records_request_fields_on_span_and_event() {
+    let captured = Arc::new(Captured::default());
+    let _guard = tracing::subscriber::set_default(CapturingSubscriber(captured.clone()));
+
+    let metrics = ActixWebMetricsBuilder::new().exclude("/health").build();
+    let app = init_service(
+        App::new()
+            .wrap(metrics)
+            // the request span is entered when the middleware is called, like tracing-actix-web
+            .wrap_fn(|req, srv| {
+                let span = tracing::info_span!(
+                    "request",
+                    http.route = Empty,
+                    http.response.status_code = Empty,
+                    http.request.body.size = Empty,
+                    http.response.body.size = Empty,
+                    http.server.request.duration = Empty,
+                );
+                let _entered = span.enter();
+                srv.call(req)
+            })
+            .service(web::resource("/resource/{id}").to(|| async { HttpResponse::Ok().body("ok") }))
+            .service(web::resource("/health").to(HttpResponse::Ok)),
+    )
+    .await;
+
+    for uri in ["/resource/1", "/health"] {
+        let res = call_service(&app, TestRequest::with_uri(uri).to_request()).await;
+        read_body(res).await;
+    }
+
+    let recorded = fields(&[
+        ("http.route", "/resource/{id}"),
+        ("http.response.status_code", "200"),
+        ("http.request.body.size", "0"),
+        ("http.response.body.size", "2"),
+        ("http.server.request.duration", "[DURATION]"),
+    ]);
+    let spans = captured.spans.lock().unwrap();
+    let mut spans: Vec<_> = spans.values().cloned().collect();
+    spans.sort();
+    // the excluded request isn't recorded
+    assert_eq!(spans, [Fields::new(), recorded.clone()]);
+
+    let mut event = recorded;
+    event.insert("http.request.method".to_string(), "GET".to_string());
+    event.insert("message".to_string(), "request recorded".to_string());
+    assert_eq!(
+        *captured.events.lock().unwrap(),
+        [("actix_web_metrics".to_string(), event)]
+    );
+}